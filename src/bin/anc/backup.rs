@@ -0,0 +1,290 @@
+use std::collections::HashSet;
+use std::fs::{File, Metadata};
+use std::path::{Path, PathBuf};
+
+use anchorage::blobserver::{client::Client, server::CreateNodeRequest};
+use anchorage::error::Kind;
+use anchorage::hash::Hasher;
+use anchorage::{BlobRef, DirEntry, Directory, Node, NodeType};
+use anyhow::{Context, Result};
+use futures::future::{FutureExt, LocalBoxFuture};
+use indicatif::ProgressBar;
+use serde::Serialize;
+
+use crate::cache::{Cache, Entry, FileState};
+use crate::transfer;
+
+/// How a backup gets run.
+pub struct Options {
+    pub jobs: usize,
+    pub budget: transfer::Budget,
+    // Re-read every file, even ones the cache says haven't changed, going by
+    // their chunks rather than their size and mtime
+    pub no_cache: bool,
+    // Re-hash files the cache says haven't changed, and check their nodes
+    // are still on the server before reusing them
+    pub verify: bool,
+    // Ticked once per file
+    pub progress: ProgressBar,
+    // What chunks are named with, which should be what the server wants
+    pub hasher: &'static dyn Hasher,
+}
+
+/// What happened to each file since the last backup, by path within the
+/// directory.
+#[derive(Debug, Default, Serialize)]
+pub struct Changes {
+    pub new: Vec<String>,
+    pub changed: Vec<String>,
+    pub unchanged: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+struct Backup<'a> {
+    client: &'a Client,
+    cache: &'a Cache,
+    options: &'a Options,
+    root: PathBuf,
+    seen: HashSet<String>, // Cache keys of every file found
+    changes: Changes,
+}
+
+/// Backs up everything under the directory, returning its root directory node.
+///
+/// Files go up as file nodes, and every directory gets a node listing what's
+/// in it. Files the cache knows are unchanged reuse their old node, as do
+/// directories with nothing changed under them.
+pub async fn backup(
+    client: &Client,
+    cache: &Cache,
+    dir: &Path,
+    options: &Options,
+) -> Result<(Node, Changes)> {
+    // Cache keys are absolute, so the same directory is found from anywhere
+    let root = dir
+        .canonicalize()
+        .with_context(|| format!("error finding {}", dir.display()))?;
+    let mut backup = Backup {
+        client,
+        cache,
+        options,
+        root: root.clone(),
+        seen: HashSet::new(),
+        changes: Changes::default(),
+    };
+
+    let (id, _) = backup.directory(&root).await?;
+    let node = client.get_node(&id).await?;
+
+    // Anything cached under the directory that wasn't found is gone
+    for path in cache.paths_under(&root.to_string_lossy())? {
+        if !backup.seen.contains(&path) {
+            backup
+                .changes
+                .deleted
+                .push(backup.relative(Path::new(&path)));
+            cache.remove(&path)?;
+        }
+    }
+    options.progress.finish_and_clear();
+
+    Ok((node, backup.changes))
+}
+
+// Chunks a file, returning the chunk files. Their names are their blob ids.
+fn chunk(path: &Path, hasher: &dyn Hasher) -> Result<Vec<PathBuf>> {
+    let mut f = File::open(path).with_context(|| format!("error opening {}", path.display()))?;
+    let chunks = anchorage::chunk::create_chunks(&mut f, hasher)?;
+
+    Ok(chunks.into_iter().map(|(p, _)| p.into()).collect())
+}
+
+// Removes chunk files once they're done with. They're only temp files, so
+// one that can't be removed is left for the system to clean up.
+fn remove_chunks(chunks: &[PathBuf]) {
+    for chunk in chunks {
+        let _ = std::fs::remove_file(chunk);
+    }
+}
+
+fn blob_ids(chunks: &[PathBuf]) -> Result<Vec<BlobRef>> {
+    chunks
+        .iter()
+        .filter_map(|p| p.file_name())
+        .map(|name| Ok(BlobRef::parse(&name.to_string_lossy())?))
+        .collect()
+}
+
+impl<'a> Backup<'a> {
+    fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // Uploads everything in a directory, then a listing of it. Returns the
+    // listing's node id and the total size of what's in it.
+    fn directory<'b>(&'b mut self, dir: &'b Path) -> LocalBoxFuture<'b, Result<(String, u64)>> {
+        async move {
+            let mut paths = std::fs::read_dir(dir)
+                .with_context(|| format!("error reading {}", dir.display()))?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            paths.sort();
+
+            let mut listing = Directory::default();
+            for path in paths {
+                // Symlinks aren't followed, so they're left out along with
+                // anything else that isn't a plain file or directory
+                let meta = std::fs::symlink_metadata(&path)?;
+                let name = path
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+
+                if meta.is_dir() {
+                    let (node, size) = self.directory(&path).await?;
+                    listing.entries.push(DirEntry {
+                        name,
+                        node_type: NodeType::Directory,
+                        node,
+                        size,
+                    });
+                } else if meta.is_file() {
+                    let node = self.file(&path, &meta).await?;
+                    listing.entries.push(DirEntry {
+                        name,
+                        node_type: NodeType::File,
+                        node,
+                        size: meta.len(),
+                    });
+                }
+            }
+
+            let size = listing.entries.iter().map(|e| e.size).sum();
+            let data = serde_json::to_vec(&listing)?;
+
+            // The same listing means the same children, so its node still holds
+            let key = dir.to_string_lossy().into_owned();
+            let id = BlobRef::hash(self.options.hasher, &data);
+            if let Some(entry) = self.cache.directory(&key)? {
+                if entry.blobs == [id.clone()]
+                    && (!self.options.verify || self.node_exists(&entry.node).await?)
+                {
+                    return Ok((entry.node, size));
+                }
+            }
+
+            let blob = self.client.put_blob(&data).await?.created;
+            let node = self
+                .client
+                .create_node(CreateNodeRequest {
+                    namespace: self.client.namespace().to_owned(),
+                    node_type: NodeType::Directory,
+                    blobs: vec![blob],
+                    attributes: Default::default(),
+                    allow_dangling: false,
+                })
+                .await?;
+            self.cache.put_directory(&key, &node.id, &id)?;
+
+            Ok((node.id, size))
+        }
+        .boxed_local()
+    }
+
+    // Uploads a file unless the cache has it, returning its node id
+    async fn file(&mut self, path: &Path, meta: &Metadata) -> Result<String> {
+        let key = path.to_string_lossy().into_owned();
+        let relative = self.relative(path);
+        let state = FileState::new(meta);
+        self.seen.insert(key.clone());
+        self.options.progress.set_message(relative.clone());
+        self.options.progress.inc(1);
+
+        // Without the cache's word for it the file is read again, and the
+        // chunks kept for uploading if it's changed
+        let cached = self.cache.get(&key)?;
+        let mut chunks = None;
+        let unchanged = match &cached {
+            Some((previous, entry)) if self.options.no_cache || *previous == state => {
+                if self.options.no_cache || self.options.verify {
+                    let read = chunk(path, self.options.hasher)?;
+                    // Chunks from another algorithm won't match, so they're
+                    // uploaded again as if they'd changed
+                    let same = blob_ids(&read)? == entry.blobs;
+                    chunks = Some(read);
+                    same && (!self.options.verify || self.node_exists(&entry.node).await?)
+                } else {
+                    true
+                }
+            }
+            _ => false,
+        };
+
+        match (&cached, unchanged) {
+            (None, _) => self.changes.new.push(relative),
+            (Some(_), false) => self.changes.changed.push(relative),
+            (Some((previous, entry)), true) => {
+                self.changes.unchanged.push(relative);
+                if let Some(chunks) = &chunks {
+                    remove_chunks(chunks);
+                }
+                // Its size or mtime can move without its contents
+                if *previous != state {
+                    self.cache.put(&key, state, entry)?;
+                }
+                return Ok(entry.node.clone());
+            }
+        }
+
+        let chunks = match chunks {
+            Some(chunks) => chunks,
+            None => chunk(path, self.options.hasher)?,
+        };
+        let entry = self.upload(path, &chunks).await;
+        remove_chunks(&chunks);
+        let entry = entry?;
+        self.cache.put(&key, state, &entry)?;
+
+        Ok(entry.node)
+    }
+
+    // Whether a cached node is still around to be reused
+    async fn node_exists(&self, id: &str) -> Result<bool> {
+        match self.client.get_node(id).await {
+            Ok(_) => Ok(true),
+            Err(e) if matches!(e.kind, Kind::NotFound) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn upload(&self, path: &Path, chunks: &[PathBuf]) -> Result<Entry> {
+        let options = transfer::Options {
+            jobs: self.options.jobs,
+            budget: self.options.budget.clone(),
+            // The backup's own bar covers it
+            progress: ProgressBar::hidden(),
+        };
+        let blobs = transfer::upload(self.client, chunks, &options)
+            .await
+            .with_context(|| format!("error uploading {}", path.display()))?;
+
+        let node = self
+            .client
+            .create_node(CreateNodeRequest {
+                namespace: self.client.namespace().to_owned(),
+                node_type: NodeType::File,
+                blobs: blobs.clone(),
+                attributes: Default::default(),
+                allow_dangling: false,
+            })
+            .await?;
+
+        Ok(Entry {
+            node: node.id,
+            blobs,
+        })
+    }
+}
@@ -0,0 +1,292 @@
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anchorage::BlobRef;
+use anyhow::{anyhow, Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+
+// What anc remembers about files it's already uploaded, so unchanged
+// files don't get read again. It lives in ~/.local/share/anchorage/cache.db.
+//
+// Entries are kept per server and namespace, since a node from one
+// means nothing to another.
+
+/// What's checked to decide if a file is unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileState {
+    pub size: u64,
+    pub mtime: i64, // Nanoseconds since the epoch
+    pub inode: u64,
+}
+
+impl FileState {
+    pub fn new(meta: &Metadata) -> Self {
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as i64)
+            .unwrap_or(0);
+
+        Self {
+            size: meta.len(),
+            mtime,
+            inode: inode(meta),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn inode(meta: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.ino()
+}
+
+// Other platforms go by size and mtime alone
+#[cfg(not(unix))]
+fn inode(_: &Metadata) -> u64 {
+    0
+}
+
+/// The node a file was uploaded as, and its chunks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub node: String,
+    pub blobs: Vec<BlobRef>,
+}
+
+pub struct Cache {
+    conn: Connection,
+    remote: String,
+    namespace: String,
+}
+
+// Where the cache lives, respecting XDG_DATA_HOME if it's set
+fn path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share"))
+        })?;
+
+    Some(base.join("anchorage").join("cache.db"))
+}
+
+impl Cache {
+    /// Opens the cache in the user's data directory, creating it if needed.
+    pub fn open(remote: &str, namespace: &str) -> Result<Self> {
+        let path = path().ok_or_else(|| anyhow!("no HOME to keep the cache in"))?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("error creating {}", parent.display()))?;
+        }
+
+        Self::open_at(&path, remote, namespace)
+    }
+
+    pub fn open_at(path: &Path, remote: &str, namespace: &str) -> Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("error opening cache at {}", path.display()))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS files (
+                remote TEXT NOT NULL,
+                namespace TEXT NOT NULL,
+                path TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                mtime INTEGER NOT NULL,
+                inode INTEGER NOT NULL,
+                node TEXT NOT NULL,
+                blobs TEXT NOT NULL,
+                PRIMARY KEY (remote, namespace, path)
+            );
+            CREATE TABLE IF NOT EXISTS directories (
+                remote TEXT NOT NULL,
+                namespace TEXT NOT NULL,
+                path TEXT NOT NULL,
+                node TEXT NOT NULL,
+                listing TEXT NOT NULL,
+                PRIMARY KEY (remote, namespace, path)
+            )",
+        )?;
+
+        Ok(Self {
+            conn,
+            remote: remote.to_owned(),
+            namespace: namespace.to_owned(),
+        })
+    }
+
+    /// What was recorded for the path last time, whether or not it's
+    /// still the same.
+    pub fn get(&self, path: &str) -> Result<Option<(FileState, Entry)>> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT size, mtime, inode, node, blobs FROM files
+                WHERE remote = ?1 AND namespace = ?2 AND path = ?3",
+                params![self.remote, self.namespace, path],
+                |row| {
+                    Ok((
+                        FileState {
+                            size: row.get::<_, i64>(0)? as u64,
+                            mtime: row.get(1)?,
+                            inode: row.get::<_, i64>(2)? as u64,
+                        },
+                        row.get::<_, String>(3)?,
+                        row.get::<_, String>(4)?,
+                    ))
+                },
+            )
+            .optional()?;
+
+        let Some((state, node, blobs)) = row else {
+            return Ok(None);
+        };
+        let entry = Entry {
+            node,
+            blobs: serde_json::from_str(&blobs)
+                .with_context(|| format!("bad chunk list cached for {}", path))?,
+        };
+
+        Ok(Some((state, entry)))
+    }
+
+    pub fn put(&self, path: &str, state: FileState, entry: &Entry) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO files (remote, namespace, path, size, mtime, inode, node, blobs)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                self.remote,
+                self.namespace,
+                path,
+                state.size as i64,
+                state.mtime,
+                state.inode as i64,
+                entry.node,
+                serde_json::to_string(&entry.blobs)?,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn remove(&self, path: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM files WHERE remote = ?1 AND namespace = ?2 AND path = ?3",
+            params![self.remote, self.namespace, path],
+        )?;
+
+        Ok(())
+    }
+
+    /// The node a directory was last uploaded as, with its listing as the
+    /// only blob.
+    pub fn directory(&self, path: &str) -> Result<Option<Entry>> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT node, listing FROM directories
+                WHERE remote = ?1 AND namespace = ?2 AND path = ?3",
+                params![self.remote, self.namespace, path],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()?;
+
+        let Some((node, listing)) = row else {
+            return Ok(None);
+        };
+        let listing =
+            BlobRef::parse(&listing).with_context(|| format!("bad listing cached for {}", path))?;
+
+        Ok(Some(Entry {
+            node,
+            blobs: vec![listing],
+        }))
+    }
+
+    pub fn put_directory(&self, path: &str, node: &str, listing: &BlobRef) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO directories (remote, namespace, path, node, listing)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![self.remote, self.namespace, path, node, listing.as_str()],
+        )?;
+
+        Ok(())
+    }
+
+    /// Every cached path within the directory.
+    pub fn paths_under(&self, dir: &str) -> Result<Vec<String>> {
+        let prefix = format!("{}/", dir.trim_end_matches('/'));
+        let mut stmt = self.conn.prepare(
+            "SELECT path FROM files WHERE remote = ?1 AND namespace = ?2
+            AND substr(path, 1, length(?3)) = ?3",
+        )?;
+        let paths = stmt
+            .query_map(params![self.remote, self.namespace, prefix], |row| {
+                row.get(0)
+            })?
+            .collect::<Result<Vec<String>, _>>()?;
+
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_files_per_remote() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        let state = FileState {
+            size: 10,
+            mtime: 1234,
+            inode: 7,
+        };
+        let entry = Entry {
+            node: String::from("node-a"),
+            blobs: vec![BlobRef::of(b"cat")],
+        };
+
+        let cache = Cache::open_at(&path, "http://one", "default").unwrap();
+        cache.put("/photos/cat.jpg", state, &entry).unwrap();
+        cache.put("/photos2/dog.jpg", state, &entry).unwrap();
+        assert_eq!(
+            cache.get("/photos/cat.jpg").unwrap(),
+            Some((state, entry.clone()))
+        );
+        assert_eq!(
+            cache.paths_under("/photos").unwrap(),
+            vec![String::from("/photos/cat.jpg")]
+        );
+
+        // Another server doesn't see it
+        let other = Cache::open_at(&path, "http://two", "default").unwrap();
+        assert_eq!(other.get("/photos/cat.jpg").unwrap(), None);
+
+        cache.remove("/photos/cat.jpg").unwrap();
+        assert_eq!(cache.get("/photos/cat.jpg").unwrap(), None);
+    }
+
+    #[test]
+    fn remembers_directories_apart_from_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::open_at(&dir.path().join("cache.db"), "http://one", "default").unwrap();
+        let listing = BlobRef::of(b"listing");
+
+        cache.put_directory("/photos", "node-a", &listing).unwrap();
+        assert_eq!(
+            cache.directory("/photos").unwrap(),
+            Some(Entry {
+                node: String::from("node-a"),
+                blobs: vec![listing],
+            })
+        );
+
+        // So they're never taken for deleted files
+        assert_eq!(cache.get("/photos").unwrap(), None);
+        assert!(cache.paths_under("/").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anchorage::blobserver::auth::SigningKey;
+use anchorage::blobserver::client::{Client, ClientTls, RetryPolicy};
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+/**
+ * anc's config, read from ~/.config/anchorage/config.yaml:
+ *
+ * default_profile: home
+ * profiles:
+ *   home:
+ *     remote: https://anchorage.home:4444
+ *     namespace: photos
+ *     identity: james
+ *     key: ~/.config/anchorage/james.pem
+ *     ca_bundle: ~/.config/anchorage/ca.pem
+ *     timeout_secs: 30
+ *     retries: 5
+ **/
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    pub default_profile: Option<String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+/// Everything about how to reach one server. Unset fields fall back to
+/// the client's defaults.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Profile {
+    pub remote: Option<String>,
+    pub namespace: Option<String>,
+    pub identity: Option<String>,
+    pub key: Option<String>, // Path to the private key pem to sign with
+    pub ca_bundle: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub timeout_secs: Option<u64>,
+    pub connect_timeout_secs: Option<u64>,
+    pub retries: Option<u32>,
+}
+
+// Where the config lives, respecting XDG_CONFIG_HOME if it's set
+fn path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(base.join("anchorage").join("config.yaml"))
+}
+
+/// Loads the config, which is empty if there's no file.
+pub fn load() -> Result<Config> {
+    let Some(path) = path() else {
+        return Ok(Config::default());
+    };
+
+    match File::open(&path) {
+        Ok(f) => serde_yaml::from_reader(f)
+            .with_context(|| format!("error parsing config at {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(e) => Err(e).with_context(|| format!("error opening config at {}", path.display())),
+    }
+}
+
+impl Config {
+    /// Picks the profile to use.
+    ///
+    /// The selection is a profile name, or a url to use as the remote with
+    /// nothing else set. Without one, it's the default profile if there is one.
+    pub fn profile(&self, selection: Option<&str>) -> Result<Profile> {
+        let name = match selection {
+            Some(url) if url.contains("://") => {
+                return Ok(Profile {
+                    remote: Some(url.to_owned()),
+                    ..Default::default()
+                })
+            }
+            Some(name) => name,
+            None => match &self.default_profile {
+                Some(name) => name,
+                None => return Ok(Profile::default()),
+            },
+        };
+
+        self.profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("no profile named '{}'", name))
+    }
+}
+
+// Lets paths in the config start with ~
+fn expand(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+fn read(path: &str) -> Result<Vec<u8>> {
+    let path = expand(path);
+    std::fs::read(&path).with_context(|| format!("error reading {}", path.display()))
+}
+
+impl Profile {
+    /// Builds a client talking to the profile's server.
+    pub fn client(&self) -> Result<Client> {
+        let mut builder = Client::builder();
+        if let Some(remote) = &self.remote {
+            builder = builder.remote(remote);
+        }
+        if let Some(namespace) = &self.namespace {
+            builder = builder.namespace(namespace);
+        }
+        if let Some(secs) = self.timeout_secs {
+            builder = builder.timeout(Duration::from_secs(secs));
+        }
+        if let Some(secs) = self.connect_timeout_secs {
+            builder = builder.connect_timeout(Duration::from_secs(secs));
+        }
+        if let Some(retries) = self.retries {
+            builder = builder.retry(RetryPolicy {
+                max_retries: retries,
+                ..Default::default()
+            });
+        }
+
+        match (&self.identity, &self.key) {
+            (Some(identity), Some(key)) => {
+                builder = builder.signing_key(SigningKey::from_pem(identity, &read(key)?)?);
+            }
+            (None, Some(_)) => return Err(anyhow!("a key needs an identity to sign as")),
+            _ => {}
+        }
+
+        let mut tls = ClientTls::default();
+        if let Some(bundle) = &self.ca_bundle {
+            tls.ca_bundle = Some(read(bundle)?);
+        }
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => tls.cert = Some((read(cert)?, read(key)?)),
+            (None, None) => {}
+            _ => return Err(anyhow!("client_cert and client_key go together")),
+        }
+
+        Ok(builder.tls(tls).build()?)
+    }
+}
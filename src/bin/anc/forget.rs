@@ -0,0 +1,201 @@
+use std::collections::HashSet;
+
+use anchorage::{Node, NodeType, Snapshot};
+
+/// Which snapshots to keep. Anything it doesn't keep gets forgotten.
+///
+/// Each rule keeps the newest snapshot from each of the most recent days,
+/// weeks or months that have one, so `keep_daily: 7` keeps a snapshot for
+/// each of the last 7 days something was backed up. A snapshot kept by any
+/// rule is kept.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Policy {
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+}
+
+const SECS_PER_DAY: u64 = 60 * 60 * 24;
+
+// Which day, week or month a time falls in, counting from the epoch
+type Period = fn(u64) -> i64;
+
+fn day(time: u64) -> i64 {
+    (time / SECS_PER_DAY) as i64
+}
+
+// Weeks start on Monday, and the epoch was a Thursday
+fn week(time: u64) -> i64 {
+    (day(time) + 3).div_euclid(7)
+}
+
+fn month(time: u64) -> i64 {
+    let (year, month, _) = civil(day(time));
+    year * 12 + month as i64
+}
+
+/// The year, month and day of a day since the epoch, in UTC.
+///
+/// From http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + i64::from(m <= 2);
+
+    (y, m, d)
+}
+
+/// Formats seconds since the epoch, e.g. `2023-08-20 14:03:09 UTC`.
+pub fn format_time(time: u64) -> String {
+    let (y, m, d) = civil(day(time));
+    let secs = time % SECS_PER_DAY;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        y,
+        m,
+        d,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+impl Policy {
+    /// Whether the policy would keep nothing at all.
+    pub fn is_empty(&self) -> bool {
+        self.keep_daily == 0 && self.keep_weekly == 0 && self.keep_monthly == 0
+    }
+
+    /// The ids of the snapshots to keep.
+    ///
+    /// Snapshots of different directories, or from different hosts, are
+    /// kept separately so one can't push out another.
+    pub fn keep(&self, snapshots: &[Node]) -> HashSet<String> {
+        let mut snapshots: Vec<(&Node, &Snapshot)> = snapshots
+            .iter()
+            .filter_map(|n| match &n.node_type {
+                NodeType::Snapshot(s) => Some((n, s)),
+                _ => None,
+            })
+            .collect();
+        // Newest first, so the newest in each period is the one kept
+        snapshots.sort_by_key(|(_, s)| std::cmp::Reverse(s.time));
+
+        let rules: [(usize, Period); 3] = [
+            (self.keep_daily, day),
+            (self.keep_weekly, week),
+            (self.keep_monthly, month),
+        ];
+
+        let mut keep = HashSet::new();
+        let mut groups: Vec<(&str, &str)> = snapshots
+            .iter()
+            .map(|(_, s)| (s.hostname.as_str(), s.path.as_str()))
+            .collect();
+        groups.sort();
+        groups.dedup();
+
+        for group in groups {
+            let group: Vec<_> = snapshots
+                .iter()
+                .filter(|(_, s)| (s.hostname.as_str(), s.path.as_str()) == group)
+                .collect();
+
+            for (count, period) in rules {
+                let mut last = None;
+                let mut kept = 0;
+                for (node, snapshot) in &group {
+                    if kept == count {
+                        break;
+                    }
+
+                    let p = period(snapshot.time);
+                    if last != Some(p) {
+                        keep.insert(node.id.clone());
+                        last = Some(p);
+                        kept += 1;
+                    }
+                }
+            }
+        }
+
+        keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(id: &str, path: &str, time: u64) -> Node {
+        Node {
+            id: id.to_owned(),
+            namespace: String::from("default"),
+            node_type: NodeType::Snapshot(Snapshot {
+                root: String::from("root"),
+                time,
+                hostname: String::from("laptop"),
+                path: path.to_owned(),
+                tags: vec![],
+                parent: None,
+            }),
+            blobs: vec![],
+            attributes: Default::default(),
+            revision: 1,
+            updated: time,
+            author: None,
+        }
+    }
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_time(1_692_540_189), "2023-08-20 14:03:09 UTC");
+        assert_eq!(civil(day(951_782_400)), (2000, 2, 29));
+    }
+
+    #[test]
+    fn keeps_newest_per_period() {
+        // 2023-08-20 is a Sunday
+        let sunday = 1_692_489_600;
+        let snapshots = vec![
+            snapshot("sun-late", "/photos", sunday + 7200),
+            snapshot("sun-early", "/photos", sunday + 3600),
+            snapshot("sat", "/photos", sunday - SECS_PER_DAY),
+            snapshot("mon", "/photos", sunday - 6 * SECS_PER_DAY),
+            snapshot("july", "/photos", sunday - 30 * SECS_PER_DAY),
+            snapshot("other", "/music", sunday - 30 * SECS_PER_DAY),
+        ];
+
+        let policy = Policy {
+            keep_daily: 2,
+            ..Default::default()
+        };
+        let mut kept: Vec<_> = policy.keep(&snapshots).into_iter().collect();
+        kept.sort();
+        assert_eq!(kept, vec!["other", "sat", "sun-late"]);
+
+        // Monday through Sunday is one week
+        let policy = Policy {
+            keep_weekly: 2,
+            ..Default::default()
+        };
+        let mut kept: Vec<_> = policy.keep(&snapshots).into_iter().collect();
+        kept.sort();
+        assert_eq!(kept, vec!["july", "other", "sun-late"]);
+
+        let policy = Policy {
+            keep_monthly: 12,
+            ..Default::default()
+        };
+        let mut kept: Vec<_> = policy.keep(&snapshots).into_iter().collect();
+        kept.sort();
+        assert_eq!(kept, vec!["july", "other", "sun-late"]);
+    }
+}
@@ -0,0 +1,362 @@
+use anchorage::blobserver::server;
+use anchorage::{BlobRef, NodeType, Snapshot};
+use anyhow::{anyhow, Context, Result};
+use clap::{arg, value_parser, Arg, ArgAction, ArgMatches, Command};
+use indicatif::ProgressBar;
+use output::{
+    BackupReport, BlobReport, ForgetReport, Format, GetNodeReport, NodeOutput, PutReport,
+    SnapshotOutput, SnapshotsReport,
+};
+
+use std::fs::File;
+use std::io::{stdin, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod backup;
+mod cache;
+mod config;
+mod forget;
+mod output;
+mod transfer;
+
+fn cli() -> Command {
+    Command::new("anc")
+        .version("0.1.0")
+        .author("James H. <jamesdholdren@gmail.com>")
+        .about("interacts with a given anchorage server")
+        .subcommand_required(true)
+        .arg(
+            arg!(-o --output <format> "how to print results")
+                .global(true)
+                .value_parser(value_parser!(Format))
+                .default_value("text"),
+        )
+        .arg(
+            arg!(-p --profile <profile> "the profile to use from the config, or a server url")
+                .global(true),
+        )
+        .arg(arg!(-n --namespace <namespace> "the namespace to act in").global(true))
+        .arg(arg!(--identity <identity> "who to sign requests as").global(true))
+        .arg(
+            arg!(--key <key> "path to the private key pem to sign requests with")
+                .global(true)
+                .requires("identity"),
+        )
+        .subcommand(Command::new("put").subcommand_required(true).subcommand(
+            transfer_args(Command::new("blob")).arg(arg!([blob_location]).required(false)),
+        ))
+        .subcommand(
+            Command::new("get").subcommand_required(true).subcommand(
+                transfer_args(Command::new("node"))
+                    .about("downloads the file a node refers to")
+                    .arg(arg!([id]).required(true))
+                    .arg(arg!([destination] "where to write the file, stdout if not given")),
+            ),
+        )
+        .subcommand(
+            transfer_args(Command::new("backup"))
+                .about("backs up a directory, skipping files that haven't changed")
+                .arg(arg!(<dir> "the directory to back up"))
+                .arg(arg!(--"no-cache" "re-read every file instead of reusing unchanged ones"))
+                .arg(arg!(--verify "re-hash unchanged files to check they can be reused"))
+                .arg(
+                    arg!(--tag <tag> "a tag for the snapshot, can be given more than once")
+                        .action(ArgAction::Append),
+                ),
+        )
+        .subcommand(Command::new("snapshots").about("lists snapshots, oldest first"))
+        .subcommand(
+            Command::new("forget")
+                .about("forgets snapshots the policy doesn't keep, leaving their blobs")
+                .arg(keep_arg(
+                    "keep-daily",
+                    "how many days to keep the newest snapshot of",
+                ))
+                .arg(keep_arg(
+                    "keep-weekly",
+                    "how many weeks to keep the newest snapshot of",
+                ))
+                .arg(keep_arg(
+                    "keep-monthly",
+                    "how many months to keep the newest snapshot of",
+                ))
+                .arg(arg!(--"dry-run" "only report what would be forgotten")),
+        )
+        .subcommand(
+            Command::new("get-blob")
+                .about("gets a blob from the server")
+                .arg(
+                    arg!([hash])
+                        .required(true)
+                        .value_parser(value_parser!(BlobRef)),
+                ),
+        )
+}
+
+// Flags for commands that move chunks around
+fn transfer_args(cmd: Command) -> Command {
+    cmd.arg(
+        arg!(-j --jobs <jobs> "how many chunks to transfer at once")
+            .value_parser(value_parser!(usize))
+            .default_value("4"),
+    )
+    .arg(
+        Arg::new("memory")
+            .long("memory")
+            .help("how many MB of chunks to hold in memory at once")
+            .value_parser(value_parser!(usize))
+            .default_value("64"),
+    )
+}
+
+fn keep_arg(name: &'static str, help: &'static str) -> Arg {
+    Arg::new(name)
+        .long(name)
+        .help(help)
+        .value_parser(value_parser!(usize))
+        .default_value("0")
+}
+
+fn transfer_options(matches: &ArgMatches, progress: ProgressBar) -> transfer::Options {
+    let jobs = *matches.get_one::<usize>("jobs").unwrap();
+    let memory = *matches.get_one::<usize>("memory").unwrap();
+    transfer::Options {
+        jobs,
+        budget: transfer::Budget::new(memory * 1024 * 1024),
+        progress,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let matches = cli().get_matches();
+    let format = *matches.get_one::<Format>("output").unwrap();
+
+    if let Err(err) = run(&matches, format).await {
+        std::process::exit(output::print_error(format, &err));
+    }
+}
+
+async fn run(matches: &ArgMatches, format: Format) -> Result<()> {
+    // The flag wins over the environment when picking a profile
+    let selection = matches
+        .get_one::<String>("profile")
+        .cloned()
+        .or_else(|| std::env::var("ANC_REMOTE").ok());
+    let mut profile = config::load()?.profile(selection.as_deref())?;
+
+    // And flags win over anything in the profile
+    if let Some(namespace) = matches.get_one::<String>("namespace") {
+        profile.namespace = Some(namespace.clone());
+    }
+    if let Some(identity) = matches.get_one::<String>("identity") {
+        profile.identity = Some(identity.clone());
+    }
+    if let Some(key) = matches.get_one::<String>("key") {
+        profile.key = Some(key.clone());
+    }
+    let client = profile.client()?;
+
+    match matches.subcommand() {
+        Some(("put", submatches)) => {
+            match submatches.subcommand() {
+                Some(("blob", submatches)) => {
+                    let mut is_file = false;
+                    // Read from either std in or read in the file
+                    let mut reader: Box<dyn Read> = if let Some(blob_location) =
+                        submatches.get_one::<String>("blob_location")
+                    {
+                        is_file = true;
+                        Box::new(File::open(blob_location)?)
+                    } else {
+                        // Read a max of 2MB from std in
+                        Box::new(stdin())
+                    };
+
+                    let hasher = transfer::hasher(&client).await?;
+                    let files = anchorage::chunk::create_chunks(&mut reader, hasher)?;
+                    let total = files
+                        .iter()
+                        .map(|(_, f)| f.metadata().map(|m| m.len()).unwrap_or(0))
+                        .sum();
+                    let paths: Vec<PathBuf> =
+                        files.into_iter().map(|(path, _)| path.into()).collect();
+                    let progress = output::byte_progress("uploading", total);
+                    let blobs =
+                        transfer::upload(&client, &paths, &transfer_options(submatches, progress))
+                            .await?;
+
+                    // If it's a file, the command should also create a node referring to the
+                    // chunks created.
+                    let mut node = None;
+                    if is_file {
+                        node = Some(
+                            client
+                                .create_node(server::CreateNodeRequest {
+                                    namespace: client.namespace().to_owned(),
+                                    node_type: NodeType::File,
+                                    blobs: blobs.clone(),
+                                    attributes: Default::default(),
+                                    allow_dangling: false,
+                                })
+                                .await?,
+                        );
+                    }
+
+                    let node = node.as_ref().map(NodeOutput::from);
+                    output::print(format, &PutReport { blobs, node })?;
+                }
+                _ => unreachable!(),
+            }
+        }
+        Some(("get", submatches)) => match submatches.subcommand() {
+            Some(("node", submatches)) => {
+                let id = submatches.get_one::<String>("id").unwrap();
+                let node = client.get_node(id).await?;
+
+                let destination = submatches.get_one::<String>("destination");
+                let mut out: Box<dyn Write> = match destination {
+                    Some(path) => Box::new(File::create(path)?),
+                    None => Box::new(std::io::stdout()),
+                };
+                let progress = output::chunk_progress("downloading", node.blobs.len() as u64);
+                let bytes = transfer::download(
+                    &client,
+                    &node.blobs,
+                    &mut out,
+                    &transfer_options(submatches, progress),
+                )
+                .await?;
+                out.flush()?;
+
+                // When the file went to stdout, there's no room for anything else
+                if let Some(path) = destination {
+                    let report = GetNodeReport {
+                        node: node.id,
+                        blobs: node.blobs.len(),
+                        bytes,
+                        destination: path.clone(),
+                    };
+                    output::print(format, &report)?;
+                }
+            }
+            _ => unreachable!(),
+        },
+        Some(("backup", submatches)) => {
+            let dir = submatches.get_one::<String>("dir").unwrap();
+            let cache = cache::Cache::open(client.remote(), client.namespace())?;
+            let transfer = transfer_options(submatches, ProgressBar::hidden());
+            let options = backup::Options {
+                jobs: transfer.jobs,
+                budget: transfer.budget,
+                no_cache: submatches.get_flag("no-cache"),
+                verify: submatches.get_flag("verify"),
+                progress: output::spinner("backing up"),
+                hasher: transfer::hasher(&client).await?,
+            };
+
+            let dir = Path::new(dir)
+                .canonicalize()
+                .with_context(|| format!("error finding {}", dir))?;
+            let (root, changes) = backup::backup(&client, &cache, &dir, &options).await?;
+
+            // The last snapshot of the same directory is this one's parent
+            let hostname = hostname::get()?.to_string_lossy().into_owned();
+            let path = dir.to_string_lossy().into_owned();
+            let parent = client
+                .list_snapshots()
+                .await?
+                .into_iter()
+                .rev()
+                .find(|n| match &n.node_type {
+                    NodeType::Snapshot(s) => s.hostname == hostname && s.path == path,
+                    _ => false,
+                })
+                .map(|n| n.id);
+            let snapshot = Snapshot {
+                root: root.id.clone(),
+                time: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                hostname,
+                path,
+                tags: submatches
+                    .get_many::<String>("tag")
+                    .unwrap_or_default()
+                    .cloned()
+                    .collect(),
+                parent,
+            };
+            let snapshot = client
+                .create_node(server::CreateNodeRequest {
+                    namespace: client.namespace().to_owned(),
+                    node_type: NodeType::Snapshot(snapshot),
+                    blobs: vec![],
+                    attributes: Default::default(),
+                    allow_dangling: false,
+                })
+                .await?;
+
+            let report = BackupReport {
+                root: NodeOutput::from(&root),
+                snapshot: SnapshotOutput::from_node(&snapshot)
+                    .context("server didn't make a snapshot")?,
+                changes,
+            };
+            output::print(format, &report)?;
+        }
+        Some(("snapshots", _)) => {
+            let snapshots = client
+                .list_snapshots()
+                .await?
+                .iter()
+                .filter_map(SnapshotOutput::from_node)
+                .collect();
+            output::print(format, &SnapshotsReport { snapshots })?;
+        }
+        Some(("forget", submatches)) => {
+            let policy = forget::Policy {
+                keep_daily: *submatches.get_one::<usize>("keep-daily").unwrap(),
+                keep_weekly: *submatches.get_one::<usize>("keep-weekly").unwrap(),
+                keep_monthly: *submatches.get_one::<usize>("keep-monthly").unwrap(),
+            };
+            if policy.is_empty() {
+                return Err(anyhow!(
+                    "the policy would forget every snapshot, give it something to keep"
+                ));
+            }
+            let dry_run = submatches.get_flag("dry-run");
+
+            let snapshots = client.list_snapshots().await?;
+            let keep = policy.keep(&snapshots);
+            let mut report = ForgetReport {
+                kept: vec![],
+                forgotten: vec![],
+                dry_run,
+            };
+            for node in snapshots {
+                if keep.contains(&node.id) {
+                    report.kept.push(node.id);
+                    continue;
+                }
+
+                if !dry_run {
+                    client.delete_node(&node.id).await?;
+                }
+                report.forgotten.push(node.id);
+            }
+            output::print(format, &report)?;
+        }
+        Some(("get-blob", submatches)) => {
+            let hash = submatches.get_one::<BlobRef>("hash").unwrap();
+            let resp = client.get_blob(hash).await?;
+            let report = BlobReport {
+                id: hash.clone(),
+                contents: resp.contents,
+            };
+            output::print(format, &report)?;
+        }
+        _ => unreachable!(),
+    };
+
+    Ok(())
+}
@@ -0,0 +1,395 @@
+use std::io::IsTerminal;
+
+use anchorage::error::{Error, Kind};
+use anchorage::{BlobRef, Node, NodeType};
+
+use crate::backup::Changes;
+use crate::forget;
+use anyhow::Result;
+use base64::{engine::general_purpose, Engine as _};
+use clap::{builder::PossibleValue, ValueEnum};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+
+/// How results get printed. Json is meant for scripts, so its shape
+/// should only ever grow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl ValueEnum for Format {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Format::Text, Format::Json]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Format::Text => PossibleValue::new("text"),
+            Format::Json => PossibleValue::new("json"),
+        })
+    }
+}
+
+// Exit codes, by the kind of error that stopped the command. 2 is left for
+// clap's usage errors, and anything that isn't from the server is 1.
+const EXIT_OTHER: i32 = 1;
+const EXIT_NOT_FOUND: i32 = 3;
+const EXIT_PERMISSION: i32 = 4;
+const EXIT_BAD_REQUEST: i32 = 5;
+const EXIT_INTERNAL: i32 = 6;
+const EXIT_INSUFFICIENT_STORAGE: i32 = 7;
+const EXIT_CONFLICT: i32 = 8;
+
+/// What a command prints when it succeeds.
+pub trait Report: Serialize {
+    fn text(&self) -> String;
+}
+
+pub fn print(format: Format, report: &impl Report) -> Result<()> {
+    match format {
+        Format::Text => println!("{}", report.text()),
+        Format::Json => println!("{}", serde_json::to_string(report)?),
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct ErrorReport {
+    error: ErrorBody,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    kind: Option<Kind>, // Only set if the error came from the server
+    message: String,
+}
+
+// The chain of what went wrong, using just the message of any server
+// errors since the kind is already reported on its own
+fn message(err: &anyhow::Error) -> String {
+    err.chain()
+        .map(|e| match e.downcast_ref::<Error>() {
+            Some(e) => e.message.clone(),
+            None => e.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+/// Prints the error to stderr, returning the code to exit with.
+pub fn print_error(format: Format, err: &anyhow::Error) -> i32 {
+    let kind = err.downcast_ref::<Error>().map(|e| e.kind.clone());
+
+    match format {
+        Format::Text => eprintln!("Error: {:#}", err),
+        Format::Json => {
+            let report = ErrorReport {
+                error: ErrorBody {
+                    kind: kind.clone(),
+                    message: message(err),
+                },
+            };
+            eprintln!("{}", serde_json::to_string(&report).unwrap());
+        }
+    }
+
+    match kind {
+        None => EXIT_OTHER,
+        Some(Kind::NotFound) => EXIT_NOT_FOUND,
+        Some(Kind::Permission | Kind::Unauthenticated) => EXIT_PERMISSION,
+        Some(Kind::BadRequest) => EXIT_BAD_REQUEST,
+        Some(Kind::Internal) => EXIT_INTERNAL,
+        Some(Kind::InsufficientStorage) => EXIT_INSUFFICIENT_STORAGE,
+        Some(Kind::Conflict) => EXIT_CONFLICT,
+    }
+}
+
+/// A progress bar for moving bytes, only drawn when someone's watching.
+pub fn byte_progress(verb: &str, total: u64) -> ProgressBar {
+    if !std::io::stdout().is_terminal() {
+        return ProgressBar::hidden();
+    }
+
+    let bar = ProgressBar::new(total);
+    bar.set_style(
+        ProgressStyle::with_template(&format!(
+            "{} [{{bar:30}}] {{bytes}}/{{total_bytes}} {{binary_bytes_per_sec}} eta {{eta}}",
+            verb
+        ))
+        .unwrap()
+        .progress_chars("=> "),
+    );
+    bar
+}
+
+/// A progress bar for when only the number of chunks is known up front.
+/// Throughput goes in the message as bytes come in.
+pub fn chunk_progress(verb: &str, chunks: u64) -> ProgressBar {
+    if !std::io::stdout().is_terminal() {
+        return ProgressBar::hidden();
+    }
+
+    let bar = ProgressBar::new(chunks);
+    bar.set_style(
+        ProgressStyle::with_template(&format!(
+            "{} [{{bar:30}}] {{pos}}/{{len}} chunks {{msg}} eta {{eta}}",
+            verb
+        ))
+        .unwrap()
+        .progress_chars("=> "),
+    );
+    bar
+}
+
+/// A spinner for when not even the number of things to do is known.
+pub fn spinner(verb: &str) -> ProgressBar {
+    if !std::io::stdout().is_terminal() {
+        return ProgressBar::hidden();
+    }
+
+    let bar = ProgressBar::new_spinner();
+    bar.set_style(
+        ProgressStyle::with_template(&format!("{{spinner}} {} {{pos}} files {{msg}}", verb))
+            .unwrap(),
+    );
+    bar.enable_steady_tick(std::time::Duration::from_millis(100));
+    bar
+}
+
+/// A node as it's printed, kept apart from the server's own type so the
+/// json doesn't change whenever that does.
+#[derive(Serialize)]
+pub struct NodeOutput {
+    pub id: String,
+    pub namespace: String,
+    pub node_type: &'static str, // File, Directory or Snapshot
+    pub blobs: Vec<BlobRef>,
+    pub revision: u64,
+}
+
+impl From<&Node> for NodeOutput {
+    fn from(node: &Node) -> Self {
+        Self {
+            id: node.id.clone(),
+            namespace: node.namespace.clone(),
+            node_type: match node.node_type {
+                NodeType::File => "File",
+                NodeType::Directory => "Directory",
+                NodeType::Snapshot(_) => "Snapshot",
+            },
+            blobs: node.blobs.clone(),
+            revision: node.revision,
+        }
+    }
+}
+
+/// A snapshot as it's printed.
+#[derive(Serialize)]
+pub struct SnapshotOutput {
+    pub id: String,
+    pub root: String, // The directory node that was backed up
+    pub time: u64,    // Seconds since the epoch
+    pub hostname: String,
+    pub path: String,
+    pub tags: Vec<String>,
+    pub parent: Option<String>,
+}
+
+impl SnapshotOutput {
+    /// None if the node isn't a snapshot.
+    pub fn from_node(node: &Node) -> Option<Self> {
+        let NodeType::Snapshot(snapshot) = &node.node_type else {
+            return None;
+        };
+
+        Some(Self {
+            id: node.id.clone(),
+            root: snapshot.root.clone(),
+            time: snapshot.time,
+            hostname: snapshot.hostname.clone(),
+            path: snapshot.path.clone(),
+            tags: snapshot.tags.clone(),
+            parent: snapshot.parent.clone(),
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct PutReport {
+    pub blobs: Vec<BlobRef>,
+    pub node: Option<NodeOutput>, // Only created when putting a file
+}
+
+impl Report for PutReport {
+    fn text(&self) -> String {
+        let mut lines: Vec<String> = self.blobs.iter().map(BlobRef::to_string).collect();
+        if let Some(node) = &self.node {
+            lines.push(format!("node {}", node.id));
+        }
+        lines.join("\n")
+    }
+}
+
+#[derive(Serialize)]
+pub struct GetNodeReport {
+    pub node: String,
+    pub blobs: usize,
+    pub bytes: u64,
+    pub destination: String,
+}
+
+impl Report for GetNodeReport {
+    fn text(&self) -> String {
+        format!(
+            "wrote {} bytes from {} blobs of node {} to {}",
+            self.bytes, self.blobs, self.node, self.destination
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct BlobReport {
+    pub id: BlobRef,
+    pub contents: String, // Base64 encoded
+}
+
+impl Report for BlobReport {
+    // Plain text if it's utf8, otherwise the base64
+    fn text(&self) -> String {
+        general_purpose::STANDARD_NO_PAD
+            .decode(&self.contents)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .unwrap_or_else(|| self.contents.clone())
+    }
+}
+
+#[derive(Serialize)]
+pub struct BackupReport {
+    pub root: NodeOutput,
+    pub snapshot: SnapshotOutput,
+    pub changes: Changes,
+}
+
+impl Report for BackupReport {
+    // Lists what's different, but only counts what isn't
+    fn text(&self) -> String {
+        let mut lines = vec![];
+        lines.extend(self.changes.new.iter().map(|p| format!("+ {}", p)));
+        lines.extend(self.changes.changed.iter().map(|p| format!("~ {}", p)));
+        lines.extend(self.changes.deleted.iter().map(|p| format!("- {}", p)));
+        lines.push(format!(
+            "{} new, {} changed, {} unchanged, {} deleted",
+            self.changes.new.len(),
+            self.changes.changed.len(),
+            self.changes.unchanged.len(),
+            self.changes.deleted.len()
+        ));
+        lines.push(format!("snapshot {}", self.snapshot.id));
+        lines.join("\n")
+    }
+}
+
+#[derive(Serialize)]
+pub struct SnapshotsReport {
+    pub snapshots: Vec<SnapshotOutput>, // Oldest first
+}
+
+impl Report for SnapshotsReport {
+    fn text(&self) -> String {
+        self.snapshots
+            .iter()
+            .map(|s| {
+                format!(
+                    "{}  {}  {}:{}  {}",
+                    s.id,
+                    forget::format_time(s.time),
+                    s.hostname,
+                    s.path,
+                    s.tags.join(",")
+                )
+            })
+            .map(|line| line.trim_end().to_owned())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[derive(Serialize)]
+pub struct ForgetReport {
+    pub kept: Vec<String>,
+    pub forgotten: Vec<String>,
+    pub dry_run: bool, // Nothing was actually forgotten
+}
+
+impl Report for ForgetReport {
+    fn text(&self) -> String {
+        let verb = if self.dry_run {
+            "would forget"
+        } else {
+            "forgot"
+        };
+        let mut lines: Vec<String> = self
+            .forgotten
+            .iter()
+            .map(|id| format!("{} {}", verb, id))
+            .collect();
+        lines.push(format!(
+            "kept {}, {} {}",
+            self.kept.len(),
+            verb,
+            self.forgotten.len()
+        ));
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchorage::Snapshot;
+
+    #[test]
+    fn snapshots_print_their_own_shape() {
+        let node = Node {
+            id: String::from("abc"),
+            namespace: String::from("photos"),
+            node_type: NodeType::Snapshot(Snapshot {
+                root: String::from("def"),
+                time: 1700000000,
+                hostname: String::from("laptop"),
+                path: String::from("/home/james"),
+                tags: vec![String::from("nightly")],
+                parent: None,
+            }),
+            blobs: vec![],
+            attributes: Default::default(),
+            revision: 3,
+            updated: 1700000001,
+            author: Some(String::from("james")),
+        };
+
+        let json = serde_json::to_value(NodeOutput::from(&node)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "id": "abc",
+                "namespace": "photos",
+                "node_type": "Snapshot",
+                "blobs": [],
+                "revision": 3,
+            })
+        );
+
+        let snapshot = SnapshotOutput::from_node(&node).unwrap();
+        assert_eq!(snapshot.root, "def");
+        let node = Node {
+            node_type: NodeType::File,
+            ..node
+        };
+        assert!(SnapshotOutput::from_node(&node).is_none());
+    }
+}
@@ -0,0 +1,167 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anchorage::blobserver::client::Client;
+use anchorage::chunk::MAX_FILE_SIZE;
+use anchorage::error::Kind;
+use anchorage::hash::{self, Hasher};
+use anchorage::BlobRef;
+use anyhow::{anyhow, Result};
+use futures::{stream, StreamExt};
+use indicatif::{HumanBytes, ProgressBar};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Caps how many bytes of chunk data are held in memory at once,
+/// across every job.
+#[derive(Clone)]
+pub struct Budget {
+    permits: Arc<Semaphore>,
+    max: u32,
+}
+
+impl Budget {
+    pub fn new(bytes: usize) -> Self {
+        let max = u32::try_from(bytes).unwrap_or(u32::MAX).max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(max as usize)),
+            max,
+        }
+    }
+
+    // Waits until the bytes fit. Anything bigger than the whole budget
+    // waits for all of it, so it still goes through, just alone.
+    async fn reserve(&self, bytes: usize) -> OwnedSemaphorePermit {
+        let wanted = u32::try_from(bytes).unwrap_or(u32::MAX).min(self.max);
+        self.permits
+            .clone()
+            .acquire_many_owned(wanted)
+            .await
+            .expect("budget semaphore is never closed")
+    }
+}
+
+/// The hash algorithm the server wants chunks named with. Servers from
+/// before there was a choice only know the default.
+pub async fn hasher(client: &Client) -> Result<&'static dyn Hasher> {
+    match client.server_info().await {
+        Ok(info) => hash::algorithm(&info.hash_algorithm).ok_or_else(|| {
+            anyhow!(
+                "server wants unknown hash algorithm {}",
+                info.hash_algorithm
+            )
+        }),
+        Err(e) if matches!(e.kind, Kind::NotFound) => Ok(hash::DEFAULT),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// The blob id of a chunk file, which is its name
+fn chunk_id(path: &Path) -> Result<BlobRef> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("chunk {} has no name", path.display()))?;
+    Ok(BlobRef::parse(&name.to_string_lossy())?)
+}
+
+/// How a transfer gets spread out.
+pub struct Options {
+    pub jobs: usize,
+    pub budget: Budget,
+    // Sized by the caller, since they know what the total is
+    pub progress: ProgressBar,
+}
+
+// The error for the first chunk that fails, which stops everything else.
+//
+// The original error is kept underneath so its kind can still be found.
+fn failed(
+    verb: &str,
+    index: usize,
+    total: usize,
+    done: usize,
+    err: anyhow::Error,
+) -> anyhow::Error {
+    err.context(format!(
+        "{} chunk {} of {} failed ({} finished before it)",
+        verb,
+        index + 1,
+        total,
+        done
+    ))
+}
+
+/// Uploads chunk files with up to `jobs` at a time, returning the blob ids
+/// in the same order as the chunks so the node comes out right.
+///
+/// The chunks are stored under the ids they're named with.
+pub async fn upload(
+    client: &Client,
+    chunks: &[PathBuf],
+    options: &Options,
+) -> Result<Vec<BlobRef>> {
+    let mut results = stream::iter(chunks.iter().enumerate())
+        .map(|(i, path)| async move {
+            let res = async {
+                let size = tokio::fs::metadata(path).await?.len() as usize;
+                let _permit = options.budget.reserve(size).await;
+                let data = tokio::fs::read(path).await?;
+                let id = client.put_blob_as(&chunk_id(path)?, &data).await?.created;
+                options.progress.inc(size as u64);
+                Ok::<_, anyhow::Error>(id)
+            };
+            (i, res.await)
+        })
+        .buffered(options.jobs.max(1));
+
+    let mut blobs = Vec::with_capacity(chunks.len());
+    while let Some((i, res)) = results.next().await {
+        // Dropping the stream on the way out cancels whatever's in flight
+        let id = res.map_err(|e| failed("uploading", i, chunks.len(), blobs.len(), e))?;
+        blobs.push(id);
+    }
+    options.progress.finish_and_clear();
+
+    Ok(blobs)
+}
+
+/// Downloads blobs with up to `jobs` at a time, writing them to `out` in
+/// order. Returns how many bytes were written.
+///
+/// Progress is counted in chunks, since their sizes aren't known up front.
+pub async fn download<W: Write>(
+    client: &Client,
+    blobs: &[BlobRef],
+    out: &mut W,
+    options: &Options,
+) -> Result<u64> {
+    let mut results = stream::iter(blobs.iter().enumerate())
+        .map(|(i, id)| async move {
+            // Sizes aren't known up front, but no chunk is bigger than this
+            let permit = options.budget.reserve(MAX_FILE_SIZE).await;
+            let res = async { Ok::<_, anyhow::Error>(client.get_blob(id).await?.data()?) };
+            // The permit is held until the data's been written out
+            (i, res.await, permit)
+        })
+        .buffered(options.jobs.max(1));
+
+    let mut written = 0;
+    let mut done = 0;
+    while let Some((i, res, _permit)) = results.next().await {
+        let data = res.map_err(|e| failed("downloading", i, blobs.len(), done, e))?;
+        out.write_all(&data)?;
+        written += data.len() as u64;
+        done += 1;
+
+        let secs = options.progress.elapsed().as_secs_f64().max(0.001);
+        options.progress.set_message(format!(
+            "{} at {}/s",
+            HumanBytes(written),
+            HumanBytes((written as f64 / secs) as u64)
+        ));
+        options.progress.inc(1);
+    }
+    options.progress.finish_and_clear();
+
+    Ok(written)
+}
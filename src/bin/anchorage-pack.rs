@@ -0,0 +1,55 @@
+use anchorage::storage::Pack;
+use anchorage::BlobRef;
+use clap::{arg, Command};
+
+// Maintenance for a pack store: deleting blobs from it, and compacting
+// away the space deleted and rewritten ones still take up. anchoraged
+// compacts on its own every so often too.
+//
+// Stop anchoraged before running it.
+fn main() {
+    let matches = Command::new("anchorage-pack")
+        .version("0.1.0")
+        .about("deletes blobs from and compacts a pack store")
+        .arg(arg!(<directory> "the store's directory"))
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("delete")
+                .about("forgets blobs, until the next compaction gives back their space")
+                .arg(arg!(<ids> ... "the ids of the blobs")),
+        )
+        .subcommand(Command::new("compact").about("rewrites the packs with deleted blobs in them"))
+        .get_matches();
+
+    let directory = matches.get_one::<String>("directory").unwrap();
+    let pack = Pack::open(directory, false).unwrap_or_else(|e| {
+        eprintln!("error opening {}: {:?}", directory, e);
+        std::process::exit(1);
+    });
+
+    match matches.subcommand() {
+        Some(("delete", sub)) => {
+            for id in sub.get_many::<String>("ids").unwrap() {
+                let deleted = BlobRef::parse(id)
+                    .map_err(|e| e.message)
+                    .and_then(|id| pack.delete(&id).map_err(|e| format!("{:?}", e)));
+                if let Err(e) = deleted {
+                    eprintln!("error deleting {}: {}", id, e);
+                    std::process::exit(1);
+                }
+                println!("deleted {}", id);
+            }
+        }
+        Some(("compact", _)) => match pack.compact() {
+            Ok(compacted) => println!(
+                "removed {} packs, reclaiming {} bytes",
+                compacted.packs_removed, compacted.bytes_reclaimed
+            ),
+            Err(e) => {
+                eprintln!("error compacting {}: {:?}", directory, e);
+                std::process::exit(1);
+            }
+        },
+        _ => unreachable!(),
+    }
+}
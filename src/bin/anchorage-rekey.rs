@@ -0,0 +1,35 @@
+use anchorage::hash;
+use anchorage::storage::Local;
+use clap::{arg, Command};
+
+// Moves every blob in a local store over to another hash algorithm. The old
+// ids keep working through the store's alias table, so nodes don't need
+// rewriting.
+//
+// Stop anchoraged before running it.
+fn main() {
+    let matches = Command::new("anchorage-rekey")
+        .version("0.1.0")
+        .about("re-hashes a local store's blobs with another algorithm")
+        .arg(arg!(<directory> "the store's directory"))
+        .arg(arg!(--to <algorithm> "the algorithm to move to, e.g. blake3").required(true))
+        .get_matches();
+
+    let directory = matches.get_one::<String>("directory").unwrap();
+    let name = matches.get_one::<String>("to").unwrap();
+    let Some(hasher) = hash::algorithm(name) else {
+        eprintln!("unknown hash algorithm {}", name);
+        std::process::exit(1);
+    };
+
+    match Local::new(directory.clone()).rekey(hasher) {
+        Ok(rekeyed) => println!(
+            "moved {} blobs to {}, {} already were",
+            rekeyed.moved, name, rekeyed.skipped
+        ),
+        Err(e) => {
+            eprintln!("error rekeying {}: {:?}", directory, e);
+            std::process::exit(1);
+        }
+    }
+}
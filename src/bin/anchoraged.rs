@@ -1,10 +1,13 @@
+use std::collections::HashMap;
 use std::fs::File;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 
 use axum::{
-    extract::State,
+    extract::{MatchedPath, State},
     http::StatusCode,
     middleware::{self, Next},
     response::IntoResponse,
@@ -12,10 +15,16 @@ use axum::{
     Json, Router,
 };
 use hyper::Request;
+use openssl::pkey::PKey;
 use tokio::time::Instant;
 
-use anchorage::{blobserver::server, NodeStore};
-use anchorage::{storage, Storage};
+use anchorage::blobserver::{
+    auth, changes::ChangeLog, client::Client, idempotency::IdempotencyKeys, metrics::Metrics,
+    namespaces::BlobNamespaces, replication, server, snapshots::Snapshots, tls,
+};
+use anchorage::hash::{self, Hasher};
+use anchorage::{storage, Acl, NamespaceAcl, Storage, WILDCARD};
+use anchorage::{AclStore, NodeStore};
 use tracing::{error, info};
 
 /**
@@ -26,45 +35,310 @@ use tracing::{error, info};
 struct Config {
     port: u16,
     storage: StorageConfig,
+    // If given, replaces whatever acl the store has on startup
+    #[serde(default)]
+    acl: Option<Acl>,
+    #[serde(default)]
+    auth: AuthConfig,
+    // Serves https instead of plain http when given
+    #[serde(default)]
+    tls: Option<tls::TlsConfig>,
+    // How long node creates are remembered by their idempotency key
+    #[serde(default = "default_idempotency_window_secs")]
+    idempotency_window_secs: u64,
+    // How many idempotency keys are remembered at once, the oldest going
+    // first past it
+    #[serde(default = "default_idempotency_max_keys")]
+    idempotency_max_keys: usize,
+    // What blobs are hashed with when clients don't say, e.g. sha256 or blake3
+    #[serde(default)]
+    hash_algorithm: Option<String>,
+    // Where the log of changes peers follow is kept. Without one it's only
+    // in memory, and peers go back over everything whenever this restarts.
+    #[serde(default)]
+    changes_path: Option<String>,
+    // Peers to copy blobs and nodes to and from
+    #[serde(default)]
+    replication: Option<ReplicationConfig>,
+    // How often the store's size is updated for metrics, which goes over
+    // every blob in it
+    #[serde(default = "default_metrics_refresh_secs")]
+    metrics_refresh_secs: u64,
+    // Only set for the default config, where anyone can do anything. The
+    // open acl is kept in memory, never over whatever acl the store has.
+    #[serde(skip)]
+    open: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplicationConfig {
+    // Who requests to peers are signed as, which has to be an admin on each
+    // of them. Without a key they're anonymous.
+    #[serde(default)]
+    identity: Option<String>,
+    #[serde(default)]
+    key: Option<String>,
+    // Where how far it's got with each peer is kept
+    cursors: PathBuf,
+    #[serde(default = "default_replication_secs")]
+    interval_secs: u64,
+    peers: Vec<PeerConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerConfig {
+    remote: String,
+    #[serde(default)]
+    direction: replication::Direction,
+}
+
+fn default_replication_secs() -> u64 {
+    60
+}
+
+fn default_metrics_refresh_secs() -> u64 {
+    60 * 60
+}
+
+fn default_idempotency_window_secs() -> u64 {
+    60 * 60 * 24
+}
+
+fn default_idempotency_max_keys() -> usize {
+    100_000
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "type")]
+enum AuthConfig {
+    // Every request is anonymous, meant for local development
+    #[default]
+    None,
+    // Requests can be signed by any of the keys, which are paths to
+    // public key pems by identity
+    Signed {
+        keys: HashMap<String, String>,
+        #[serde(default = "default_window_secs")]
+        window_secs: u64,
+    },
+}
+
+fn default_window_secs() -> u64 {
+    300
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type")]
 enum StorageConfig {
-    Local { directory: String },
+    Local {
+        directory: String,
+        // Creates the directory on startup if it's not there
+        #[serde(default)]
+        create: bool,
+        // Puts are refused once the disk has less free space than this
+        #[serde(default)]
+        min_free_bytes: u64,
+    },
+    // Blobs are appended into big pack files, for stores with lots of
+    // small ones. Nodes are kept as files next to the packs.
+    Pack {
+        directory: String,
+        #[serde(default)]
+        create: bool,
+        // A pack is closed off and the next one started past this size
+        #[serde(default = "default_max_pack_bytes")]
+        max_pack_bytes: u64,
+        // How often packs with deleted or rewritten blobs in them are
+        // compacted, starting on startup
+        #[serde(default = "default_compact_secs")]
+        compact_secs: u64,
+    },
+    // Blobs, nodes and the acl all in one sqlite file
+    Sqlite {
+        path: String,
+    },
+    // Everything in a bucket of an S3 compatible object store
+    S3(storage::S3Config),
+    // Nothing written to disk, everything's gone when the server stops
+    Memory {
+        #[serde(default)]
+        max_bytes: Option<u64>,
+    },
+    // Another storage, with the blobs read from it cached in memory and
+    // optionally on disk
+    Cached {
+        max_bytes: u64,
+        #[serde(default)]
+        disk: Option<DiskCacheConfig>,
+        storage: Box<StorageConfig>,
+    },
+    // Blobs, nodes and the acl copied into each of several storages
+    Mirror {
+        #[serde(default)]
+        policy: storage::WritePolicy,
+        // How often copies that went missing are filled back in, starting
+        // on startup
+        #[serde(default = "default_resync_secs")]
+        resync_secs: u64,
+        children: Vec<StorageConfig>,
+    },
+    // Blobs split into data and parity shards, one per child, with nodes
+    // and the acl copied into every child. Children past the data shards
+    // are parity, and that many can be lost.
+    Erasure {
+        data_shards: usize,
+        // How often missing and corrupt shards are rewritten, and nodes
+        // caught up, starting on startup
+        #[serde(default = "default_repair_secs")]
+        repair_secs: u64,
+        children: Vec<StorageConfig>,
+    },
+}
+
+fn default_resync_secs() -> u64 {
+    60 * 60
+}
+
+fn default_repair_secs() -> u64 {
+    24 * 60 * 60
+}
+
+#[derive(Debug, Deserialize)]
+struct DiskCacheConfig {
+    directory: PathBuf,
+    max_bytes: u64,
+}
+
+fn default_max_pack_bytes() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_compact_secs() -> u64 {
+    24 * 60 * 60
 }
 
 #[tokio::main]
 async fn main() {
+    // initialize tracing
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .json()
+        .init();
+
     let config = config();
-    let store = Arc::new(store(&config));
+    let mut stores = stores(&config.storage);
+    if config.open {
+        let acl = storage::Memory::new();
+        acl.put_acl(&open_acl()).unwrap();
+        stores.acl = Arc::new(acl);
+    } else if let Some(acl) = &config.acl {
+        stores.acl.put_acl(acl).unwrap();
+    }
+
+    let hasher = match &config.hash_algorithm {
+        Some(name) => {
+            hash::algorithm(name).unwrap_or_else(|| panic!("unknown hash algorithm {}", name))
+        }
+        None => hash::DEFAULT,
+    };
+
+    let changes = match &config.changes_path {
+        Some(path) => ChangeLog::open(Path::new(path))
+            .unwrap_or_else(|e| panic!("change log at {} isn't usable: {}", path, e)),
+        None => ChangeLog::memory(),
+    };
+    if changes.is_empty() {
+        changes
+            .seed(stores.blobs.as_ref(), stores.nodes.as_ref())
+            .unwrap_or_else(|e| panic!("error seeding the change log: {}", e));
+    }
+
+    let nodes = stores
+        .nodes
+        .list()
+        .unwrap_or_else(|e| panic!("error listing nodes: {}", e));
+    let blob_namespaces = BlobNamespaces::load(stores.nodes.as_ref(), &nodes)
+        .unwrap_or_else(|e| panic!("error loading blob namespaces: {}", e));
+    let snapshots = Snapshots::load(&nodes);
+
+    let acl = stores
+        .acl
+        .get_acl()
+        .unwrap_or_else(|e| panic!("error reading the acl: {}", e));
 
     let app_state = AppState {
         started: Instant::now(),
-        blob_store: store.clone(),
-        node_store: store.clone(),
+        blob_store: stores.blobs,
+        node_store: stores.nodes,
+        acl_store: stores.acl,
+        acl: Arc::new(RwLock::new(acl)),
+        metrics: Arc::new(Metrics::default()),
+        idempotency: Arc::new(IdempotencyKeys::new(
+            Duration::from_secs(config.idempotency_window_secs),
+            config.idempotency_max_keys,
+        )),
+        hasher,
+        changes: Arc::new(changes),
+        blob_namespaces: Arc::new(blob_namespaces),
+        snapshots: Arc::new(snapshots),
     };
 
-    let blob_routes = server::new_router();
+    app_state.metrics.set_nodes(nodes.len());
+    let refresh = Duration::from_secs(config.metrics_refresh_secs);
+    let refreshing = app_state.clone();
+    std::thread::spawn(move || loop {
+        refreshing.metrics.refresh(refreshing.blob_store.as_ref());
+        std::thread::sleep(refresh);
+    });
+
+    let expiring = app_state.idempotency.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(60));
+        expiring.expire();
+    });
+
+    if let Some(replication) = &config.replication {
+        let replicator = replicator(replication, app_state.clone().into());
+        tokio::spawn(replicator.run(Duration::from_secs(replication.interval_secs)));
+    }
+
+    let mut blob_routes = server::new_router();
+    if let Some(keyring) = keyring(&config.auth) {
+        blob_routes = blob_routes.layer(middleware::from_fn_with_state(
+            Arc::new(keyring),
+            auth::authenticate,
+        ));
+    }
     // Crazy into/from stuff going on here, but declaring the type so we know it's
     // still Router<AppState>
     let blob_router: Router<AppState> = blob_routes.with_state(app_state.clone().into());
 
     let router = Router::new()
         .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
         .merge(blob_router)
-        .with_state(app_state)
-        .layer(middleware::from_fn(log_request_response));
-
-    // initialize tracing
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::DEBUG)
-        .json()
-        .init();
+        .with_state(app_state.clone())
+        .layer(middleware::from_fn_with_state(
+            app_state,
+            log_request_response,
+        ));
 
     let formatted = format!("0.0.0.0:{}", config.port);
     println!("listening on: {}", formatted);
 
+    if let Some(tls_config) = &config.tls {
+        let acceptor =
+            tls::acceptor(tls_config).unwrap_or_else(|e| panic!("error loading tls config: {}", e));
+        let listener = tokio::net::TcpListener::bind(&formatted)
+            .await
+            .unwrap_or_else(|e| panic!("error binding {}: {}", formatted, e));
+        tls::serve(listener, acceptor, router)
+            .await
+            .unwrap_or_else(|e| panic!("error serving tls: {}", e));
+        return;
+    }
+
     axum::Server::bind(&formatted.parse().unwrap())
         .serve(router.into_make_service())
         .await
@@ -74,11 +348,25 @@ async fn main() {
 fn config() -> Config {
     // Load some env config
     let Ok(config_path) = std::env::var("CONFIG_PATH") else {
-        // Just return a default config
+        // Just return a default config, where anyone can do anything
         return Config {
             port: 4444,
-            storage: StorageConfig::Local { directory: String::from("./file_store") },
-        }
+            storage: StorageConfig::Local {
+                directory: String::from("./file_store"),
+                create: true,
+                min_free_bytes: 0,
+            },
+            acl: None,
+            auth: AuthConfig::None,
+            tls: None,
+            idempotency_window_secs: default_idempotency_window_secs(),
+            idempotency_max_keys: default_idempotency_max_keys(),
+            hash_algorithm: None,
+            changes_path: None,
+            replication: None,
+            metrics_refresh_secs: default_metrics_refresh_secs(),
+            open: true,
+        };
     };
 
     File::open(config_path)
@@ -87,10 +375,297 @@ fn config() -> Config {
         .unwrap()
 }
 
+// Sets up replication with every peer, signed in as the configured identity
+fn replicator(config: &ReplicationConfig, state: server::State) -> replication::Replicator {
+    let signing_key = match (&config.identity, &config.key) {
+        (Some(identity), Some(key)) => {
+            let pem = std::fs::read(key)
+                .unwrap_or_else(|e| panic!("error reading key at {}: {}", key, e));
+            Some(
+                auth::SigningKey::from_pem(identity, &pem)
+                    .unwrap_or_else(|e| panic!("key at {} isn't usable: {}", key, e)),
+            )
+        }
+        (None, None) => None,
+        _ => panic!("replication needs both an identity and a key, or neither"),
+    };
+
+    let mut replicator = replication::Replicator::new(state, Some(config.cursors.clone()))
+        .unwrap_or_else(|e| panic!("replication cursors aren't usable: {}", e));
+    for peer in &config.peers {
+        let mut client = Client::builder().remote(&peer.remote);
+        if let Some(key) = &signing_key {
+            client = client.signing_key(key.clone());
+        }
+        let client = client
+            .build()
+            .unwrap_or_else(|e| panic!("error making a client for {}: {}", peer.remote, e));
+        replicator = replicator.peer(client, peer.direction);
+    }
+
+    replicator
+}
+
+// Loads the public keys for signed requests, if the config asks for them
+fn keyring(config: &AuthConfig) -> Option<auth::Keyring> {
+    let AuthConfig::Signed { keys, window_secs } = config else {
+        return None;
+    };
+
+    let keys = keys
+        .iter()
+        .map(|(identity, path)| {
+            let pem = std::fs::read(path)
+                .unwrap_or_else(|e| panic!("error reading key {} for {}: {}", path, identity, e));
+            let key = PKey::public_key_from_pem(&pem)
+                .unwrap_or_else(|e| panic!("error reading key {} for {}: {}", path, identity, e));
+            (identity.clone(), key)
+        })
+        .collect();
+    Some(auth::Keyring::new(keys, Duration::from_secs(*window_secs)))
+}
+
+// An acl granting everyone access to every namespace, for local development
+fn open_acl() -> Acl {
+    let everyone = vec![String::from(WILDCARD)];
+    Acl {
+        admins: everyone.clone(),
+        namespaces: [(
+            String::from(WILDCARD),
+            NamespaceAcl {
+                read: everyone.clone(),
+                write: everyone,
+            },
+        )]
+        .into(),
+    }
+}
+
+// Where blobs, nodes and the acl are kept, which aren't always the same place
+struct Stores {
+    blobs: Arc<dyn Storage + Send + Sync>,
+    nodes: Arc<dyn NodeStore + Send + Sync>,
+    acl: Arc<dyn AclStore + Send + Sync>,
+}
+
 // Configures a new blob store from what the config says
-fn store(config: &Config) -> storage::Local {
-    match &config.storage {
-        StorageConfig::Local { directory } => storage::Local::new(directory.clone()),
+//
+// The store gets checked before it's handed back, so a bad directory stops the
+// server from starting instead of failing the first request.
+fn stores(config: &StorageConfig) -> Stores {
+    match config {
+        StorageConfig::Local {
+            directory,
+            create,
+            min_free_bytes,
+        } => {
+            let local = storage::Local::new(directory.clone()).with_min_free_bytes(*min_free_bytes);
+            if let Err(e) = local.validate(*create) {
+                panic!("storage at {} isn't usable: {}", directory, e);
+            }
+            let local = Arc::new(local);
+
+            // Stores from before sharding get moved over while serving
+            if local.needs_migration() {
+                let local = local.clone();
+                std::thread::spawn(move || match local.migrate() {
+                    Ok(migrated) => info!(
+                        blobs = migrated.blobs,
+                        nodes = migrated.nodes,
+                        "migrated store to the sharded layout"
+                    ),
+                    Err(e) => error!("error migrating store to the sharded layout: {}", e),
+                });
+            }
+
+            Stores {
+                blobs: local.clone(),
+                nodes: local.clone(),
+                acl: local,
+            }
+        }
+        StorageConfig::Pack {
+            directory,
+            create,
+            max_pack_bytes,
+            compact_secs,
+        } => {
+            let pack = storage::Pack::open(directory, *create)
+                .unwrap_or_else(|e| panic!("storage at {} isn't usable: {}", directory, e))
+                .with_max_pack_bytes(*max_pack_bytes);
+            let pack = Arc::new(pack);
+            // Nodes and the acl sit alongside the packs, checked the same as
+            // any local store
+            let local = storage::Local::new(directory.clone());
+            if let Err(e) = local.validate(*create) {
+                panic!("storage at {} isn't usable: {}", directory, e);
+            }
+            let local = Arc::new(local);
+
+            let compact = Duration::from_secs(*compact_secs);
+            let compacting = pack.clone();
+            std::thread::spawn(move || loop {
+                match compacting.compact() {
+                    Ok(compacted) => info!(
+                        packs = compacted.packs_removed,
+                        bytes = compacted.bytes_reclaimed,
+                        "compacted packs"
+                    ),
+                    Err(e) => error!("error compacting packs: {}", e),
+                }
+                std::thread::sleep(compact);
+            });
+
+            Stores {
+                blobs: pack,
+                nodes: local.clone(),
+                acl: local,
+            }
+        }
+        StorageConfig::Sqlite { path } => {
+            let sqlite = storage::Sqlite::open(Path::new(path))
+                .unwrap_or_else(|e| panic!("storage at {} isn't usable: {}", path, e));
+            let sqlite = Arc::new(sqlite);
+            Stores {
+                blobs: sqlite.clone(),
+                nodes: sqlite.clone(),
+                acl: sqlite,
+            }
+        }
+        StorageConfig::S3(s3_config) => {
+            let s3 = storage::S3::new(s3_config.clone())
+                .and_then(|s3| Storage::check(&s3).map(|_| s3))
+                .unwrap_or_else(|e| panic!("bucket {} isn't usable: {}", s3_config.bucket, e));
+            let s3 = Arc::new(s3);
+            Stores {
+                blobs: s3.clone(),
+                nodes: s3.clone(),
+                acl: s3,
+            }
+        }
+        StorageConfig::Memory { max_bytes } => {
+            let mut memory = storage::Memory::new();
+            if let Some(max_bytes) = max_bytes {
+                memory = memory.with_max_bytes(*max_bytes);
+            }
+            let memory = Arc::new(memory);
+            Stores {
+                blobs: memory.clone(),
+                nodes: memory.clone(),
+                acl: memory,
+            }
+        }
+        StorageConfig::Cached {
+            max_bytes,
+            disk,
+            storage,
+        } => {
+            let stores = stores(storage);
+            let mut cached = storage::Cached::new(stores.blobs, *max_bytes);
+            if let Some(disk) = disk {
+                cached = cached
+                    .with_disk(disk.directory.clone(), disk.max_bytes)
+                    .unwrap_or_else(|e| {
+                        panic!("cache at {} isn't usable: {}", disk.directory.display(), e)
+                    });
+            }
+            Stores {
+                blobs: Arc::new(cached),
+                ..stores
+            }
+        }
+        StorageConfig::Mirror {
+            policy,
+            resync_secs,
+            children,
+        } => {
+            let children: Vec<Stores> = children.iter().map(stores).collect();
+            if children.is_empty() {
+                panic!("a mirror needs at least one child");
+            }
+            let blobs = children.iter().map(|c| c.blobs.clone()).collect();
+            let mirror = Arc::new(storage::Mirror::new(blobs, *policy));
+            let nodes = Arc::new(mirrored_nodes(&children, policy.needed(children.len())));
+
+            let resync = Duration::from_secs(*resync_secs);
+            let resyncing = mirror.clone();
+            let resyncing_nodes = nodes.clone();
+            std::thread::spawn(move || loop {
+                match resyncing.resync() {
+                    Ok(resynced) => info!(
+                        copied = resynced.copied,
+                        failed = resynced.failed,
+                        "resynced mirror"
+                    ),
+                    Err(e) => error!("error resyncing mirror: {}", e),
+                }
+                resync_nodes(&resyncing_nodes);
+                std::thread::sleep(resync);
+            });
+
+            Stores {
+                blobs: mirror,
+                nodes: nodes.clone(),
+                acl: nodes,
+            }
+        }
+        StorageConfig::Erasure {
+            data_shards,
+            repair_secs,
+            children,
+        } => {
+            let children: Vec<Stores> = children.iter().map(stores).collect();
+            let blobs = children.iter().map(|c| c.blobs.clone()).collect();
+            let erasure = storage::Erasure::new(blobs, *data_shards)
+                .unwrap_or_else(|e| panic!("erasure coding isn't usable: {}", e));
+            let erasure = Arc::new(erasure);
+            // As many children can be lost as for the blobs
+            let nodes = Arc::new(mirrored_nodes(&children, *data_shards));
+
+            let repair = Duration::from_secs(*repair_secs);
+            let repairing = erasure.clone();
+            let resyncing_nodes = nodes.clone();
+            std::thread::spawn(move || loop {
+                match repairing.repair_all() {
+                    Ok(repaired) => info!(
+                        blobs = repaired.blobs,
+                        shards = repaired.shards,
+                        lost = repaired.lost,
+                        "repaired erasure coded shards"
+                    ),
+                    Err(e) => error!("error repairing erasure coded shards: {}", e),
+                }
+                resync_nodes(&resyncing_nodes);
+                std::thread::sleep(repair);
+            });
+
+            Stores {
+                blobs: erasure,
+                nodes: nodes.clone(),
+                acl: nodes,
+            }
+        }
+    }
+}
+
+// Nodes and the acl kept in every child, with writes having to reach
+// `needed` of them
+fn mirrored_nodes(children: &[Stores], needed: usize) -> storage::MirroredNodes {
+    storage::MirroredNodes::new(
+        children.iter().map(|c| c.nodes.clone()).collect(),
+        children.iter().map(|c| c.acl.clone()).collect(),
+        needed,
+    )
+}
+
+fn resync_nodes(nodes: &storage::MirroredNodes) {
+    match nodes.resync() {
+        Ok(resynced) => info!(
+            copied = resynced.copied,
+            failed = resynced.failed,
+            "resynced nodes"
+        ),
+        Err(e) => error!("error resyncing nodes: {}", e),
     }
 }
 
@@ -100,6 +675,14 @@ struct AppState {
     started: Instant,
     blob_store: Arc<dyn Storage + Send + Sync>,
     node_store: Arc<dyn NodeStore + Send + Sync>,
+    acl_store: Arc<dyn AclStore + Send + Sync>,
+    acl: Arc<RwLock<Acl>>,
+    metrics: Arc<Metrics>,
+    idempotency: Arc<IdempotencyKeys>,
+    hasher: &'static dyn Hasher,
+    changes: Arc<ChangeLog>,
+    blob_namespaces: Arc<BlobNamespaces>,
+    snapshots: Arc<Snapshots>,
 }
 
 // Splitting an AppState into something specific for the server implementations
@@ -112,6 +695,14 @@ impl Into<server::State> for AppState {
         server::State {
             blob_store: self.blob_store,
             node_store: self.node_store,
+            acl_store: self.acl_store,
+            acl: self.acl,
+            metrics: self.metrics,
+            idempotency: self.idempotency,
+            hasher: self.hasher,
+            changes: self.changes,
+            blob_namespaces: self.blob_namespaces,
+            snapshots: self.snapshots,
         }
     }
 }
@@ -127,14 +718,70 @@ async fn healthz(State(state): State<AppState>) -> impl IntoResponse {
     })
 }
 
+#[derive(Serialize)]
+struct ReadyzResponse {
+    ready: bool,
+    free_bytes: Option<u64>,
+    error: Option<String>,
+}
+
+// Reports whether the blob and node stores can be reached, unlike healthz
+// which only says the process is up
+async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let checked = state
+        .blob_store
+        .check()
+        .map_err(|e| e.to_string())
+        .and_then(|health| {
+            state.node_store.check().map_err(|e| e.to_string())?;
+            Ok(health)
+        });
+
+    match checked {
+        Ok(health) => (
+            StatusCode::OK,
+            Json(ReadyzResponse {
+                ready: true,
+                free_bytes: health.free_bytes,
+                error: None,
+            }),
+        ),
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ReadyzResponse {
+                ready: false,
+                free_bytes: None,
+                error: Some(e),
+            }),
+        ),
+    }
+}
+
+// Prometheus metrics in the text format
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    state.metrics.render()
+}
+
 async fn log_request_response<B>(
+    State(state): State<AppState>,
     req: Request<B>,
     next: Next<B>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
     info!(target: "request received", method = req.method().as_str(), path = req.uri().path());
+    // Labeled by the route pattern rather than the path, so every blob
+    // doesn't get its own series
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_else(|| String::from("unmatched"));
+    let start = Instant::now();
     let res = next.run(req).await;
 
     let resp_code = res.status().as_u16();
+    state
+        .metrics
+        .record_request(&route, resp_code, start.elapsed());
     // Has to be called different ways since you can't use `event!` without a constant value for level
     if !(200..=299).contains(&resp_code) {
         info!(code = resp_code, "response");
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use base64::{engine::general_purpose, Engine as _};
+use openssl::pkey::{PKey, Private, Public};
+use openssl::sign::{Signer, Verifier};
+use sha256::digest;
+use uuid::Uuid;
+
+use crate::blobserver::server::{MAX_BODY_BYTES, NAMESPACE_HEADER};
+use crate::error::{Error, Kind, WithKind};
+use crate::Identity;
+
+// Headers a signed request carries
+pub const IDENTITY_HEADER: &str = "x-anchorage-identity";
+pub const TIMESTAMP_HEADER: &str = "x-anchorage-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-anchorage-signature";
+pub const NONCE_HEADER: &str = "x-anchorage-nonce";
+
+/// Builds the string that gets signed for a request.
+///
+/// The body is hashed so the payload stays small no matter how big the
+/// upload is. The nonce keeps two identical requests in the same second,
+/// like a retry, from having identical signatures. The namespace is signed
+/// too, so it can't be swapped for another one.
+pub fn signing_payload(
+    method: &str,
+    path: &str,
+    namespace: &str,
+    body: &[u8],
+    timestamp: u64,
+    nonce: &str,
+) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        namespace,
+        digest(body),
+        timestamp,
+        nonce
+    )
+}
+
+/// Seconds since the epoch, which is what request timestamps are in.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// An identity's private key, used by clients to sign their requests.
+///
+/// Keys are ed25519, e.g. from `openssl genpkey -algorithm ed25519`.
+#[derive(Clone)]
+pub struct SigningKey {
+    identity: String,
+    key: PKey<Private>,
+}
+
+impl SigningKey {
+    pub fn new(identity: &str, key: PKey<Private>) -> Self {
+        Self {
+            identity: identity.to_owned(),
+            key,
+        }
+    }
+
+    pub fn from_pem(identity: &str, pem: &[u8]) -> Result<Self, Error> {
+        let key =
+            PKey::private_key_from_pem(pem).with_kind("error parsing key", Kind::BadRequest)?;
+        Ok(Self::new(identity, key))
+    }
+
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    /// Signs the request, returning the headers to send along with it.
+    pub fn sign(
+        &self,
+        method: &str,
+        path: &str,
+        namespace: &str,
+        body: &[u8],
+        timestamp: u64,
+    ) -> Result<Vec<(&'static str, String)>, Error> {
+        let nonce = Uuid::new_v4().to_string();
+        let payload = signing_payload(method, path, namespace, body, timestamp, &nonce);
+        let signature = Signer::new_without_digest(&self.key)
+            .and_then(|mut signer| signer.sign_oneshot_to_vec(payload.as_bytes()))
+            .with_kind("error signing request", Kind::Internal)?;
+
+        Ok(vec![
+            (IDENTITY_HEADER, self.identity.clone()),
+            (TIMESTAMP_HEADER, timestamp.to_string()),
+            (NONCE_HEADER, nonce),
+            (
+                SIGNATURE_HEADER,
+                general_purpose::STANDARD_NO_PAD.encode(signature),
+            ),
+        ])
+    }
+}
+
+/// Holds the public keys of every identity allowed to sign requests,
+/// and checks request signatures against them.
+pub struct Keyring {
+    keys: HashMap<String, PKey<Public>>,
+    // How far a request's timestamp can be from the server's clock
+    window: Duration,
+    // Nonces seen within the window, so the exact same request can't be
+    // replayed while its timestamp is still valid
+    seen: Mutex<HashMap<String, u64>>,
+}
+
+impl Keyring {
+    pub fn new(keys: HashMap<String, PKey<Public>>, window: Duration) -> Self {
+        Self {
+            keys,
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the signature headers of a request, returning who signed it.
+    ///
+    /// A request without any signature is anonymous, and it's up to the acl
+    /// to decide what anonymous requests can do.
+    pub fn verify(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+        now: u64,
+    ) -> Result<Identity, Error> {
+        let Some(identity) = header(headers, IDENTITY_HEADER)? else {
+            return Ok(Identity::default());
+        };
+        let timestamp: u64 = header(headers, TIMESTAMP_HEADER)?
+            .ok_or_else(|| unauthenticated("missing timestamp"))?
+            .parse()
+            .map_err(|_| unauthenticated("invalid timestamp"))?;
+        let signature = header(headers, SIGNATURE_HEADER)?
+            .ok_or_else(|| unauthenticated("missing signature"))?;
+        let nonce =
+            header(headers, NONCE_HEADER)?.ok_or_else(|| unauthenticated("missing nonce"))?;
+        let namespace = header(headers, NAMESPACE_HEADER)?.unwrap_or_default();
+
+        if now.abs_diff(timestamp) > self.window.as_secs() {
+            return Err(unauthenticated("timestamp outside of the allowed window"));
+        }
+
+        let key = self
+            .keys
+            .get(identity)
+            .ok_or_else(|| unauthenticated("unknown identity"))?;
+        let decoded = general_purpose::STANDARD_NO_PAD
+            .decode(signature)
+            .map_err(|_| unauthenticated("invalid signature encoding"))?;
+        let payload = signing_payload(method, path, namespace, body, timestamp, nonce);
+        let valid = Verifier::new_without_digest(key)
+            .and_then(|mut verifier| verifier.verify_oneshot(&decoded, payload.as_bytes()))
+            .unwrap_or(false);
+        if !valid {
+            return Err(unauthenticated("invalid signature"));
+        }
+
+        // Only a valid signature gets remembered, otherwise anyone could
+        // fill the map up
+        let mut seen = self.seen.lock().unwrap();
+        let window = self.window.as_secs();
+        seen.retain(|_, ts| now.abs_diff(*ts) <= window);
+        if seen
+            .insert(format!("{}:{}", identity, nonce), timestamp)
+            .is_some()
+        {
+            return Err(unauthenticated("request was replayed"));
+        }
+
+        Ok(Identity(Some(identity.to_owned())))
+    }
+}
+
+// Reads a header as a string, if it's there
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Result<Option<&'a str>, Error> {
+    headers
+        .get(name)
+        .map(|v| v.to_str().map_err(|_| unauthenticated("invalid header")))
+        .transpose()
+}
+
+fn unauthenticated(msg: &str) -> Error {
+    Error::from_msg(msg, Kind::Unauthenticated)
+}
+
+/// Middleware that verifies signed requests, leaving the signer's identity
+/// in the request extensions for handlers to pick up.
+pub async fn authenticate(
+    State(keyring): State<Arc<Keyring>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response, Error> {
+    // The body has to be read in full to check its hash, then put back.
+    // It's held to the same limit as the handlers hold it to, since this
+    // runs before them.
+    let (mut parts, body) = req.into_parts();
+    let bytes = hyper::body::to_bytes(http_body::Limited::new(body, MAX_BODY_BYTES))
+        .await
+        .map_err(|e| Error::from_msg(&format!("error reading body: {}", e), Kind::BadRequest))?;
+
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    let identity = keyring.verify(parts.method.as_str(), path, &parts.headers, &bytes, now())?;
+    // An unsigned request keeps whatever identity its connection had,
+    // like one from a client certificate
+    if identity.0.is_some() {
+        parts.extensions.insert(identity);
+    }
+
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (SigningKey, Keyring) {
+        let private = PKey::generate_ed25519().unwrap();
+        let public = PKey::public_key_from_raw_bytes(
+            &private.raw_public_key().unwrap(),
+            openssl::pkey::Id::ED25519,
+        )
+        .unwrap();
+
+        let keyring = Keyring::new(
+            [(String::from("james"), public)].into(),
+            Duration::from_secs(300),
+        );
+        (SigningKey::new("james", private), keyring)
+    }
+
+    fn signed_headers(key: &SigningKey, body: &[u8], timestamp: u64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(NAMESPACE_HEADER, "photos".parse().unwrap());
+        for (name, value) in key.sign("PUT", "/blob", "photos", body, timestamp).unwrap() {
+            headers.insert(name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn verifies_signed_request() {
+        let (key, keyring) = setup();
+        let headers = signed_headers(&key, b"hello", 1000);
+
+        let identity = keyring
+            .verify("PUT", "/blob", &headers, b"hello", 1010)
+            .unwrap();
+        assert_eq!(identity, Identity(Some(String::from("james"))));
+    }
+
+    #[test]
+    fn unsigned_request_is_anonymous() {
+        let (_, keyring) = setup();
+
+        let identity = keyring
+            .verify("PUT", "/blob", &HeaderMap::new(), b"hello", 1000)
+            .unwrap();
+        assert_eq!(identity, Identity::default());
+    }
+
+    #[test]
+    fn rejects_tampering_and_replays() {
+        let (key, keyring) = setup();
+        let headers = signed_headers(&key, b"hello", 1000);
+
+        // A different body or path than what was signed
+        assert!(keyring
+            .verify("PUT", "/blob", &headers, b"goodbye", 1000)
+            .is_err());
+        assert!(keyring
+            .verify("GET", "/blob", &headers, b"hello", 1000)
+            .is_err());
+        let mut moved = headers.clone();
+        moved.insert(NAMESPACE_HEADER, "documents".parse().unwrap());
+        assert!(keyring
+            .verify("PUT", "/blob", &moved, b"hello", 1000)
+            .is_err());
+
+        // Too far from the server's clock
+        assert!(keyring
+            .verify("PUT", "/blob", &headers, b"hello", 2000)
+            .is_err());
+
+        // The same request twice
+        keyring
+            .verify("PUT", "/blob", &headers, b"hello", 1000)
+            .unwrap();
+        assert!(keyring
+            .verify("PUT", "/blob", &headers, b"hello", 1000)
+            .is_err());
+
+        // But two identical requests signed separately are both fine
+        let again = signed_headers(&key, b"hello", 1000);
+        keyring
+            .verify("PUT", "/blob", &again, b"hello", 1000)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn limits_body_before_reading_it() {
+        let (_, keyring) = setup();
+        let router = axum::Router::new()
+            .route("/", axum::routing::put(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(
+                Arc::new(keyring),
+                authenticate,
+            ));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.into_make_service()),
+        );
+
+        let client = reqwest::Client::new();
+        let resp = client.put(&remote).body("small").send().await.unwrap();
+        assert!(resp.status().is_success());
+        let resp = client
+            .put(&remote)
+            .body(vec![0; MAX_BODY_BYTES + 1])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), hyper::StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::error::{Error, Kind, WithKind};
+use crate::{BlobRef, NodeStore, Storage};
+
+/// Something that changed on a server, for peers following it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Change {
+    Blob {
+        id: BlobRef,
+    },
+    // A node was made or got a new revision, at the time that revision
+    // says it was. Changes from before that was kept have 0.
+    Node {
+        id: String,
+        revision: u64,
+        #[serde(default)]
+        updated: u64,
+    },
+    NodeDeleted {
+        id: String,
+    },
+}
+
+/// A change and where it falls in the log, counting up from 1.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+/// A page of changes from the log.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Changes {
+    pub changes: Vec<Entry>,
+    // Where to ask from for the next page
+    pub cursor: String,
+    // If there's more past this page already
+    pub more: bool,
+}
+
+// The first line of a log's file, naming it
+#[derive(Serialize, Deserialize)]
+struct Header {
+    log: String,
+}
+
+// Every this many entries in a log's file, where the entry starts is kept
+// in memory, so a page can be read without going through the whole file
+const INDEX_EVERY: usize = 1024;
+// Logs smaller than this aren't worth compacting
+const MIN_COMPACT: usize = 4096;
+
+/// Everything that's changed on a server, in the order it happened.
+///
+/// Each log gets its own id, which goes into the cursors it hands out. A
+/// cursor from some other log, like one that got lost and started over,
+/// starts from the beginning of this one instead of skipping ahead.
+///
+/// Once the log has doubled in size, changes that a later one for the same
+/// node makes redundant are dropped, so it grows with how much there is
+/// rather than with how often it changes. Anyone following it still ends
+/// up with the same.
+pub struct ChangeLog {
+    id: String,
+    inner: RwLock<Inner>,
+}
+
+struct Inner {
+    // What the next change will be numbered
+    next_seq: u64,
+    len: usize,
+    // How many entries there were after the last compaction
+    compacted_len: usize,
+    kept: Kept,
+}
+
+enum Kept {
+    Memory(Vec<Entry>),
+    // Only every INDEX_EVERY-th entry's seq and offset is held, the rest
+    // are read from the file
+    File {
+        path: PathBuf,
+        file: File,
+        end: u64,
+        index: Vec<(u64, u64)>,
+    },
+}
+
+impl ChangeLog {
+    /// A log that's gone once the server stops.
+    pub fn memory() -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            inner: RwLock::new(Inner {
+                next_seq: 1,
+                len: 0,
+                compacted_len: 0,
+                kept: Kept::Memory(vec![]),
+            }),
+        }
+    }
+
+    /// Opens the log kept in a file, starting a new one if it's not there.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_kind("error opening change log", Kind::Internal)?;
+        let mut reader =
+            BufReader::new(File::open(path).with_kind("error opening change log", Kind::Internal)?);
+
+        let (id, mut end) = match read_line(&mut reader)? {
+            Some(line) => {
+                let header: Header = serde_json::from_slice(&line)
+                    .with_kind("error decoding change log", Kind::Internal)?;
+                (header.log, line.len() as u64)
+            }
+            None => {
+                // Anything there is half a header that never got written
+                file.set_len(0)
+                    .with_kind("error truncating change log", Kind::Internal)?;
+                let header = Header {
+                    log: Uuid::new_v4().to_string(),
+                };
+                let written = write_line(&mut file, &header)?;
+                reader
+                    .seek(SeekFrom::Start(written))
+                    .with_kind("error reading change log", Kind::Internal)?;
+                (header.log, written)
+            }
+        };
+
+        let (mut len, mut next_seq, mut index) = (0usize, 1, vec![]);
+        while let Some(line) = read_line(&mut reader)? {
+            let entry: Entry = serde_json::from_slice(&line)
+                .with_kind("error decoding change log", Kind::Internal)?;
+            if len.is_multiple_of(INDEX_EVERY) {
+                index.push((entry.seq, end));
+            }
+            len += 1;
+            next_seq = entry.seq + 1;
+            end += line.len() as u64;
+        }
+        // Anything after the last newline is a write that never finished
+        file.set_len(end)
+            .with_kind("error truncating change log", Kind::Internal)?;
+
+        Ok(Self {
+            id,
+            inner: RwLock::new(Inner {
+                next_seq,
+                len,
+                compacted_len: len,
+                kept: Kept::File {
+                    path: path.to_owned(),
+                    file,
+                    end,
+                    index,
+                },
+            }),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.read().unwrap().len == 0
+    }
+
+    /// Adds a change to the end of the log.
+    pub fn record(&self, change: Change) -> Result<(), Error> {
+        let mut inner = self.inner.write().unwrap();
+        let entry = Entry {
+            seq: inner.next_seq,
+            change,
+        };
+        let len = inner.len;
+        match &mut inner.kept {
+            Kept::Memory(entries) => entries.push(entry),
+            Kept::File {
+                file, end, index, ..
+            } => {
+                if len.is_multiple_of(INDEX_EVERY) {
+                    index.push((entry.seq, *end));
+                }
+                *end += write_line(file, &entry)?;
+            }
+        }
+        inner.next_seq += 1;
+        inner.len += 1;
+
+        // The change is already in, so failing to compact only costs space
+        if inner.len >= MIN_COMPACT.max(inner.compacted_len * 2) {
+            if let Err(e) = inner.compact(&self.id) {
+                tracing::warn!("error compacting change log: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records everything already in the stores, blobs before the nodes
+    /// that point at them, for a log that's only just been started.
+    pub fn seed(&self, blobs: &dyn Storage, nodes: &dyn NodeStore) -> Result<(), Error> {
+        let ids = blobs
+            .list_blobs()
+            .map_err(|e| Error::from_err("error listing blobs", e, Kind::Internal))?;
+        for id in ids {
+            self.record(Change::Blob { id })?;
+        }
+        for node in nodes.list()? {
+            self.record(Change::Node {
+                id: node.id,
+                revision: node.revision,
+                updated: node.updated,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Up to limit changes after the cursor, or from the start without one.
+    pub fn since(&self, cursor: Option<&str>, limit: usize) -> Result<Changes, Error> {
+        let inner = self.inner.read().unwrap();
+        let after = cursor
+            .and_then(|c| c.split_once(':'))
+            .filter(|(log, _)| *log == self.id)
+            .and_then(|(_, seq)| seq.parse::<u64>().ok())
+            .unwrap_or(0)
+            .min(inner.next_seq - 1);
+
+        // One more than asked for, to tell if there's more
+        let mut changes = match &inner.kept {
+            Kept::Memory(entries) => {
+                let start = entries.partition_point(|e| e.seq <= after);
+                entries[start..].iter().take(limit + 1).cloned().collect()
+            }
+            Kept::File { path, index, .. } => {
+                let from = match index.partition_point(|(seq, _)| *seq <= after) {
+                    0 => index.first(),
+                    i => index.get(i - 1),
+                };
+                match from {
+                    Some((_, offset)) => read_entries(path, *offset, after, limit + 1)?,
+                    None => vec![],
+                }
+            }
+        };
+        let more = changes.len() > limit;
+        changes.truncate(limit);
+
+        Ok(Changes {
+            cursor: format!("{}:{}", self.id, changes.last().map_or(after, |e| e.seq)),
+            changes,
+            more,
+        })
+    }
+}
+
+impl Inner {
+    // Drops every change that a later one for the same thing makes
+    // redundant, keeping the seqs of the rest
+    fn compact(&mut self, log: &str) -> Result<(), Error> {
+        match &mut self.kept {
+            Kept::Memory(entries) => {
+                let latest: HashMap<_, _> =
+                    entries.iter().map(|e| (key(&e.change), e.seq)).collect();
+                entries.retain(|e| latest.get(&key(&e.change)) == Some(&e.seq));
+                self.len = entries.len();
+            }
+            Kept::File {
+                path,
+                file,
+                end,
+                index,
+            } => {
+                let mut latest = HashMap::new();
+                for entry in EntryReader::open(path, 0)? {
+                    let entry = entry?;
+                    latest.insert(key(&entry.change), entry.seq);
+                }
+
+                let tmp = path.with_extension("tmp");
+                let mut compacted = BufWriter::new(
+                    File::create(&tmp).with_kind("error compacting change log", Kind::Internal)?,
+                );
+                let mut written = write_line(
+                    &mut compacted,
+                    &Header {
+                        log: log.to_owned(),
+                    },
+                )?;
+                let (mut len, mut kept_index) = (0usize, vec![]);
+                for entry in EntryReader::open(path, 0)? {
+                    let entry = entry?;
+                    if latest.get(&key(&entry.change)) != Some(&entry.seq) {
+                        continue;
+                    }
+                    if len.is_multiple_of(INDEX_EVERY) {
+                        kept_index.push((entry.seq, written));
+                    }
+                    len += 1;
+                    written += write_line(&mut compacted, &entry)?;
+                }
+                compacted
+                    .into_inner()
+                    .map_err(|e| e.into_error())
+                    .and_then(|f| f.sync_all())
+                    .and_then(|_| std::fs::rename(&tmp, &*path))
+                    .with_kind("error compacting change log", Kind::Internal)?;
+
+                *file = OpenOptions::new()
+                    .append(true)
+                    .open(&*path)
+                    .with_kind("error opening change log", Kind::Internal)?;
+                *end = written;
+                *index = kept_index;
+                self.len = len;
+            }
+        }
+        self.compacted_len = self.len;
+
+        Ok(())
+    }
+}
+
+// What a change is about, which later changes to the same thing replace
+fn key(change: &Change) -> String {
+    match change {
+        Change::Blob { id } => format!("blob:{}", id),
+        Change::Node { id, .. } | Change::NodeDeleted { id } => format!("node:{}", id),
+    }
+}
+
+// Reads the entries of a log's file from an offset, one at a time
+struct EntryReader {
+    reader: BufReader<File>,
+}
+
+impl EntryReader {
+    // The offset has to be the start of an entry, or 0 for the header
+    fn open(path: &Path, offset: u64) -> Result<Self, Error> {
+        let mut reader =
+            BufReader::new(File::open(path).with_kind("error opening change log", Kind::Internal)?);
+        reader
+            .seek(SeekFrom::Start(offset))
+            .with_kind("error reading change log", Kind::Internal)?;
+        if offset == 0 {
+            read_line(&mut reader)?;
+        }
+
+        Ok(Self { reader })
+    }
+}
+
+impl Iterator for EntryReader {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match read_line(&mut self.reader) {
+            Ok(Some(line)) => Some(
+                serde_json::from_slice(&line)
+                    .with_kind("error decoding change log", Kind::Internal),
+            ),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+fn read_entries(path: &Path, offset: u64, after: u64, limit: usize) -> Result<Vec<Entry>, Error> {
+    let mut entries = vec![];
+    for entry in EntryReader::open(path, offset)? {
+        let entry = entry?;
+        if entry.seq <= after {
+            continue;
+        }
+        entries.push(entry);
+        if entries.len() == limit {
+            break;
+        }
+    }
+
+    Ok(entries)
+}
+
+// The next whole line, newline and all, or None at the end. Half a line
+// at the end is treated as not there.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>, Error> {
+    let mut line = vec![];
+    reader
+        .read_until(b'\n', &mut line)
+        .with_kind("error reading change log", Kind::Internal)?;
+    match line.last() {
+        Some(b'\n') => Ok(Some(line)),
+        _ => Ok(None),
+    }
+}
+
+// Returns how long the line was
+fn write_line<T: Serialize>(file: &mut impl Write, value: &T) -> Result<u64, Error> {
+    let mut line = serde_json::to_vec(value).with_kind("error encoding change", Kind::Internal)?;
+    line.push(b'\n');
+    file.write_all(&line)
+        .with_kind("error writing change log", Kind::Internal)?;
+    Ok(line.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_through_changes_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("changes");
+
+        let log = ChangeLog::open(&path).unwrap();
+        for i in 0..3 {
+            log.record(Change::Node {
+                id: String::from("abc"),
+                revision: i + 1,
+                updated: 0,
+            })
+            .unwrap();
+        }
+        let first = log.since(None, 2).unwrap();
+        assert_eq!(first.changes.len(), 2);
+        assert!(first.more);
+
+        // Half a line from a crash gets dropped
+        drop(log);
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"{\"seq\":4,")
+            .unwrap();
+        let log = ChangeLog::open(&path).unwrap();
+        log.record(Change::NodeDeleted {
+            id: String::from("abc"),
+        })
+        .unwrap();
+
+        let rest = log.since(Some(&first.cursor), 10).unwrap();
+        let seqs: Vec<_> = rest.changes.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![3, 4]);
+        assert!(!rest.more);
+
+        // A cursor from another log starts over
+        let other = ChangeLog::memory().since(None, 10).unwrap().cursor;
+        assert_eq!(log.since(Some(&other), 10).unwrap().changes.len(), 4);
+    }
+
+    fn node(id: &str, revision: u64) -> Change {
+        Change::Node {
+            id: id.to_owned(),
+            revision,
+            updated: 0,
+        }
+    }
+
+    // Every seq after the cursor, a page at a time
+    fn seqs_since(log: &ChangeLog, cursor: Option<String>) -> Vec<u64> {
+        let mut seqs = vec![];
+        let mut cursor = cursor;
+        loop {
+            let page = log.since(cursor.as_deref(), 100).unwrap();
+            seqs.extend(page.changes.iter().map(|e| e.seq));
+            cursor = Some(page.cursor);
+            if !page.more {
+                return seqs;
+            }
+        }
+    }
+
+    #[test]
+    fn compacts_redundant_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("changes");
+
+        for log in [ChangeLog::memory(), ChangeLog::open(&path).unwrap()] {
+            log.record(Change::Blob {
+                id: BlobRef::of(b"hello"),
+            })
+            .unwrap();
+            for i in 0..INDEX_EVERY as u64 * 2 {
+                log.record(node(&format!("node-{}", i), 1)).unwrap();
+            }
+            let cursor = log.since(None, 10).unwrap().cursor;
+            // Just enough to fill the log up to where it's compacted
+            for revision in 1..(MIN_COMPACT - INDEX_EVERY * 2) as u64 {
+                log.record(node("abc", revision)).unwrap();
+            }
+
+            // All that's left of abc is its latest change, and the rest
+            // keep their place
+            let last = MIN_COMPACT as u64;
+            let seqs = seqs_since(&log, None);
+            assert_eq!(seqs.len(), INDEX_EVERY * 2 + 2);
+            assert_eq!(&seqs[..3], &[1, 2, 3]);
+            assert_eq!(seqs.last(), Some(&last));
+            let rest = seqs_since(&log, Some(cursor));
+            assert_eq!(rest.len(), INDEX_EVERY * 2 + 2 - 10);
+            assert_eq!(rest.last(), Some(&last));
+        }
+
+        // It's read back from where it was compacted to
+        let log = ChangeLog::open(&path).unwrap();
+        log.record(Change::NodeDeleted {
+            id: String::from("abc"),
+        })
+        .unwrap();
+        let seqs = seqs_since(&log, None);
+        assert_eq!(seqs.len(), INDEX_EVERY * 2 + 3);
+        assert_eq!(seqs.last(), Some(&(MIN_COMPACT as u64 + 1)));
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(lines, INDEX_EVERY * 2 + 4);
+    }
+}
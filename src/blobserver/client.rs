@@ -1,27 +1,184 @@
 use base64::{engine::general_purpose, Engine as _};
-use serde::de::DeserializeOwned;
+use reqwest::Method;
+use serde::{de::DeserializeOwned, Serialize};
 use std::result::Result;
+use std::time::Duration;
+use uuid::Uuid;
 
+use crate::blobserver::auth::{self, SigningKey};
+use crate::blobserver::changes::Changes;
+use crate::blobserver::idempotency::IDEMPOTENCY_KEY_HEADER;
 use crate::blobserver::server;
-use crate::error::Error;
-use crate::Node;
+use crate::error::{Error, Kind, WithKind};
+use crate::{BlobRef, Node, DEFAULT_NAMESPACE};
 
-use super::server::CreateNodeRequest;
+use super::server::{CreateNodeRequest, NAMESPACE_HEADER};
 
 pub struct Client {
     remote: String,
+    namespace: String,
+    // Requests are anonymous without a key
+    signing_key: Option<SigningKey>,
+    retry: RetryPolicy,
     client: reqwest::Client,
 }
 
 impl Default for Client {
+    fn default() -> Self {
+        // Nothing in the default builder can fail
+        Client::builder().build().unwrap()
+    }
+}
+
+/// Certificates for talking to a server over TLS.
+#[derive(Clone, Default)]
+pub struct ClientTls {
+    // Extra CAs to trust, e.g. for a self-signed home server
+    pub ca_bundle: Option<Vec<u8>>,
+    // A certificate and pkcs8 key pem to authenticate with
+    pub cert: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// How idempotent calls get retried when the server can't be reached or
+/// has an internal error.
+///
+/// The wait doubles after every attempt, up to the max.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Never retries anything.
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    // How long to wait before the retry after the given attempt, counting from 0
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
+/// Configures a Client. Anything left unset falls back to talking to a
+/// local server anonymously.
+pub struct ClientBuilder {
+    remote: String,
+    namespace: String,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    retry: RetryPolicy,
+    user_agent: String,
+    signing_key: Option<SigningKey>,
+    tls: ClientTls,
+}
+
+impl Default for ClientBuilder {
     fn default() -> Self {
         Self {
             remote: String::from("http://localhost:4444"),
-            client: reqwest::Client::new(),
+            namespace: String::from(DEFAULT_NAMESPACE),
+            timeout: None,
+            connect_timeout: None,
+            retry: RetryPolicy::default(),
+            user_agent: format!("anchorage/{}", env!("CARGO_PKG_VERSION")),
+            signing_key: None,
+            tls: ClientTls::default(),
         }
     }
 }
 
+impl ClientBuilder {
+    /// The server to talk to, e.g. `https://anchorage.home:4444`.
+    pub fn remote(mut self, remote: &str) -> Self {
+        self.remote = remote.trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// The namespace that blob requests act in.
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.namespace = namespace.to_owned();
+        self
+    }
+
+    /// How long a whole request can take.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// How long connecting to the server can take.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.to_owned();
+        self
+    }
+
+    /// The key every request gets signed with.
+    pub fn signing_key(mut self, key: SigningKey) -> Self {
+        self.signing_key = Some(key);
+        self
+    }
+
+    /// The certificates used when the remote is https.
+    pub fn tls(mut self, tls: ClientTls) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn build(self) -> Result<Client, Error> {
+        let mut builder = reqwest::Client::builder().user_agent(&self.user_agent);
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(bundle) = &self.tls.ca_bundle {
+            for cert in reqwest::Certificate::from_pem_bundle(bundle)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if let Some((cert, key)) = &self.tls.cert {
+            builder = builder.identity(reqwest::Identity::from_pkcs8_pem(cert, key)?);
+        }
+
+        Ok(Client {
+            remote: self.remote,
+            namespace: self.namespace,
+            signing_key: self.signing_key,
+            retry: self.retry,
+            client: builder.build()?,
+        })
+    }
+}
+
 /// Handles the response from the server, switching between
 /// the given struct to decode to vs the error struct when
 /// a non-200 code is received.
@@ -31,37 +188,369 @@ async fn handle_resp<T: DeserializeOwned>(resp: reqwest::Response) -> Result<T,
     // Non-200 should unmarshal to an error
     let status = resp.status();
     if !status.is_success() {
-        return Err(resp.json().await?);
+        // Errors from outside the handlers, like the body limit, aren't json
+        let body = resp.bytes().await?;
+        return Err(serde_json::from_slice(&body).unwrap_or_else(|_| {
+            let msg = format!("{}: {}", status, String::from_utf8_lossy(&body));
+            Error::from_msg(&msg, Kind::from(status))
+        }));
     }
 
     Ok(resp.json().await?)
 }
 
 impl Client {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    /// The server requests go to.
+    pub fn remote(&self) -> &str {
+        &self.remote
+    }
+
+    /// The namespace blob requests act in.
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Sends a request to the server, signing it if there's a key,
+    /// and decodes the response.
+    ///
+    /// Only idempotent requests should set `retry`, since a request that
+    /// timed out may have still gone through.
+    pub(crate) async fn send<T: DeserializeOwned, B: Serialize>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&B>,
+        retry: bool,
+    ) -> Result<T, Error> {
+        self.send_with_headers(method, path, body, retry, &[]).await
+    }
+
+    // Like send, with extra headers that go out on every attempt
+    async fn send_with_headers<T: DeserializeOwned, B: Serialize>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&B>,
+        retry: bool,
+        headers: &[(&str, String)],
+    ) -> Result<T, Error> {
+        let body = match body {
+            Some(b) => serde_json::to_vec(b).with_kind("error encoding body", Kind::Internal)?,
+            None => vec![],
+        };
+
+        let mut attempt = 0;
+        loop {
+            let signature = self.sign(&method, path, &body)?;
+            let resp = self
+                .send_once(&method, path, &body, headers, &signature)
+                .await;
+
+            // Only the server being unreachable or broken is worth trying again
+            let retryable = match &resp {
+                Ok(r) => r.status().is_server_error(),
+                Err(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+            };
+            if retry && retryable && attempt < self.retry.max_retries {
+                tokio::time::sleep(self.retry.backoff(attempt)).await;
+                attempt += 1;
+                continue;
+            }
+
+            return handle_resp(resp?).await;
+        }
+    }
+
+    // The signature headers for a request, none without a key. Each attempt
+    // is signed fresh so retries aren't mistaken for replays.
+    fn sign(
+        &self,
+        method: &Method,
+        path: &str,
+        body: &[u8],
+    ) -> Result<Vec<(&'static str, String)>, Error> {
+        match &self.signing_key {
+            Some(key) => key.sign(method.as_str(), path, &self.namespace, body, auth::now()),
+            None => Ok(vec![]),
+        }
+    }
+
+    // A single attempt at a request
+    async fn send_once(
+        &self,
+        method: &Method,
+        path: &str,
+        body: &[u8],
+        headers: &[(&str, String)],
+        signature: &[(&str, String)],
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let mut req = self
+            .client
+            .request(method.clone(), format!("{}{}", self.remote, path))
+            .header(NAMESPACE_HEADER, &self.namespace)
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        for (name, value) in headers.iter().chain(signature) {
+            req = req.header(*name, value);
+        }
+
+        req.body(body.to_vec()).send().await
+    }
+
     /// Calls to the server to create a new blob.
     ///
     /// The request is encoded base64 for safe transfer.
     /// If the blob already exists, this is an idempotent response:
-    /// the same struct will come back with the same ID.
+    /// the same struct will come back with the same ID, so it's retried.
     pub async fn put_blob(&self, data: &[u8]) -> Result<server::CreateBlobResponse, Error> {
         let body = server::CreateBlobRequest {
             data: general_purpose::STANDARD_NO_PAD.encode(data),
+            id: None,
         };
-        let path = format!("{}/blob", self.remote);
-        handle_resp(self.client.put(path).json(&body).send().await?).await
+        self.send(Method::PUT, "/blob", Some(&body), true).await
+    }
+
+    /// Calls to the server to create a blob under the id the client hashed
+    /// it to, which the server checks.
+    pub async fn put_blob_as(
+        &self,
+        id: &BlobRef,
+        data: &[u8],
+    ) -> Result<server::CreateBlobResponse, Error> {
+        let body = server::CreateBlobRequest {
+            data: general_purpose::STANDARD_NO_PAD.encode(data),
+            id: Some(id.clone()),
+        };
+        self.send(Method::PUT, "/blob", Some(&body), true).await
+    }
+
+    /// Calls the server for what it expects of clients, like which hash
+    /// algorithm to use.
+    pub async fn server_info(&self) -> Result<server::ServerInfo, Error> {
+        self.send(Method::GET, "/info", None::<&()>, true).await
+    }
+
+    /// Asks the server if it has a blob, without fetching it.
+    pub async fn has_blob(&self, hash: &BlobRef) -> Result<bool, Error> {
+        let path = format!("/blob/{}", hash);
+        let signature = self.sign(&Method::HEAD, &path, &[])?;
+        let resp = self
+            .send_once(&Method::HEAD, &path, &[], &[], &signature)
+            .await?;
+        match resp.status() {
+            status if status.is_success() => Ok(true),
+            reqwest::StatusCode::NOT_FOUND => Ok(false),
+            status => Err(Error::from_msg(
+                &format!("error checking for blob: {}", status),
+                Kind::from(status),
+            )),
+        }
     }
 
     /// Calls the server to retrieve a blob.
     ///
     /// If it's not found, expect a 404 status error.
-    pub async fn get_blob(&self, hash: &str) -> Result<server::BlobResponse, Error> {
-        let path = format!("{}/blob/{}", self.remote, hash);
-        handle_resp(self.client.get(path).send().await?).await
+    pub async fn get_blob(&self, hash: &BlobRef) -> Result<server::BlobResponse, Error> {
+        let path = format!("/blob/{}", hash);
+        self.send(Method::GET, &path, None::<&()>, true).await
+    }
+
+    /// Calls the server to retrieve a node.
+    pub async fn get_node(&self, id: &str) -> Result<Node, Error> {
+        let path = format!("/node/{}", id);
+        self.send(Method::GET, &path, None::<&()>, true).await
+    }
+
+    /// Calls the server for a node as it was at the given time, in seconds
+    /// since the epoch.
+    pub async fn get_node_at(&self, id: &str, at: u64) -> Result<Node, Error> {
+        let path = format!("/node/{}?at={}", id, at);
+        self.send(Method::GET, &path, None::<&()>, true).await
+    }
+
+    /// Calls the server for every revision of a node, oldest first.
+    pub async fn node_revisions(&self, id: &str) -> Result<Vec<Node>, Error> {
+        let path = format!("/node/{}/revisions", id);
+        self.send(Method::GET, &path, None::<&()>, true).await
+    }
+
+    /// Calls the server to change a node, returning its new revision.
+    ///
+    /// If the node has changed since the revision in the request, it's a
+    /// conflict and the change should be made again against the latest.
+    pub async fn update_node(
+        &self,
+        id: &str,
+        update: server::UpdateNodeRequest,
+    ) -> Result<Node, Error> {
+        let path = format!("/node/{}", id);
+        self.send(Method::PUT, &path, Some(&update), false).await
+    }
+
+    /// Calls the server to delete a node, returning what was deleted.
+    ///
+    /// A retry after the delete went through would be not found, so it isn't retried.
+    pub async fn delete_node(&self, id: &str) -> Result<Node, Error> {
+        let path = format!("/node/{}", id);
+        self.send(Method::DELETE, &path, None::<&()>, false).await
+    }
+
+    /// Lists the snapshots in the client's namespace, oldest first.
+    pub async fn list_snapshots(&self) -> Result<Vec<Node>, Error> {
+        self.send(Method::GET, "/snapshots", None::<&()>, true)
+            .await
+    }
+
+    /// Calls the server for up to limit changes after the cursor, or from
+    /// the start without one. Only admins can follow changes.
+    pub async fn changes(&self, since: Option<&str>, limit: usize) -> Result<Changes, Error> {
+        // Encoded here rather than by reqwest, since the path is signed as sent
+        let query = {
+            let mut query = url::form_urlencoded::Serializer::new(String::new());
+            query.append_pair("limit", &limit.to_string());
+            if let Some(since) = since {
+                query.append_pair("since", since);
+            }
+            query.finish()
+        };
+        let path = format!("/changes?{}", query);
+        self.send(Method::GET, &path, None::<&()>, true).await
+    }
+
+    /// Hands the server a node's revisions from elsewhere, keeping its id,
+    /// and returns its latest. Only admins can.
+    pub async fn replicate_node(&self, id: &str, revisions: &[Node]) -> Result<Node, Error> {
+        let path = format!("/node/{}/revisions", id);
+        self.send(Method::PUT, &path, Some(&revisions), true).await
     }
 
     /// Calls the server to create a node.
+    ///
+    /// Every attempt carries the same idempotency key, so retries get back
+    /// the node from an attempt that went through instead of making another.
     pub async fn create_node(&self, node: CreateNodeRequest) -> Result<Node, Error> {
-        let path = format!("{}/node", self.remote);
-        handle_resp(self.client.post(path).json(&node).send().await?).await
+        let key = Uuid::new_v4().to_string();
+        self.send_with_headers(
+            Method::POST,
+            "/node",
+            Some(&node),
+            true,
+            &[(IDEMPOTENCY_KEY_HEADER, key)],
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use axum::{
+        extract::Query,
+        http::StatusCode,
+        routing::{get, put},
+        Json, Router,
+    };
+
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(800));
+        assert_eq!(policy.backoff(4), Duration::from_secs(1));
+        assert_eq!(policy.backoff(40), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn encodes_the_changes_cursor() {
+        // Hands back whatever cursor it was asked from
+        let router = Router::new().route(
+            "/changes",
+            get(|Query(query): Query<HashMap<String, String>>| async move {
+                Json(Changes {
+                    changes: vec![],
+                    cursor: query["since"].clone(),
+                    more: query["limit"] != "5",
+                })
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.into_make_service()),
+        );
+
+        let client = Client::builder()
+            .remote(&remote)
+            .retry(RetryPolicy::none())
+            .build()
+            .unwrap();
+        let cursor = "a+b&limit=1 c#d";
+        let changes = client.changes(Some(cursor), 5).await.unwrap();
+        assert_eq!(changes.cursor, cursor);
+        assert!(!changes.more);
+    }
+
+    #[tokio::test]
+    async fn retries_blob_puts() {
+        // Fails the first two attempts
+        let calls = Arc::new(AtomicU32::new(0));
+        let counter = calls.clone();
+        let router = Router::new().route(
+            "/blob",
+            put(move || async move {
+                if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                    let err = Error::from_msg("try again", Kind::Internal);
+                    return Err((StatusCode::SERVICE_UNAVAILABLE, Json(err)));
+                }
+                Ok(Json(server::CreateBlobResponse {
+                    created: BlobRef::of(b"hello"),
+                }))
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.into_make_service()),
+        );
+
+        let client = Client::builder()
+            .remote(&remote)
+            .retry(RetryPolicy {
+                initial_backoff: Duration::from_millis(1),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let resp = client.put_blob(b"hello").await.unwrap();
+        assert_eq!(resp.created, BlobRef::of(b"hello"));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // Without retries, the first failure is what comes back
+        calls.store(0, Ordering::SeqCst);
+        let client = Client::builder()
+            .remote(&remote)
+            .retry(RetryPolicy::none())
+            .build()
+            .unwrap();
+        assert!(client.put_blob(b"hello").await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
     }
 }
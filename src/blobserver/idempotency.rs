@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Kind};
+use crate::Node;
+
+/// The header a client sends so retrying a create doesn't make the node twice.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Remembers the node created under each idempotency key for a while, so a
+/// retried create gets back what the first attempt made.
+///
+/// Creates under different keys don't wait on each other, only retries of
+/// the same one do. Keys past the window are dropped by `expire`, which is
+/// meant to be called every so often rather than on every create.
+pub struct IdempotencyKeys {
+    window: Duration,
+    max_keys: usize,
+    keys: Mutex<HashMap<String, Arc<Slot>>>,
+}
+
+// Empty until the first create under the key goes through
+type Slot = Mutex<Option<Remembered>>;
+
+struct Remembered {
+    at: Instant,
+    // Identifies the request body, so a key can't be reused for something else
+    fingerprint: String,
+    node: Node,
+}
+
+impl IdempotencyKeys {
+    /// Remembers up to max_keys creates, each for the window.
+    pub fn new(window: Duration, max_keys: usize) -> Self {
+        Self {
+            window,
+            max_keys,
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates the node with `create`, unless the key's already been used.
+    /// Returns the node and whether it's a replay of an earlier create.
+    ///
+    /// Reusing a key with a different request is a bad request. Failed
+    /// creates aren't remembered, so they can be retried with the same key.
+    pub fn create_once<F>(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        create: F,
+    ) -> Result<(Node, bool), Error>
+    where
+        F: FnOnce() -> Result<Node, Error>,
+    {
+        let slot = {
+            let mut keys = self.keys.lock().unwrap();
+            if !keys.contains_key(key) && keys.len() >= self.max_keys {
+                self.make_room(&mut keys);
+            }
+            keys.entry(key.to_owned()).or_default().clone()
+        };
+
+        // Held through the create, so two attempts racing each other
+        // can't both make a node
+        let mut remembered = slot.lock().unwrap();
+        if let Some(remembered) = remembered.as_ref().filter(|r| self.live(r)) {
+            if remembered.fingerprint != fingerprint {
+                return Err(Error::from_msg(
+                    "idempotency key was already used for a different request",
+                    Kind::BadRequest,
+                ));
+            }
+            return Ok((remembered.node.clone(), true));
+        }
+
+        let node = create()?;
+        *remembered = Some(Remembered {
+            at: Instant::now(),
+            fingerprint: fingerprint.to_owned(),
+            node: node.clone(),
+        });
+
+        Ok((node, false))
+    }
+
+    /// Drops the keys that have been remembered for longer than the window.
+    pub fn expire(&self) {
+        let mut keys = self.keys.lock().unwrap();
+        keys.retain(|_, slot| self.keep(slot));
+    }
+
+    fn live(&self, remembered: &Remembered) -> bool {
+        remembered.at.elapsed() < self.window
+    }
+
+    // Whether a slot is still needed. One anybody else holds is mid-create,
+    // and they can only have got it through the map, whose lock is held.
+    fn keep(&self, slot: &Arc<Slot>) -> bool {
+        Arc::strong_count(slot) > 1 || slot.lock().unwrap().as_ref().is_some_and(|r| self.live(r))
+    }
+
+    // Full up, so the expired keys go early. If that's not enough the
+    // oldest one does, which is the least likely to still be retried.
+    fn make_room(&self, keys: &mut HashMap<String, Arc<Slot>>) {
+        keys.retain(|_, slot| self.keep(slot));
+        if keys.len() < self.max_keys {
+            return;
+        }
+
+        let oldest = keys
+            .iter()
+            .filter(|(_, slot)| Arc::strong_count(slot) == 1)
+            .filter_map(|(key, slot)| Some((slot.lock().unwrap().as_ref()?.at, key)))
+            .min()
+            .map(|(_, key)| key.clone());
+        if let Some(key) = oldest {
+            keys.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str) -> Node {
+        Node {
+            id: id.to_owned(),
+            namespace: String::from(crate::DEFAULT_NAMESPACE),
+            node_type: crate::NodeType::File,
+            blobs: vec![],
+            attributes: Default::default(),
+            revision: 1,
+            updated: 0,
+            author: None,
+        }
+    }
+
+    #[test]
+    fn replays_within_window() {
+        let keys = IdempotencyKeys::new(Duration::from_secs(60), 10);
+
+        let (created, replayed) = keys.create_once("k", "body", || Ok(node("a"))).unwrap();
+        assert_eq!(created.id, "a");
+        assert!(!replayed);
+
+        let (created, replayed) = keys.create_once("k", "body", || Ok(node("b"))).unwrap();
+        assert_eq!(created.id, "a");
+        assert!(replayed);
+
+        let err = keys
+            .create_once("k", "other body", || Ok(node("c")))
+            .unwrap_err();
+        assert!(matches!(err.kind, Kind::BadRequest));
+    }
+
+    #[test]
+    fn forgets_failures_and_expired_keys() {
+        let keys = IdempotencyKeys::new(Duration::ZERO, 10);
+
+        let failed = keys.create_once("k", "body", || {
+            Err(Error::from_msg("disk full", Kind::InsufficientStorage))
+        });
+        assert!(failed.is_err());
+
+        keys.create_once("k", "body", || Ok(node("a"))).unwrap();
+        let (created, replayed) = keys.create_once("k", "body", || Ok(node("b"))).unwrap();
+        assert_eq!(created.id, "b");
+        assert!(!replayed);
+    }
+
+    #[test]
+    fn expires_and_caps_keys() {
+        let keys = IdempotencyKeys::new(Duration::ZERO, 10);
+        keys.create_once("k", "body", || Ok(node("a"))).unwrap();
+        keys.expire();
+        assert!(keys.keys.lock().unwrap().is_empty());
+
+        let keys = IdempotencyKeys::new(Duration::from_secs(60), 2);
+        keys.create_once("a", "body", || Ok(node("a"))).unwrap();
+        keys.create_once("b", "body", || Ok(node("b"))).unwrap();
+        keys.create_once("c", "body", || Ok(node("c"))).unwrap();
+        assert_eq!(keys.keys.lock().unwrap().len(), 2);
+
+        // The oldest made way
+        let (_, replayed) = keys.create_once("c", "body", || Ok(node("d"))).unwrap();
+        assert!(replayed);
+        let (_, replayed) = keys.create_once("a", "body", || Ok(node("e"))).unwrap();
+        assert!(!replayed);
+    }
+
+    #[test]
+    fn only_waits_on_the_same_key() {
+        let keys = Arc::new(IdempotencyKeys::new(Duration::from_secs(60), 10));
+        let (started, wait) = std::sync::mpsc::channel();
+        let (release, hold) = std::sync::mpsc::channel::<()>();
+
+        let slow = {
+            let keys = keys.clone();
+            std::thread::spawn(move || {
+                keys.create_once("slow", "body", || {
+                    started.send(()).unwrap();
+                    hold.recv().unwrap();
+                    Ok(node("a"))
+                })
+                .unwrap()
+            })
+        };
+        wait.recv().unwrap();
+
+        // Goes through while the other create is still going
+        let (created, _) = keys.create_once("fast", "body", || Ok(node("b"))).unwrap();
+        assert_eq!(created.id, "b");
+        keys.expire();
+
+        release.send(()).unwrap();
+        assert_eq!(slow.join().unwrap().0.id, "a");
+        let (created, replayed) = keys.create_once("slow", "body", || Ok(node("c"))).unwrap();
+        assert_eq!(created.id, "a");
+        assert!(replayed);
+    }
+}
@@ -0,0 +1,263 @@
+use std::time::Duration;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::{Storage, StorageError};
+
+/// Everything the server counts, exposed in the Prometheus text format.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    bytes_uploaded: IntCounter,
+    bytes_downloaded: IntCounter,
+    blob_puts: IntCounterVec,
+    nodes_created: IntCounter,
+    storage_errors: IntCounterVec,
+    store_blobs: IntGauge,
+    store_bytes: IntGauge,
+    store_physical_bytes: IntGauge,
+    store_nodes: IntGauge,
+    cache_hits: IntCounter,
+    cache_misses: IntCounter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new("anchorage_requests_total", "Requests handled"),
+            &["route", "status"],
+        )
+        .unwrap();
+        let latency = HistogramVec::new(
+            HistogramOpts::new(
+                "anchorage_request_duration_seconds",
+                "How long requests took to handle",
+            ),
+            &["route", "status"],
+        )
+        .unwrap();
+        let bytes_uploaded =
+            IntCounter::new("anchorage_bytes_uploaded_total", "Blob bytes received").unwrap();
+        let bytes_downloaded =
+            IntCounter::new("anchorage_bytes_downloaded_total", "Blob bytes served").unwrap();
+        let blob_puts = IntCounterVec::new(
+            Opts::new(
+                "anchorage_blob_puts_total",
+                "Blob uploads, by whether the blob was already stored",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let nodes_created =
+            IntCounter::new("anchorage_nodes_created_total", "Nodes created").unwrap();
+        let storage_errors = IntCounterVec::new(
+            Opts::new(
+                "anchorage_storage_errors_total",
+                "Errors from the blob store",
+            ),
+            &["kind"],
+        )
+        .unwrap();
+        let store_blobs = IntGauge::new("anchorage_store_blobs", "Blobs in the store").unwrap();
+        let store_bytes = IntGauge::new(
+            "anchorage_store_bytes",
+            "Bytes of blobs in the store, as they were uploaded",
+        )
+        .unwrap();
+        let store_physical_bytes = IntGauge::new(
+            "anchorage_store_physical_bytes",
+            "Bytes taken up by blobs in the store after compression",
+        )
+        .unwrap();
+        let store_nodes = IntGauge::new("anchorage_store_nodes", "Nodes in the store").unwrap();
+        let cache_hits = IntCounter::new(
+            "anchorage_cache_hits_total",
+            "Blob reads served from the cache",
+        )
+        .unwrap();
+        let cache_misses = IntCounter::new(
+            "anchorage_cache_misses_total",
+            "Blob reads that went past the cache to the store",
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        registry.register(Box::new(bytes_uploaded.clone())).unwrap();
+        registry
+            .register(Box::new(bytes_downloaded.clone()))
+            .unwrap();
+        registry.register(Box::new(blob_puts.clone())).unwrap();
+        registry.register(Box::new(nodes_created.clone())).unwrap();
+        registry.register(Box::new(storage_errors.clone())).unwrap();
+        registry.register(Box::new(store_blobs.clone())).unwrap();
+        registry.register(Box::new(store_bytes.clone())).unwrap();
+        registry
+            .register(Box::new(store_physical_bytes.clone()))
+            .unwrap();
+        registry.register(Box::new(store_nodes.clone())).unwrap();
+        registry.register(Box::new(cache_hits.clone())).unwrap();
+        registry.register(Box::new(cache_misses.clone())).unwrap();
+
+        Self {
+            registry,
+            requests,
+            latency,
+            bytes_uploaded,
+            bytes_downloaded,
+            blob_puts,
+            nodes_created,
+            storage_errors,
+            store_blobs,
+            store_bytes,
+            store_physical_bytes,
+            store_nodes,
+            cache_hits,
+            cache_misses,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_request(&self, route: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        self.requests.with_label_values(&[route, &status]).inc();
+        self.latency
+            .with_label_values(&[route, &status])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Counts a blob upload, and whether it was already in the store.
+    pub fn record_blob_put(&self, bytes: usize, deduped: bool) {
+        self.bytes_uploaded.inc_by(bytes as u64);
+        let result = if deduped { "dedupe" } else { "new" };
+        self.blob_puts.with_label_values(&[result]).inc();
+    }
+
+    pub fn record_blob_get(&self, bytes: usize) {
+        self.bytes_downloaded.inc_by(bytes as u64);
+    }
+
+    pub fn record_node_created(&self) {
+        self.nodes_created.inc();
+        self.store_nodes.inc();
+    }
+
+    pub fn record_node_deleted(&self) {
+        self.store_nodes.dec();
+    }
+
+    /// Sets how many nodes there are to start from, which creating and
+    /// deleting them keeps up to date after.
+    pub fn set_nodes(&self, count: usize) {
+        self.store_nodes.set(count as i64);
+    }
+
+    pub fn record_storage_error(&self, err: &StorageError) {
+        let kind = match err {
+            StorageError::NotFound => "not_found",
+            StorageError::IO(_) => "io",
+            StorageError::InsufficientSpace { .. } => "insufficient_space",
+        };
+        self.storage_errors.with_label_values(&[kind]).inc();
+    }
+
+    /// Updates what the blob store is holding. It means going over the
+    /// whole store, so it's done now and again rather than on every scrape.
+    pub fn refresh(&self, store: &dyn Storage) {
+        match store.stats() {
+            Ok(stats) => {
+                self.store_blobs.set(stats.blobs as i64);
+                self.store_bytes.set(stats.bytes as i64);
+                self.store_physical_bytes.set(stats.physical_bytes as i64);
+                // The cache counts for itself, these only catch up to it
+                if let Some(cache) = stats.cache {
+                    self.cache_hits
+                        .inc_by(cache.hits.saturating_sub(self.cache_hits.get()));
+                    self.cache_misses
+                        .inc_by(cache.misses.saturating_sub(self.cache_misses.get()));
+                }
+            }
+            Err(e) => self.record_storage_error(&e),
+        }
+    }
+
+    /// Renders everything in the text format, with the stores as of the
+    /// last refresh.
+    pub fn render(&self) -> String {
+        let mut buf = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BlobRef, CacheStats, StorageHealth, StorageStats};
+
+    struct Sized;
+
+    impl Storage for Sized {
+        fn get(&self, _: &BlobRef) -> Result<Vec<u8>, StorageError> {
+            Err(StorageError::NotFound)
+        }
+        fn put(&self, _: &BlobRef, _: Vec<u8>) -> Result<(), StorageError> {
+            Ok(())
+        }
+        fn contains(&self, _: &BlobRef) -> Result<bool, StorageError> {
+            Ok(false)
+        }
+        fn list_blobs(&self) -> Result<Vec<BlobRef>, StorageError> {
+            Ok(vec![])
+        }
+        fn stats(&self) -> Result<StorageStats, StorageError> {
+            Ok(StorageStats {
+                blobs: 2,
+                bytes: 1024,
+                physical_bytes: 256,
+                cache: Some(CacheStats {
+                    hits: 3,
+                    ..Default::default()
+                }),
+            })
+        }
+        fn check(&self) -> Result<StorageHealth, StorageError> {
+            Ok(StorageHealth::default())
+        }
+    }
+
+    #[test]
+    fn renders_text_format() {
+        let metrics = Metrics::default();
+        metrics.record_request("/blob", 200, Duration::from_millis(5));
+        metrics.record_blob_put(10, false);
+        metrics.record_blob_put(10, true);
+        metrics.record_storage_error(&StorageError::NotFound);
+
+        metrics.set_nodes(1);
+        metrics.record_node_created();
+        metrics.record_node_created();
+        metrics.record_node_deleted();
+        assert!(metrics.render().contains("anchorage_store_bytes 0"));
+
+        metrics.refresh(&Sized);
+        let rendered = metrics.render();
+        assert!(rendered.contains(r#"anchorage_requests_total{route="/blob",status="200"} 1"#));
+        assert!(rendered.contains(r#"anchorage_blob_puts_total{result="dedupe"} 1"#));
+        assert!(rendered.contains("anchorage_bytes_uploaded_total 20"));
+        assert!(rendered.contains(r#"anchorage_storage_errors_total{kind="not_found"} 1"#));
+        assert!(rendered.contains("anchorage_store_bytes 1024"));
+        assert!(rendered.contains("anchorage_store_physical_bytes 256"));
+        assert!(rendered.contains("anchorage_cache_hits_total 3"));
+        assert!(rendered.contains("anchorage_nodes_created_total 2"));
+        assert!(rendered.contains("anchorage_store_nodes 2"));
+    }
+}
@@ -1,2 +1,10 @@
+pub mod auth;
+pub mod changes;
 pub mod client;
+pub mod idempotency;
+pub mod metrics;
+pub mod namespaces;
+pub mod replication;
 pub mod server;
+pub mod snapshots;
+pub mod tls;
@@ -0,0 +1,113 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use crate::error::Error;
+use crate::{BlobRef, Node, NodeStore};
+
+/// Which namespaces each blob has been uploaded to or is pointed at from,
+/// so reading a blob takes being able to read one of them.
+///
+/// Blobs are shared by content, so one can be in any number of namespaces.
+/// Only what nodes point at is kept across restarts, so a blob that was
+/// uploaded but never pointed at can't be read until a node does.
+#[derive(Default)]
+pub struct BlobNamespaces {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // Each namespace's name once, shared by every blob in it
+    names: HashSet<Arc<str>>,
+    blobs: HashMap<BlobRef, Vec<Arc<str>>>,
+}
+
+impl BlobNamespaces {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the index from every revision of the nodes listed from the
+    /// store.
+    pub fn load(store: &dyn NodeStore, nodes: &[Node]) -> Result<Self, Error> {
+        let namespaces = Self::new();
+        for node in nodes {
+            namespaces.add(&node.namespace, &node.blobs);
+            // Earlier revisions can still be restored, so their blobs count
+            if node.revision > 1 {
+                for revision in store.revisions(&node.id)? {
+                    namespaces.add(&revision.namespace, &revision.blobs);
+                }
+            }
+        }
+
+        Ok(namespaces)
+    }
+
+    /// Notes that the blobs are in the namespace.
+    pub fn add(&self, namespace: &str, blobs: &[BlobRef]) {
+        let mut inner = self.inner.write().unwrap();
+        let name = match inner.names.get(namespace) {
+            Some(name) => name.clone(),
+            None => {
+                let name: Arc<str> = Arc::from(namespace);
+                inner.names.insert(name.clone());
+                name
+            }
+        };
+
+        for id in blobs {
+            let namespaces = inner.blobs.entry(id.clone()).or_default();
+            if !namespaces.contains(&name) {
+                namespaces.push(name.clone());
+            }
+        }
+    }
+
+    /// Whether the blob is in any namespace the check passes for.
+    pub fn any(&self, id: &BlobRef, check: impl Fn(&str) -> bool) -> bool {
+        self.inner
+            .read()
+            .unwrap()
+            .blobs
+            .get(id)
+            .is_some_and(|namespaces| namespaces.iter().any(|ns| check(ns)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Memory;
+    use crate::NodeType;
+
+    #[test]
+    fn loads_every_revision() {
+        let one = BlobRef::of(b"one");
+        let two = BlobRef::of(b"two");
+        let mut node = Node {
+            id: String::from("abc"),
+            namespace: String::from("photos"),
+            node_type: NodeType::File,
+            blobs: vec![one.clone()],
+            attributes: Default::default(),
+            revision: 1,
+            updated: 0,
+            author: None,
+        };
+        let memory = Memory::new();
+        memory.put("abc", &node).unwrap();
+        node.blobs = vec![two.clone()];
+        node.revision = 2;
+        memory.update("abc", 1, &node).unwrap();
+
+        let namespaces = BlobNamespaces::load(&memory, &memory.list().unwrap()).unwrap();
+        assert!(namespaces.any(&one, |ns| ns == "photos"));
+        assert!(namespaces.any(&two, |ns| ns == "photos"));
+
+        namespaces.add("documents", std::slice::from_ref(&two));
+        assert!(namespaces.any(&two, |ns| ns == "documents"));
+        assert!(!namespaces.any(&one, |ns| ns == "documents"));
+        assert!(!namespaces.any(&BlobRef::of(b"three"), |_| true));
+    }
+}
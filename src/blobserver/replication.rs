@@ -0,0 +1,498 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::blobserver::changes::Change;
+use crate::blobserver::client::Client;
+use crate::blobserver::server::{self, State};
+use crate::error::{Error, Kind, WithKind};
+use crate::{Node, StorageError};
+
+// How many changes are asked for at once
+const PAGE_SIZE: usize = 500;
+
+/// Which way changes go between this server and a peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum Direction {
+    // Changes on the peer are copied here
+    #[default]
+    Pull,
+    // Changes here are copied to the peer
+    Push,
+    Both,
+}
+
+/// Copies blobs and nodes between this server and its peers, following
+/// their change logs and its own.
+///
+/// How far it got with each peer is kept, so it picks up where it left off.
+/// Peers have to let it in as an admin, since changes cover every namespace.
+pub struct Replicator {
+    state: State,
+    peers: Vec<(Client, Direction)>,
+    // Where the cursors are kept between restarts, if anywhere
+    cursors_path: Option<PathBuf>,
+    // By the peer's url
+    cursors: Mutex<HashMap<String, Cursors>>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Cursors {
+    #[serde(default)]
+    pull: Option<String>,
+    #[serde(default)]
+    push: Option<String>,
+}
+
+/// What a sync copied, either way.
+#[derive(Debug, Default)]
+pub struct Synced {
+    pub blobs: u64,
+    pub nodes: u64,
+    // Nodes changed differently here and on a peer, which are left alone
+    pub diverged: u64,
+}
+
+impl Replicator {
+    pub fn new(state: State, cursors_path: Option<PathBuf>) -> Result<Self, Error> {
+        let cursors = match &cursors_path {
+            Some(path) => match std::fs::read(path) {
+                Ok(contents) => serde_json::from_slice(&contents)
+                    .with_kind("error decoding replication cursors", Kind::Internal)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+                Err(e) => {
+                    return Err(Error::from_err(
+                        "error reading replication cursors",
+                        e,
+                        Kind::Internal,
+                    ))
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        Ok(Self {
+            state,
+            peers: vec![],
+            cursors_path,
+            cursors: Mutex::new(cursors),
+        })
+    }
+
+    /// Adds a peer, which the client should be signed in to as an admin.
+    pub fn peer(mut self, client: Client, direction: Direction) -> Self {
+        self.peers.push((client, direction));
+        self
+    }
+
+    /// Syncs with every peer once, until there's nothing new either way.
+    ///
+    /// A peer that can't be synced with doesn't stop the rest, the first
+    /// error is returned once they've all been tried.
+    pub async fn sync(&self) -> Result<Synced, Error> {
+        let mut synced = Synced::default();
+        let mut err = None;
+        for (client, direction) in &self.peers {
+            let res = match direction {
+                Direction::Pull => self.pull(client, &mut synced).await,
+                Direction::Push => self.push(client, &mut synced).await,
+                Direction::Both => match self.pull(client, &mut synced).await {
+                    Ok(()) => self.push(client, &mut synced).await,
+                    Err(e) => Err(e),
+                },
+            };
+            if let Err(e) = res {
+                tracing::warn!("error syncing with {}: {}", client.remote(), e);
+                err.get_or_insert(e);
+            }
+        }
+
+        match err {
+            Some(e) => Err(e),
+            None => Ok(synced),
+        }
+    }
+
+    /// Syncs every interval, forever.
+    pub async fn run(self, interval: Duration) {
+        loop {
+            match self.sync().await {
+                Ok(synced) => tracing::info!(
+                    blobs = synced.blobs,
+                    nodes = synced.nodes,
+                    diverged = synced.diverged,
+                    "synced with peers"
+                ),
+                Err(e) => tracing::error!("error syncing with peers: {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    // Copies whatever's changed on the peer since last time over here
+    async fn pull(&self, client: &Client, synced: &mut Synced) -> Result<(), Error> {
+        loop {
+            let since = self.cursors(client).pull;
+            let page = client.changes(since.as_deref(), PAGE_SIZE).await?;
+            for entry in page.changes {
+                self.pull_change(client, entry.change, synced).await?;
+            }
+
+            self.save_cursor(client, |c| c.pull = Some(page.cursor))?;
+            if !page.more {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn pull_change(
+        &self,
+        client: &Client,
+        change: Change,
+        synced: &mut Synced,
+    ) -> Result<(), Error> {
+        let state = &self.state;
+        if let Change::Node { id, .. } | Change::NodeDeleted { id } = &change {
+            if let Err(e) = crate::validate_node_id(id) {
+                tracing::warn!("skipping change from {}: {}", client.remote(), e);
+                return Ok(());
+            }
+        }
+
+        match change {
+            Change::Blob { id } => {
+                if state.blob_store.contains(&id).map_err(storage_error)? {
+                    return Ok(());
+                }
+                let data = client.get_blob(&id).await?.data()?;
+                if !id.matches(&data) {
+                    return Err(Error::from_msg(
+                        &format!("peer sent blob {} with the wrong data", id),
+                        Kind::Internal,
+                    ));
+                }
+
+                state.blob_store.put(&id, data).map_err(storage_error)?;
+                state.changes.record(Change::Blob { id })?;
+                synced.blobs += 1;
+            }
+            Change::Node {
+                id,
+                revision,
+                updated,
+            } => {
+                let local = match state.node_store.get(&id) {
+                    Ok(node) => Some(node),
+                    Err(e) if matches!(e.kind, Kind::NotFound) => None,
+                    Err(e) => return Err(e),
+                };
+                if let Some(local) = local.filter(|n| n.revision >= revision) {
+                    if diverged(&local, revision, updated) {
+                        tracing::warn!(
+                            "node {} has diverged from {} at revision {}",
+                            id,
+                            client.remote(),
+                            revision
+                        );
+                        synced.diverged += 1;
+                    }
+                    return Ok(());
+                }
+
+                // Gone since, which a later change will say
+                let revisions = match client.node_revisions(&id).await {
+                    Ok(revisions) => revisions,
+                    Err(e) if matches!(e.kind, Kind::NotFound) => return Ok(()),
+                    Err(e) => return Err(e),
+                };
+                if server::apply_revisions(state, &id, revisions)? {
+                    synced.nodes += 1;
+                }
+            }
+            Change::NodeDeleted { id } => match state.node_store.delete(&id) {
+                Ok(()) => {
+                    state.metrics.record_node_deleted();
+                    state.snapshots.remove(&id);
+                    state.changes.record(Change::NodeDeleted { id })?;
+                    synced.nodes += 1;
+                }
+                Err(e) if matches!(e.kind, Kind::NotFound) => {}
+                Err(e) => return Err(e),
+            },
+        }
+
+        Ok(())
+    }
+
+    // Copies whatever's changed here since last time over to the peer
+    async fn push(&self, client: &Client, synced: &mut Synced) -> Result<(), Error> {
+        loop {
+            let since = self.cursors(client).push;
+            let page = self.state.changes.since(since.as_deref(), PAGE_SIZE)?;
+            for entry in page.changes {
+                self.push_change(client, entry.change, synced).await?;
+            }
+
+            self.save_cursor(client, |c| c.push = Some(page.cursor))?;
+            if !page.more {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn push_change(
+        &self,
+        client: &Client,
+        change: Change,
+        synced: &mut Synced,
+    ) -> Result<(), Error> {
+        let state = &self.state;
+        match change {
+            Change::Blob { id } => {
+                if client.has_blob(&id).await? {
+                    return Ok(());
+                }
+                let data = state.blob_store.get(&id).map_err(storage_error)?;
+                client.put_blob_as(&id, &data).await?;
+                synced.blobs += 1;
+            }
+            Change::Node {
+                id,
+                revision,
+                updated,
+            } => {
+                let remote = match client.get_node(&id).await {
+                    Ok(node) => Some(node),
+                    Err(e) if matches!(e.kind, Kind::NotFound) => None,
+                    Err(e) => return Err(e),
+                };
+                if let Some(remote) = remote.filter(|n| n.revision >= revision) {
+                    if diverged(&remote, revision, updated) {
+                        tracing::warn!(
+                            "node {} has diverged on {} at revision {}",
+                            id,
+                            client.remote(),
+                            revision
+                        );
+                        synced.diverged += 1;
+                    }
+                    return Ok(());
+                }
+
+                let revisions = match state.node_store.revisions(&id) {
+                    Ok(revisions) => revisions,
+                    Err(e) if matches!(e.kind, Kind::NotFound) => return Ok(()),
+                    Err(e) => return Err(e),
+                };
+                client.replicate_node(&id, &revisions).await?;
+                synced.nodes += 1;
+            }
+            Change::NodeDeleted { id } => match client.delete_node(&id).await {
+                Ok(_) => synced.nodes += 1,
+                Err(e) if matches!(e.kind, Kind::NotFound) => {}
+                Err(e) => return Err(e),
+            },
+        }
+
+        Ok(())
+    }
+
+    fn cursors(&self, client: &Client) -> Cursors {
+        let cursors = self.cursors.lock().unwrap();
+        cursors.get(client.remote()).cloned().unwrap_or_default()
+    }
+
+    // Moves a peer's cursor along, writing them all out if they're kept
+    fn save_cursor(&self, client: &Client, update: impl FnOnce(&mut Cursors)) -> Result<(), Error> {
+        let mut cursors = self.cursors.lock().unwrap();
+        update(cursors.entry(client.remote().to_owned()).or_default());
+
+        let Some(path) = &self.cursors_path else {
+            return Ok(());
+        };
+        let contents = serde_json::to_vec(&*cursors)
+            .with_kind("error encoding replication cursors", Kind::Internal)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, contents)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .with_kind("error writing replication cursors", Kind::Internal)
+    }
+}
+
+// Whether a node already at a change's revision got there some other way.
+// Changes that don't say when they were made can't tell, and a node that's
+// moved past them is for a later change to compare.
+fn diverged(node: &Node, revision: u64, updated: u64) -> bool {
+    node.revision == revision && updated != 0 && node.updated != updated
+}
+
+fn storage_error(err: StorageError) -> Error {
+    Error::from_err("error replicating blob", err, Kind::Internal)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::blobserver::changes::ChangeLog;
+    use crate::blobserver::client::RetryPolicy;
+    use crate::blobserver::idempotency::IdempotencyKeys;
+    use crate::blobserver::metrics::Metrics;
+    use crate::blobserver::namespaces::BlobNamespaces;
+    use crate::blobserver::server::{CreateNodeRequest, UpdateNodeRequest};
+    use crate::blobserver::snapshots::Snapshots;
+    use crate::storage::Memory;
+    use crate::{hash, Acl, AclStore, BlobRef, NodeType, DEFAULT_NAMESPACE, WILDCARD};
+
+    // Starts a server over memory stores where everyone's an admin,
+    // returning its state and url
+    fn server() -> (State, String) {
+        let memory = Arc::new(Memory::new());
+        let acl = Acl {
+            admins: vec![String::from(WILDCARD)],
+            namespaces: HashMap::new(),
+        };
+        memory.put_acl(&acl).unwrap();
+
+        let state = State {
+            blob_store: memory.clone(),
+            node_store: memory.clone(),
+            acl_store: memory,
+            acl: Arc::new(std::sync::RwLock::new(acl)),
+            metrics: Arc::new(Metrics::default()),
+            idempotency: Arc::new(IdempotencyKeys::new(Duration::from_secs(60), 100)),
+            hasher: hash::DEFAULT,
+            changes: Arc::new(ChangeLog::memory()),
+            blob_namespaces: Arc::new(BlobNamespaces::new()),
+            snapshots: Arc::new(Snapshots::new()),
+        };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener).unwrap().serve(
+                server::new_router()
+                    .with_state(state.clone())
+                    .into_make_service(),
+            ),
+        );
+
+        (state, remote)
+    }
+
+    fn client(remote: &str) -> Client {
+        Client::builder()
+            .remote(remote)
+            .retry(RetryPolicy::none())
+            .build()
+            .unwrap()
+    }
+
+    fn file(blobs: Vec<BlobRef>) -> CreateNodeRequest {
+        CreateNodeRequest {
+            namespace: String::from(DEFAULT_NAMESPACE),
+            node_type: NodeType::File,
+            blobs,
+            attributes: HashMap::new(),
+            allow_dangling: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_a_peer() {
+        let dir = tempfile::tempdir().unwrap();
+        let cursors = dir.path().join("cursors.json");
+        let (_, a) = server();
+        let (b_state, b) = server();
+        let (a, b) = (client(&a), client(&b));
+
+        let id = a.put_blob(b"hello").await.unwrap().created;
+        let node = a.create_node(file(vec![id.clone()])).await.unwrap();
+        let update = UpdateNodeRequest {
+            revision: 1,
+            blobs: None,
+            attributes: Some(HashMap::from([(String::from("k"), String::from("v"))])),
+            allow_dangling: false,
+        };
+        a.update_node(&node.id, update).await.unwrap();
+
+        let replicator = Replicator::new(b_state.clone(), Some(cursors.clone()))
+            .unwrap()
+            .peer(client(a.remote()), Direction::Pull);
+        let synced = replicator.sync().await.unwrap();
+        assert_eq!((synced.blobs, synced.nodes), (1, 1));
+        assert_eq!(b.get_blob(&id).await.unwrap().data().unwrap(), b"hello");
+        assert_eq!(b.node_revisions(&node.id).await.unwrap().len(), 2);
+
+        // Starting again picks up from the saved cursor
+        a.delete_node(&node.id).await.unwrap();
+        let replicator = Replicator::new(b_state, Some(cursors))
+            .unwrap()
+            .peer(client(a.remote()), Direction::Pull);
+        let synced = replicator.sync().await.unwrap();
+        assert_eq!((synced.blobs, synced.nodes), (0, 1));
+        let err = b.get_node(&node.id).await.unwrap_err();
+        assert!(matches!(err.kind, Kind::NotFound));
+    }
+
+    #[tokio::test]
+    async fn syncs_both_ways() {
+        let (a_state, a) = server();
+        let (_, b) = server();
+        let (a, b) = (client(&a), client(&b));
+
+        let ours = a.put_blob(b"ours").await.unwrap().created;
+        let theirs = b.put_blob(b"theirs").await.unwrap().created;
+        let node = b.create_node(file(vec![theirs.clone()])).await.unwrap();
+
+        let replicator = Replicator::new(a_state, None)
+            .unwrap()
+            .peer(client(b.remote()), Direction::Both);
+        let synced = replicator.sync().await.unwrap();
+        assert_eq!((synced.blobs, synced.nodes), (2, 1));
+        for id in [&ours, &theirs] {
+            assert!(a.has_blob(id).await.unwrap());
+            assert!(b.has_blob(id).await.unwrap());
+        }
+        assert_eq!(a.get_node(&node.id).await.unwrap().blobs, vec![theirs]);
+
+        // Everything that went across comes back as a change, but there's
+        // nothing left to copy
+        let synced = replicator.sync().await.unwrap();
+        assert_eq!((synced.blobs, synced.nodes), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn notices_divergence_at_the_same_revision() {
+        let (_, a) = server();
+        let (b_state, b) = server();
+        let (a, b) = (client(&a), client(&b));
+        let node = a.create_node(file(vec![])).await.unwrap();
+        let replicator = Replicator::new(b_state.clone(), None)
+            .unwrap()
+            .peer(client(a.remote()), Direction::Pull);
+        replicator.sync().await.unwrap();
+
+        // Both change it, so each has its own second revision
+        let update = UpdateNodeRequest {
+            revision: 1,
+            blobs: None,
+            attributes: Some(HashMap::from([(String::from("k"), String::from("a"))])),
+            allow_dangling: false,
+        };
+        a.update_node(&node.id, update).await.unwrap();
+        let ours = crate::Node {
+            revision: 2,
+            updated: 1,
+            ..b.get_node(&node.id).await.unwrap()
+        };
+        b_state.node_store.update(&node.id, 1, &ours).unwrap();
+
+        let synced = replicator.sync().await.unwrap();
+        assert_eq!((synced.nodes, synced.diverged), (0, 1));
+        assert_eq!(b.get_node(&node.id).await.unwrap().updated, 1);
+    }
+}
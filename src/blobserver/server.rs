@@ -1,24 +1,39 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
 use std::{fmt::Debug, result::Result};
 
 use axum::routing::post;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use axum::{
-    extract::{DefaultBodyLimit, Json as exJson, Path, State as exState},
+    async_trait,
+    body::Body,
+    extract::{
+        DefaultBodyLimit, FromRequest, FromRequestParts, Json as exJson, Path, Query,
+        State as exState,
+    },
+    http::{request::Parts, HeaderMap, Request},
     response::IntoResponse,
     routing::{get, put},
     Json, Router,
 };
 use hyper::StatusCode;
-use tracing::debug;
 use uuid::Uuid;
 
 use crate::{
+    blobserver::{
+        auth,
+        changes::{Change, ChangeLog, Changes},
+        idempotency::{IdempotencyKeys, IDEMPOTENCY_KEY_HEADER},
+        metrics::Metrics,
+        namespaces::BlobNamespaces,
+        snapshots::Snapshots,
+    },
     error::{Error, Kind},
-    Storage,
+    hash::{self, Hasher},
+    Storage, StorageError,
 };
-use crate::{Node, NodeStore, NodeType};
+use crate::{Acl, AclStore, BlobRef, Identity, Node, NodeStore, NodeType, Permission, WILDCARD};
 
 use base64::{engine::general_purpose, Engine as _};
 use sha256::digest;
@@ -27,20 +42,209 @@ use sha256::digest;
 pub struct State {
     pub blob_store: Arc<dyn Storage + Send + Sync>,
     pub node_store: Arc<dyn NodeStore + Send + Sync>,
+    pub acl_store: Arc<dyn AclStore + Send + Sync>,
+    // The acl in the store, loaded once and kept up to date when it's
+    // replaced here, so requests don't each read it
+    pub acl: Arc<RwLock<Acl>>,
+    pub metrics: Arc<Metrics>,
+    pub idempotency: Arc<IdempotencyKeys>,
+    // What blobs are hashed with when the client doesn't say
+    pub hasher: &'static dyn Hasher,
+    // Every write, for peers replicating from this server
+    pub changes: Arc<ChangeLog>,
+    // Which namespaces can read each blob
+    pub blob_namespaces: Arc<BlobNamespaces>,
+    // The snapshots in each namespace
+    pub snapshots: Arc<Snapshots>,
+}
+
+impl State {
+    fn acl(&self) -> RwLockReadGuard<'_, Acl> {
+        self.acl.read().unwrap()
+    }
 }
 
 pub fn new_router() -> Router<State> {
     Router::new()
         .route("/blob", put(create_blob))
-        .route("/blob/:hash", get(fetch_blob))
+        .route("/blob/:hash", get(fetch_blob).head(blob_exists))
+        .route("/info", get(fetch_info))
         .route("/node", post(create_node))
-        .layer(DefaultBodyLimit::max(1024 * 1024 * 11)) // 11MB
+        .route(
+            "/node/:id",
+            get(fetch_node).put(update_node).delete(delete_node),
+        )
+        .route(
+            "/node/:id/revisions",
+            get(fetch_revisions).put(replicate_node),
+        )
+        .route("/snapshots", get(list_snapshots))
+        .route("/acl", get(fetch_acl).put(replace_acl))
+        .route("/changes", get(fetch_changes))
+        .layer(DefaultBodyLimit::max(MAX_BODY_BYTES))
+}
+
+/// The most a request's body can be. A 10MB chunk grows by a third when
+/// base64 encoded.
+pub const MAX_BODY_BYTES: usize = 1024 * 1024 * 14; // 14MB
+
+/// The header blob requests use to say which namespace they're acting in.
+pub const NAMESPACE_HEADER: &str = "x-anchorage-namespace";
+
+// Pulls the identity out of the request's extensions, where whatever
+// authenticated the request should have left it.
+//
+// If nothing did, the request is anonymous.
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Identity {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<Identity>()
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// The namespace a blob request is acting in, taken from the namespace header.
+pub struct Namespace(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Namespace {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let namespace = parts
+            .headers
+            .get(NAMESPACE_HEADER)
+            .ok_or_else(|| Error::from_msg("missing namespace header", Kind::BadRequest))?
+            .to_str()
+            .map_err(|e| Error::from_err("invalid namespace header", e, Kind::BadRequest))?;
+
+        Ok(Namespace(namespace.to_owned()))
+    }
+}
+
+// Pulls a blob id out of the path, turning away anything malformed before
+// it gets near the store.
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for BlobRef {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path(id) = Path::<String>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| Error::from_err("invalid path", e, Kind::BadRequest))?;
+
+        BlobRef::parse(&id)
+    }
+}
+
+/// Axum's json extractor, with rejections turned into errors the client
+/// can decode.
+pub struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S, Body> for JsonBody<T> {
+    type Rejection = Error;
+
+    async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        let exJson(value) = exJson::<T>::from_request(req, state)
+            .await
+            .map_err(|e| Error::from_msg(&e.body_text(), Kind::BadRequest))?;
+
+        Ok(JsonBody(value))
+    }
+}
+
+// Checks the acl to see if the identity can act on the namespace,
+// returning a permission error if not.
+fn authorize(
+    acl: &Acl,
+    identity: &Identity,
+    namespace: &str,
+    permission: Permission,
+) -> Result<(), Error> {
+    if acl.allows(identity, namespace, permission) {
+        return Ok(());
+    }
+
+    Err(Error::from_msg(
+        &format!("no {:?} access to namespace '{}'", permission, namespace),
+        Kind::Permission,
+    ))
+}
+
+// Checks the identity can read a namespace the blob is in, or every
+// namespace for a blob that isn't in any.
+//
+// A blob that can't be read looks the same as one that isn't there, so
+// callers can't find out what other namespaces hold.
+fn authorize_blob(
+    state: &State,
+    acl: &Acl,
+    identity: &Identity,
+    id: &BlobRef,
+) -> Result<(), Error> {
+    if acl.allows(identity, WILDCARD, Permission::Read)
+        || state
+            .blob_namespaces
+            .any(id, |ns| acl.allows(identity, ns, Permission::Read))
+    {
+        return Ok(());
+    }
+
+    Err(Error::from_msg("blob not found", Kind::NotFound))
+}
+
+// Looks up a node, checking the identity has the permission on its namespace.
+//
+// A node that can't be read looks the same as one that isn't there, so
+// callers can't find out what's in namespaces they can't see.
+fn authorized_node(
+    state: &State,
+    acl: &Acl,
+    identity: &Identity,
+    id: &str,
+    permission: Permission,
+) -> Result<Node, Error> {
+    crate::validate_node_id(id)?;
+    let not_found = || Error::from_msg("node not found", Kind::NotFound);
+    let node = match state.node_store.get(id) {
+        Err(e) if matches!(e.kind, Kind::NotFound) => return Err(not_found()),
+        node => node?,
+    };
+    if !acl.allows(identity, &node.namespace, Permission::Read) {
+        return Err(not_found());
+    }
+    authorize(acl, identity, &node.namespace, permission)?;
+
+    Ok(node)
+}
+
+// Turns a storage failure into an error for the client, counting it on the way.
+//
+// Running out of space gets its own kind, anything else gets the one given.
+fn storage_error(state: &State, msg: &str, err: StorageError, kind: Kind) -> Error {
+    state.metrics.record_storage_error(&err);
+
+    let kind = match err {
+        StorageError::InsufficientSpace { .. } => Kind::InsufficientStorage,
+        _ => kind,
+    };
+    Error::from_err(msg, err, kind)
 }
 
 /// CreateBlobRequest holds the data to be stored by the server.
 #[derive(Serialize, Deserialize)]
 pub struct CreateBlobRequest {
     pub data: String,
+    // The id the client hashed the data to, which gets checked. Without one,
+    // the server hashes it with its preferred algorithm.
+    #[serde(default)]
+    pub id: Option<BlobRef>,
 }
 
 impl CreateBlobRequest {
@@ -57,16 +261,20 @@ impl CreateBlobRequest {
 
 /// Returned on a successful call to store a blob. It contains
 /// the hash that was inserted.
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct CreateBlobResponse {
-    pub created: String,
+    pub created: BlobRef,
 }
 
 // Endpoint for ingesting a blob
 async fn create_blob(
     exState(state): exState<State>,
-    exJson(body): exJson<CreateBlobRequest>,
+    identity: Identity,
+    Namespace(namespace): Namespace,
+    JsonBody(body): JsonBody<CreateBlobRequest>,
 ) -> Result<Json<CreateBlobResponse>, Error> {
+    authorize(&state.acl(), &identity, &namespace, Permission::Write)?;
+
     body.validate()
         .map_err(|e| Error::from_msg(e, Kind::BadRequest))?;
 
@@ -76,22 +284,69 @@ async fn create_blob(
         .map_err(|e| Error::from_err("error decoding body", e, Kind::BadRequest))?;
 
     // The name of the file will be the hash of the contents
-    let id = format!("sha256-{}", digest(data.as_slice()));
+    let id = match body.id {
+        Some(id) if !id.matches(&data) => {
+            return Err(Error::from_msg(
+                &format!("data doesn't hash to {}", id),
+                Kind::BadRequest,
+            ))
+        }
+        Some(id) => id,
+        None => BlobRef::hash(state.hasher, &data),
+    };
 
-    // Store it in the blob store
+    // Store it in the blob store, noting if it was already there
+    let size = data.len();
+    let stored = state.blob_store.contains(&id).and_then(|deduped| {
+        state.blob_store.put(&id, data)?;
+        Ok(deduped)
+    });
+    let deduped =
+        stored.map_err(|e| storage_error(&state, "error storing blob", e, Kind::BadRequest))?;
+    state.metrics.record_blob_put(size, deduped);
     state
-        .blob_store
-        .put(&id, data)
-        .map_err(|e| Error::from_err("error storing blob", e, Kind::BadRequest))?;
+        .blob_namespaces
+        .add(&namespace, std::slice::from_ref(&id));
+    if !deduped {
+        state.changes.record(Change::Blob { id: id.clone() })?;
+    }
 
     Ok(Json(CreateBlobResponse { created: id }))
 }
 
+/// What clients need to know to talk to the server.
+#[derive(Serialize, Deserialize)]
+pub struct ServerInfo {
+    // The algorithm clients should hash blobs with
+    pub hash_algorithm: String,
+    // Every algorithm blob ids are accepted from
+    pub hash_algorithms: Vec<String>,
+}
+
+async fn fetch_info(exState(state): exState<State>) -> Json<ServerInfo> {
+    Json(ServerInfo {
+        hash_algorithm: state.hasher.name().to_owned(),
+        hash_algorithms: hash::ALGORITHMS
+            .iter()
+            .map(|h| h.name().to_owned())
+            .collect(),
+    })
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct BlobResponse {
     pub contents: String,
 }
 
+impl BlobResponse {
+    /// Decodes the blob's contents back into bytes.
+    pub fn data(&self) -> Result<Vec<u8>, Error> {
+        general_purpose::STANDARD_NO_PAD
+            .decode(&self.contents)
+            .map_err(|e| Error::from_err("error decoding blob", e, Kind::Internal))
+    }
+}
+
 impl Debug for BlobResponse {
     fn fmt(&self, w: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // Send it in plain text if it it's utf8
@@ -108,13 +363,17 @@ impl Debug for BlobResponse {
 
 // Endpoint for fetching a stored blob
 async fn fetch_blob(
-    Path(hash): Path<String>,
+    hash: BlobRef,
     exState(state): exState<State>,
+    identity: Identity,
 ) -> Result<impl IntoResponse, Error> {
+    authorize_blob(&state, &state.acl(), &identity, &hash)?;
+
     let data_res = state
         .blob_store
         .get(&hash)
-        .map_err(|e| Error::from_err("error finding blob", e, Kind::NotFound))?;
+        .map_err(|e| storage_error(&state, "error finding blob", e, Kind::NotFound))?;
+    state.metrics.record_blob_get(data_res.len());
 
     // Decode the base64 encoded data
     let data = general_purpose::STANDARD_NO_PAD.encode(data_res);
@@ -122,28 +381,731 @@ async fn fetch_blob(
     Ok((StatusCode::CREATED, Json(BlobResponse { contents: data })))
 }
 
+// Endpoint for checking a blob is stored without fetching it
+async fn blob_exists(
+    hash: BlobRef,
+    exState(state): exState<State>,
+    identity: Identity,
+) -> Result<StatusCode, Error> {
+    authorize_blob(&state, &state.acl(), &identity, &hash)?;
+
+    let found = state
+        .blob_store
+        .contains(&hash)
+        .map_err(|e| storage_error(&state, "error finding blob", e, Kind::Internal))?;
+    if !found {
+        return Err(Error::from_msg("blob not found", Kind::NotFound));
+    }
+
+    Ok(StatusCode::OK)
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct CreateNodeRequest {
+    pub namespace: String,
     pub node_type: NodeType,
-    pub blobs: Vec<String>,
+    pub blobs: Vec<BlobRef>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    // Lets the node point at blobs that haven't been uploaded yet
+    #[serde(default)]
+    pub allow_dangling: bool,
+}
+
+/// A change to a node. Anything left out stays as it was.
+#[derive(Serialize, Deserialize)]
+pub struct UpdateNodeRequest {
+    // The revision the change was made against, which has to still be the
+    // latest for it to go through
+    pub revision: u64,
+    #[serde(default)]
+    pub blobs: Option<Vec<BlobRef>>,
+    #[serde(default)]
+    pub attributes: Option<HashMap<String, String>>,
+    // Lets the node point at blobs that haven't been uploaded yet
+    #[serde(default)]
+    pub allow_dangling: bool,
 }
 
+#[derive(Deserialize)]
+pub struct FetchNodeQuery {
+    // Seconds since the epoch, to get the node as it was then
+    at: Option<u64>,
+}
+
+// Checks a node's blobs are stored unless dangling ones are allowed, so a
+// node can't be made that could never be restored.
+//
+// Malformed ids never get this far, since they can't be decoded.
+fn check_blobs(state: &State, blobs: &[BlobRef], allow_dangling: bool) -> Result<(), Error> {
+    if allow_dangling {
+        return Ok(());
+    }
+
+    let missing = state
+        .blob_store
+        .missing(blobs)
+        .map_err(|e| storage_error(state, "error checking blobs", e, Kind::Internal))?;
+    if !missing.is_empty() {
+        return Err(Error::from_msg(
+            &format!(
+                "missing blobs: {}",
+                missing
+                    .iter()
+                    .map(BlobRef::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Kind::BadRequest,
+        ));
+    }
+
+    Ok(())
+}
+
+// Endpoint for creating a node.
+//
+// With an idempotency key, retries of the same request get back the node
+// the first one made instead of making another.
 async fn create_node(
     exState(state): exState<State>,
-    exJson(body): exJson<CreateNodeRequest>,
+    identity: Identity,
+    headers: HeaderMap,
+    JsonBody(body): JsonBody<CreateNodeRequest>,
 ) -> Result<(StatusCode, Json<Node>), Error> {
-    let node = Node {
-        id: uuid(),
-        blobs: body.blobs,
-        node_type: body.node_type,
+    authorize(&state.acl(), &identity, &body.namespace, Permission::Write)?;
+    check_blobs(&state, &body.blobs, body.allow_dangling)?;
+
+    let key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .map(|v| v.to_str())
+        .transpose()
+        .map_err(|e| Error::from_err("invalid idempotency key", e, Kind::BadRequest))?;
+    // Json values keep their keys sorted, so the same body always matches
+    let fingerprint = serde_json::to_value(&body)
+        .map(|v| digest(v.to_string()))
+        .map_err(|e| Error::from_err("error encoding body", e, Kind::Internal))?;
+
+    let author = identity.0.clone();
+    let create = || {
+        let node = Node {
+            id: uuid(),
+            namespace: body.namespace,
+            blobs: body.blobs,
+            node_type: body.node_type,
+            attributes: body.attributes,
+            revision: 1,
+            updated: auth::now(),
+            author,
+        };
+        state.node_store.put(&node.id, &node)?;
+        state.blob_namespaces.add(&node.namespace, &node.blobs);
+        state.snapshots.add(&node);
+        state.changes.record(Change::Node {
+            id: node.id.clone(),
+            revision: node.revision,
+            updated: node.updated,
+        })?;
+        Ok(node)
     };
-    print!("{:?}", node);
 
-    state.node_store.put(&node.id, &node)?;
+    let node = match key {
+        // Keys are per identity, so nobody can replay someone else's create
+        Some(key) => {
+            let key = format!("{}:{}", identity.0.unwrap_or_default(), key);
+            let (node, replayed) = state.idempotency.create_once(&key, &fingerprint, create)?;
+            if replayed {
+                return Ok((StatusCode::CREATED, Json(node)));
+            }
+            node
+        }
+        None => create()?,
+    };
+    state.metrics.record_node_created();
 
     Ok((StatusCode::CREATED, Json(node)))
 }
 
+// Endpoint for fetching a node, checking its namespace can be read.
+//
+// It's the latest revision, unless asked for the one at some earlier time.
+async fn fetch_node(
+    Path(id): Path<String>,
+    Query(query): Query<FetchNodeQuery>,
+    exState(state): exState<State>,
+    identity: Identity,
+) -> Result<Json<Node>, Error> {
+    let node = authorized_node(&state, &state.acl(), &identity, &id, Permission::Read)?;
+
+    let Some(at) = query.at else {
+        return Ok(Json(node));
+    };
+    let node = state
+        .node_store
+        .revisions(&id)?
+        .into_iter()
+        .rev()
+        .find(|n| n.updated <= at)
+        .ok_or_else(|| Error::from_msg("node didn't exist yet", Kind::NotFound))?;
+
+    Ok(Json(node))
+}
+
+// Endpoint for every revision of a node, oldest first
+async fn fetch_revisions(
+    Path(id): Path<String>,
+    exState(state): exState<State>,
+    identity: Identity,
+) -> Result<Json<Vec<Node>>, Error> {
+    authorized_node(&state, &state.acl(), &identity, &id, Permission::Read)?;
+
+    Ok(Json(state.node_store.revisions(&id)?))
+}
+
+// Endpoint for changing a node, making a new revision of it.
+//
+// The change has to be made against the latest revision, so two clients
+// can't overwrite each other without knowing.
+async fn update_node(
+    Path(id): Path<String>,
+    exState(state): exState<State>,
+    identity: Identity,
+    JsonBody(body): JsonBody<UpdateNodeRequest>,
+) -> Result<Json<Node>, Error> {
+    let latest = authorized_node(&state, &state.acl(), &identity, &id, Permission::Write)?;
+    if let Some(blobs) = &body.blobs {
+        check_blobs(&state, blobs, body.allow_dangling)?;
+    }
+
+    let node = Node {
+        blobs: body.blobs.unwrap_or(latest.blobs),
+        attributes: body.attributes.unwrap_or(latest.attributes),
+        revision: body.revision + 1,
+        updated: auth::now(),
+        author: identity.0,
+        ..latest
+    };
+    state.node_store.update(&id, body.revision, &node)?;
+    state.blob_namespaces.add(&node.namespace, &node.blobs);
+    state.changes.record(Change::Node {
+        id,
+        revision: node.revision,
+        updated: node.updated,
+    })?;
+
+    Ok(Json(node))
+}
+
+// Endpoint for deleting a node, returning what was deleted.
+//
+// Only the node goes, any blobs it points to are left for cleaning up later.
+async fn delete_node(
+    Path(id): Path<String>,
+    exState(state): exState<State>,
+    identity: Identity,
+) -> Result<Json<Node>, Error> {
+    let node = authorized_node(&state, &state.acl(), &identity, &id, Permission::Write)?;
+    state.node_store.delete(&id)?;
+    state.metrics.record_node_deleted();
+    state.snapshots.remove(&id);
+    state.changes.record(Change::NodeDeleted { id })?;
+
+    Ok(Json(node))
+}
+
+// Endpoint for listing the snapshots in the request's namespace, oldest first
+async fn list_snapshots(
+    exState(state): exState<State>,
+    identity: Identity,
+    Namespace(namespace): Namespace,
+) -> Result<Json<Vec<Node>>, Error> {
+    authorize(&state.acl(), &identity, &namespace, Permission::Read)?;
+
+    let mut snapshots = vec![];
+    for id in state.snapshots.list(&namespace) {
+        match state.node_store.get(&id) {
+            Ok(node) => snapshots.push(node),
+            // Deleted since it was listed
+            Err(e) if matches!(e.kind, Kind::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(Json(snapshots))
+}
+
+// Endpoint for reading the acl, only for admins
+async fn fetch_acl(exState(state): exState<State>, identity: Identity) -> Result<Json<Acl>, Error> {
+    let acl = state.acl();
+    if !acl.is_admin(&identity) {
+        return Err(Error::from_msg(
+            "only admins can read the acl",
+            Kind::Permission,
+        ));
+    }
+
+    Ok(Json(acl.clone()))
+}
+
+// Endpoint for replacing the acl, only for admins of the current one
+async fn replace_acl(
+    exState(state): exState<State>,
+    identity: Identity,
+    JsonBody(body): JsonBody<Acl>,
+) -> Result<Json<Acl>, Error> {
+    // Held through the write, so replacements land in the order they're
+    // checked
+    let mut acl = state.acl.write().unwrap();
+    if !acl.is_admin(&identity) {
+        return Err(Error::from_msg(
+            "only admins can change the acl",
+            Kind::Permission,
+        ));
+    }
+
+    state.acl_store.put_acl(&body)?;
+    *acl = body.clone();
+
+    Ok(Json(body))
+}
+
+/// Which changes to fetch, and how many at once.
+#[derive(Deserialize)]
+pub struct ChangesQuery {
+    // A cursor from the last page, to start after it
+    since: Option<String>,
+    limit: Option<usize>,
+}
+
+const MAX_CHANGES: usize = 1000;
+
+// Endpoint for following what's changed, only for admins since it covers
+// every namespace
+async fn fetch_changes(
+    Query(query): Query<ChangesQuery>,
+    exState(state): exState<State>,
+    identity: Identity,
+) -> Result<Json<Changes>, Error> {
+    if !state.acl().is_admin(&identity) {
+        return Err(Error::from_msg(
+            "only admins can follow changes",
+            Kind::Permission,
+        ));
+    }
+
+    let limit = query.limit.unwrap_or(MAX_CHANGES).clamp(1, MAX_CHANGES);
+    Ok(Json(state.changes.since(query.since.as_deref(), limit)?))
+}
+
+// Endpoint for a peer to hand over a node's revisions, keeping its id.
+// Only admins can, since it skips the namespace's acl.
+async fn replicate_node(
+    Path(id): Path<String>,
+    exState(state): exState<State>,
+    identity: Identity,
+    JsonBody(revisions): JsonBody<Vec<Node>>,
+) -> Result<Json<Node>, Error> {
+    if !state.acl().is_admin(&identity) {
+        return Err(Error::from_msg(
+            "only admins can replicate nodes",
+            Kind::Permission,
+        ));
+    }
+
+    apply_revisions(&state, &id, revisions)?;
+
+    Ok(Json(state.node_store.get(&id)?))
+}
+
+/// Stores whichever of a node's revisions from elsewhere are newer than the
+/// ones here, returning if there were any.
+///
+/// Two servers that both changed a node since they last synced each keep
+/// their own revisions, since there's no telling which should win.
+pub(crate) fn apply_revisions(
+    state: &State,
+    id: &str,
+    mut revisions: Vec<Node>,
+) -> Result<bool, Error> {
+    crate::validate_node_id(id)?;
+    let local = match state.node_store.revisions(id) {
+        Ok(local) => local,
+        Err(e) if matches!(e.kind, Kind::NotFound) => vec![],
+        Err(e) => return Err(e),
+    };
+    let mut latest = local.last().map_or(0, |n| n.revision);
+    let mut updated = local.last().map_or(0, |n| n.updated);
+
+    revisions.sort_by_key(|n| n.revision);
+    if let Some(theirs) = revisions.iter().find(|n| n.revision == latest) {
+        if local
+            .last()
+            .is_some_and(|ours| ours.updated != theirs.updated)
+        {
+            tracing::warn!("node {} has diverged at revision {}", id, latest);
+            return Ok(false);
+        }
+    }
+
+    let newer: Vec<Node> = revisions
+        .into_iter()
+        .filter(|n| n.revision > latest)
+        .collect();
+    let applied = !newer.is_empty();
+    for node in newer {
+        if node.id != id {
+            return Err(Error::from_msg(
+                &format!("revision is of node {}, not {}", node.id, id),
+                Kind::BadRequest,
+            ));
+        }
+
+        if latest == 0 {
+            state.node_store.put(id, &node)?;
+            state.metrics.record_node_created();
+        } else {
+            state.node_store.update(id, latest, &node)?;
+        }
+        state.blob_namespaces.add(&node.namespace, &node.blobs);
+        state.snapshots.add(&node);
+        latest = node.revision;
+        updated = node.updated;
+    }
+
+    if applied {
+        state.changes.record(Change::Node {
+            id: id.to_owned(),
+            revision: latest,
+            updated,
+        })?;
+    }
+
+    Ok(applied)
+}
+
 fn uuid() -> String {
     format!("sha256-{}", digest(Uuid::new_v4().to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use openssl::pkey::{PKey, Private, Public};
+
+    use super::*;
+    use crate::blobserver::auth::{self, Keyring, SigningKey};
+    use crate::blobserver::client::{Client, RetryPolicy};
+    use crate::storage::Memory;
+    use crate::{NamespaceAcl, DEFAULT_NAMESPACE};
+
+    // Serves the router over the memory store, with the default namespace
+    // open to everyone
+    fn serve(memory: Arc<Memory>) -> Client {
+        let acl = Acl {
+            admins: vec![],
+            namespaces: HashMap::from([(
+                String::from(DEFAULT_NAMESPACE),
+                namespace_acl(&[crate::WILDCARD], &[crate::WILDCARD]),
+            )]),
+        };
+        let (remote, _) = serve_with(memory, &acl, None);
+
+        Client::builder()
+            .remote(&remote)
+            .retry(RetryPolicy::none())
+            .build()
+            .unwrap()
+    }
+
+    fn namespace_acl(read: &[&str], write: &[&str]) -> NamespaceAcl {
+        NamespaceAcl {
+            read: read.iter().map(|s| s.to_string()).collect(),
+            write: write.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    // Serves the router over the memory store with the acl, checking
+    // signatures against the keyring if there is one, and returns where
+    // along with the state it's serving
+    fn serve_with(memory: Arc<Memory>, acl: &Acl, keyring: Option<Keyring>) -> (String, State) {
+        memory.put_acl(acl).unwrap();
+
+        let state = State {
+            blob_store: memory.clone(),
+            node_store: memory.clone(),
+            acl_store: memory,
+            acl: Arc::new(RwLock::new(acl.clone())),
+            metrics: Arc::new(Metrics::default()),
+            idempotency: Arc::new(IdempotencyKeys::new(Duration::from_secs(60), 100)),
+            hasher: hash::DEFAULT,
+            changes: Arc::new(ChangeLog::memory()),
+            blob_namespaces: Arc::new(BlobNamespaces::new()),
+            snapshots: Arc::new(Snapshots::new()),
+        };
+        let mut router = new_router();
+        if let Some(keyring) = keyring {
+            router = router.layer(axum::middleware::from_fn_with_state(
+                Arc::new(keyring),
+                auth::authenticate,
+            ));
+        }
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router.with_state(state.clone()).into_make_service()),
+        );
+
+        (remote, state)
+    }
+
+    fn keypair() -> (PKey<Private>, PKey<Public>) {
+        let private = PKey::generate_ed25519().unwrap();
+        let public = PKey::public_key_from_raw_bytes(
+            &private.raw_public_key().unwrap(),
+            openssl::pkey::Id::ED25519,
+        )
+        .unwrap();
+        (private, public)
+    }
+
+    #[tokio::test]
+    async fn stores_and_fetches_blobs() {
+        let memory = Arc::new(Memory::new().with_max_bytes(16));
+        let client = serve(memory.clone());
+
+        let id = client.put_blob(b"hello").await.unwrap().created;
+        assert_eq!(
+            client.get_blob(&id).await.unwrap().data().unwrap(),
+            b"hello"
+        );
+
+        let err = client.get_blob(&BlobRef::of(b"nope")).await.unwrap_err();
+        assert!(matches!(err.kind, Kind::NotFound));
+
+        let err = client.put_blob(&[7; 32]).await.unwrap_err();
+        assert!(matches!(err.kind, Kind::InsufficientStorage));
+
+        memory.fail_nth_put(1);
+        assert!(client.put_blob(b"world").await.is_err());
+        client.put_blob(b"world").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn updates_nodes_by_revision() {
+        let client = serve(Arc::new(Memory::new()));
+        let id = client.put_blob(b"one").await.unwrap().created;
+
+        // Blobs that were never uploaded are turned away
+        let request = |blobs| CreateNodeRequest {
+            namespace: String::from(DEFAULT_NAMESPACE),
+            node_type: NodeType::File,
+            blobs,
+            attributes: HashMap::new(),
+            allow_dangling: false,
+        };
+        let err = client
+            .create_node(request(vec![BlobRef::of(b"missing")]))
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind, Kind::BadRequest));
+
+        let node = client.create_node(request(vec![id])).await.unwrap();
+        let update = || UpdateNodeRequest {
+            revision: 1,
+            blobs: None,
+            attributes: Some(HashMap::from([(String::from("k"), String::from("v"))])),
+            allow_dangling: false,
+        };
+        let updated = client.update_node(&node.id, update()).await.unwrap();
+        assert_eq!(updated.revision, 2);
+        let err = client.update_node(&node.id, update()).await.unwrap_err();
+        assert!(matches!(err.kind, Kind::Conflict));
+
+        assert_eq!(client.node_revisions(&node.id).await.unwrap().len(), 2);
+        client.delete_node(&node.id).await.unwrap();
+        let err = client.get_node(&node.id).await.unwrap_err();
+        assert!(matches!(err.kind, Kind::NotFound));
+
+        // Ids that could reach outside the store are turned away
+        let err = client.get_node("..%2F..%2Facl").await.unwrap_err();
+        assert!(matches!(err.kind, Kind::BadRequest));
+    }
+
+    #[tokio::test]
+    async fn lists_snapshots_oldest_first() {
+        let client = serve(Arc::new(Memory::new()));
+        let request = |node_type| CreateNodeRequest {
+            namespace: String::from(DEFAULT_NAMESPACE),
+            node_type,
+            blobs: vec![],
+            attributes: HashMap::new(),
+            allow_dangling: false,
+        };
+        let snapshot = |time| {
+            NodeType::Snapshot(crate::Snapshot {
+                root: String::from("root"),
+                time,
+                hostname: String::from("host"),
+                path: String::from("/home"),
+                tags: vec![],
+                parent: None,
+            })
+        };
+
+        let newer = client.create_node(request(snapshot(2))).await.unwrap();
+        let older = client.create_node(request(snapshot(1))).await.unwrap();
+        let deleted = client.create_node(request(snapshot(3))).await.unwrap();
+        client.create_node(request(NodeType::File)).await.unwrap();
+        client.delete_node(&deleted.id).await.unwrap();
+
+        let ids: Vec<String> = client
+            .list_snapshots()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|n| n.id)
+            .collect();
+        assert_eq!(ids, vec![older.id, newer.id]);
+    }
+
+    #[tokio::test]
+    async fn keeps_namespaces_apart() {
+        let memory = Arc::new(Memory::new());
+        let (alice_key, alice_public) = keypair();
+        let (bob_key, bob_public) = keypair();
+        let keyring = Keyring::new(
+            HashMap::from([
+                (String::from("alice"), alice_public),
+                (String::from("bob"), bob_public),
+            ]),
+            Duration::from_secs(300),
+        );
+        let mut acl = Acl {
+            admins: vec![],
+            namespaces: HashMap::from([
+                (String::from("alice"), namespace_acl(&["alice"], &["alice"])),
+                (String::from("bob"), namespace_acl(&["bob"], &["bob"])),
+            ]),
+        };
+        let (remote, state) = serve_with(memory.clone(), &acl, Some(keyring));
+        let client = |namespace: &str, key: Option<SigningKey>| {
+            let mut builder = Client::builder()
+                .remote(&remote)
+                .namespace(namespace)
+                .retry(RetryPolicy::none());
+            if let Some(key) = key {
+                builder = builder.signing_key(key);
+            }
+            builder.build().unwrap()
+        };
+        let alice = client("alice", Some(SigningKey::new("alice", alice_key)));
+        let bob = client("bob", Some(SigningKey::new("bob", bob_key.clone())));
+        let bob_in_alice = client("alice", Some(SigningKey::new("bob", bob_key.clone())));
+        let anonymous = client("alice", None);
+
+        let blob = alice.put_blob(b"alice's").await.unwrap().created;
+        let request = |namespace: &str| CreateNodeRequest {
+            namespace: String::from(namespace),
+            node_type: NodeType::File,
+            blobs: vec![blob.clone()],
+            attributes: HashMap::new(),
+            allow_dangling: false,
+        };
+        let node = alice.create_node(request("alice")).await.unwrap();
+        let update = || UpdateNodeRequest {
+            revision: 1,
+            blobs: None,
+            attributes: Some(HashMap::from([(String::from("k"), String::from("v"))])),
+            allow_dangling: false,
+        };
+
+        // What's in alice's namespace looks like it isn't there to anyone else
+        for other in [&bob, &anonymous] {
+            let err = other.get_blob(&blob).await.unwrap_err();
+            assert!(matches!(err.kind, Kind::NotFound));
+            assert!(!other.has_blob(&blob).await.unwrap());
+            let err = other.get_node(&node.id).await.unwrap_err();
+            assert!(matches!(err.kind, Kind::NotFound));
+            let err = other.node_revisions(&node.id).await.unwrap_err();
+            assert!(matches!(err.kind, Kind::NotFound));
+            let err = other.update_node(&node.id, update()).await.unwrap_err();
+            assert!(matches!(err.kind, Kind::NotFound));
+            let err = other.delete_node(&node.id).await.unwrap_err();
+            assert!(matches!(err.kind, Kind::NotFound));
+        }
+
+        // Nor can anyone else act in it
+        for other in [&bob_in_alice, &anonymous] {
+            let err = other.put_blob(b"bob's").await.unwrap_err();
+            assert!(matches!(err.kind, Kind::Permission));
+            let err = other.list_snapshots().await.unwrap_err();
+            assert!(matches!(err.kind, Kind::Permission));
+        }
+        let err = bob.create_node(request("alice")).await.unwrap_err();
+        assert!(matches!(err.kind, Kind::Permission));
+        let err = bob.changes(None, 10).await.unwrap_err();
+        assert!(matches!(err.kind, Kind::Permission));
+
+        // Signing as alice takes alice's key
+        let forged = client("alice", Some(SigningKey::new("alice", bob_key)));
+        let err = forged.get_node(&node.id).await.unwrap_err();
+        assert!(matches!(err.kind, Kind::Unauthenticated));
+
+        // Letting bob read shows them the node, but they still can't change it
+        acl.namespaces.insert(
+            String::from("alice"),
+            namespace_acl(&["alice", "bob"], &["alice"]),
+        );
+        *state.acl.write().unwrap() = acl;
+        assert_eq!(bob.get_node(&node.id).await.unwrap().id, node.id);
+        assert_eq!(
+            bob.get_blob(&blob).await.unwrap().data().unwrap(),
+            b"alice's"
+        );
+        let err = bob.update_node(&node.id, update()).await.unwrap_err();
+        assert!(matches!(err.kind, Kind::Permission));
+        let err = bob.delete_node(&node.id).await.unwrap_err();
+        assert!(matches!(err.kind, Kind::Permission));
+
+        assert_eq!(
+            alice
+                .update_node(&node.id, update())
+                .await
+                .unwrap()
+                .revision,
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn replaces_the_acl() {
+        let memory = Arc::new(Memory::new());
+        let acl = Acl {
+            admins: vec![String::from(crate::WILDCARD)],
+            namespaces: HashMap::new(),
+        };
+        let (remote, state) = serve_with(memory.clone(), &acl, None);
+        let http = reqwest::Client::new();
+
+        let replaced = Acl {
+            admins: vec![String::from("james")],
+            namespaces: HashMap::new(),
+        };
+        let resp = http
+            .put(format!("{}/acl", remote))
+            .json(&replaced)
+            .send()
+            .await
+            .unwrap();
+        assert!(resp.status().is_success());
+        assert_eq!(memory.get_acl().unwrap().admins, replaced.admins);
+        assert_eq!(state.acl().admins, replaced.admins);
+
+        // Which the next request goes by
+        let resp = http.get(format!("{}/acl", remote)).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::RwLock;
+
+use crate::{Node, NodeType};
+
+/// The snapshots in each namespace, so listing them doesn't mean reading
+/// every node in the store.
+///
+/// A node's type and namespace never change, so only creating and deleting
+/// nodes touches it.
+#[derive(Default)]
+pub struct Snapshots {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // Each snapshot's namespace and time, to find it again when it's deleted
+    ids: HashMap<String, (String, u64)>,
+    // Ordered by time, then id
+    namespaces: HashMap<String, BTreeSet<(u64, String)>>,
+}
+
+impl Snapshots {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the index from the nodes in the store.
+    pub fn load(nodes: &[Node]) -> Self {
+        let snapshots = Self::new();
+        for node in nodes {
+            snapshots.add(node);
+        }
+
+        snapshots
+    }
+
+    /// Notes the node, if it's a snapshot.
+    pub fn add(&self, node: &Node) {
+        let NodeType::Snapshot(snapshot) = &node.node_type else {
+            return;
+        };
+
+        let mut inner = self.inner.write().unwrap();
+        if inner.ids.contains_key(&node.id) {
+            return;
+        }
+        inner
+            .ids
+            .insert(node.id.clone(), (node.namespace.clone(), snapshot.time));
+        inner
+            .namespaces
+            .entry(node.namespace.clone())
+            .or_default()
+            .insert((snapshot.time, node.id.clone()));
+    }
+
+    /// Forgets the node, if it was a snapshot.
+    pub fn remove(&self, id: &str) {
+        let mut inner = self.inner.write().unwrap();
+        let Some((namespace, time)) = inner.ids.remove(id) else {
+            return;
+        };
+        if let Some(snapshots) = inner.namespaces.get_mut(&namespace) {
+            snapshots.remove(&(time, id.to_owned()));
+            if snapshots.is_empty() {
+                inner.namespaces.remove(&namespace);
+            }
+        }
+    }
+
+    /// The ids of the snapshots in the namespace, oldest first.
+    pub fn list(&self, namespace: &str) -> Vec<String> {
+        self.inner
+            .read()
+            .unwrap()
+            .namespaces
+            .get(namespace)
+            .map(|snapshots| snapshots.iter().map(|(_, id)| id.clone()).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Snapshot;
+
+    fn node(id: &str, namespace: &str, node_type: NodeType) -> Node {
+        Node {
+            id: String::from(id),
+            namespace: String::from(namespace),
+            node_type,
+            blobs: vec![],
+            attributes: Default::default(),
+            revision: 1,
+            updated: 0,
+            author: None,
+        }
+    }
+
+    fn snapshot(time: u64) -> NodeType {
+        NodeType::Snapshot(Snapshot {
+            root: String::from("root"),
+            time,
+            hostname: String::from("host"),
+            path: String::from("/home"),
+            tags: vec![],
+            parent: None,
+        })
+    }
+
+    #[test]
+    fn lists_by_namespace_oldest_first() {
+        let snapshots = Snapshots::load(&[
+            node("b", "photos", snapshot(2)),
+            node("a", "photos", snapshot(1)),
+            node("c", "documents", snapshot(0)),
+            node("d", "photos", NodeType::File),
+        ]);
+        assert_eq!(snapshots.list("photos"), vec!["a", "b"]);
+        assert_eq!(snapshots.list("documents"), vec!["c"]);
+
+        snapshots.remove("a");
+        snapshots.remove("d");
+        assert_eq!(snapshots.list("photos"), vec!["b"]);
+        assert!(snapshots.list("music").is_empty());
+    }
+}
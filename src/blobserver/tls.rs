@@ -0,0 +1,284 @@
+use std::pin::Pin;
+use std::sync::Arc;
+
+use axum::{Extension, Router};
+use openssl::nid::Nid;
+use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslRef, SslVerifyMode};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tokio_openssl::SslStream;
+use tracing::debug;
+
+use crate::error::{Error, Kind, WithKind};
+use crate::Identity;
+
+/// Where the server's certificate and key live, plus optional
+/// client certificate authentication.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    pub cert: String, // Path to the certificate chain pem
+    pub key: String,  // Path to the private key pem
+    // If set, clients can present certificates signed by this CA, and
+    // the certificate's common name becomes their identity
+    #[serde(default)]
+    pub client_ca: Option<String>,
+    // Turns away any client without a certificate
+    #[serde(default)]
+    pub require_client_cert: bool,
+}
+
+/// Builds the acceptor for incoming connections from the config.
+pub fn acceptor(config: &TlsConfig) -> Result<SslAcceptor, Error> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())
+        .with_kind("error creating acceptor", Kind::Internal)?;
+    builder
+        .set_private_key_file(&config.key, SslFiletype::PEM)
+        .with_kind("error loading key", Kind::Internal)?;
+    builder
+        .set_certificate_chain_file(&config.cert)
+        .with_kind("error loading certificate", Kind::Internal)?;
+    builder
+        .check_private_key()
+        .with_kind("key doesn't match certificate", Kind::Internal)?;
+
+    if let Some(ca) = &config.client_ca {
+        builder
+            .set_ca_file(ca)
+            .with_kind("error loading client ca", Kind::Internal)?;
+
+        let mut mode = SslVerifyMode::PEER;
+        if config.require_client_cert {
+            mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+        }
+        builder.set_verify(mode);
+    }
+
+    Ok(builder.build())
+}
+
+// The identity from a client's certificate, if it presented one.
+//
+// Verification happens during the handshake, so any certificate still
+// around afterwards was signed by the client CA.
+fn peer_identity(ssl: &SslRef) -> Option<Identity> {
+    let cert = ssl.peer_certificate()?;
+    let name = cert
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()?
+        .data()
+        .to_string()
+        .ok()?;
+
+    Some(Identity(Some(name)))
+}
+
+/// Serves the router over TLS on the listener, forever.
+///
+/// Connections that fail their handshake are dropped without taking
+/// down the server.
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: SslAcceptor,
+    router: Router,
+) -> Result<(), Error> {
+    let acceptor = Arc::new(acceptor);
+
+    loop {
+        let (tcp, addr) = listener
+            .accept()
+            .await
+            .with_kind("error accepting connection", Kind::Internal)?;
+        let acceptor = acceptor.clone();
+        let router = router.clone();
+
+        tokio::spawn(async move {
+            let stream = Ssl::new(acceptor.context()).and_then(|ssl| SslStream::new(ssl, tcp));
+            let mut stream = match stream {
+                Ok(s) => s,
+                Err(e) => return debug!("error setting up tls for {}: {}", addr, e),
+            };
+            if let Err(e) = Pin::new(&mut stream).accept().await {
+                return debug!("tls handshake with {} failed: {}", addr, e);
+            }
+
+            // Handlers pick the identity up the same way as from a signed request
+            let router = match peer_identity(stream.ssl()) {
+                Some(identity) => router.layer(Extension(identity)),
+                None => router,
+            };
+            if let Err(e) = hyper::server::conn::Http::new()
+                .serve_connection(stream, router)
+                .await
+            {
+                debug!("error serving {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use axum::routing::get;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509NameBuilder, X509};
+    use reqwest::Method;
+
+    use crate::blobserver::client::{Client, ClientTls};
+
+    use super::*;
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    // Makes a certificate for the common name, signed by the issuer or
+    // itself if there isn't one
+    fn cert(cn: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>) -> X509 {
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, cn).unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(rand_serial())
+            .unwrap()
+            .to_asn1_integer()
+            .unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+
+        match issuer {
+            Some((issuer_cert, issuer_key)) => {
+                let san = SubjectAlternativeName::new()
+                    .dns("localhost")
+                    .build(&builder.x509v3_context(Some(issuer_cert), None))
+                    .unwrap();
+                builder.append_extension(san).unwrap();
+                builder.set_issuer_name(issuer_cert.subject_name()).unwrap();
+                builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+            }
+            None => {
+                builder
+                    .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+                    .unwrap();
+                builder.set_issuer_name(&name).unwrap();
+                builder.sign(key, MessageDigest::sha256()).unwrap();
+            }
+        }
+
+        builder.build()
+    }
+
+    fn rand_serial() -> u32 {
+        uuid::Uuid::new_v4().as_u128() as u32
+    }
+
+    fn write(dir: &Path, name: &str, pem: Vec<u8>) -> String {
+        let path = dir.join(name);
+        std::fs::write(&path, pem).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Whoami {
+        name: Option<String>,
+    }
+
+    // Starts a server that replies with the caller's identity
+    async fn start(config: &TlsConfig) -> u16 {
+        let router = Router::new().route(
+            "/whoami",
+            get(|identity: Identity| async move {
+                axum::Json(serde_json::json!({ "name": identity.0 }))
+            }),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acceptor = acceptor(config).unwrap();
+        tokio::spawn(serve(listener, acceptor, router));
+
+        port
+    }
+
+    #[tokio::test]
+    async fn serves_with_self_signed_certs() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let ca_key = key();
+        let ca = cert("anchorage ca", &ca_key, None);
+        let server_key = key();
+        let server_cert = cert("localhost", &server_key, Some((&ca, &ca_key)));
+        let client_key = key();
+        let client_cert = cert("james", &client_key, Some((&ca, &ca_key)));
+
+        let config = TlsConfig {
+            cert: write(dir.path(), "cert.pem", server_cert.to_pem().unwrap()),
+            key: write(
+                dir.path(),
+                "key.pem",
+                server_key.private_key_to_pem_pkcs8().unwrap(),
+            ),
+            client_ca: Some(write(dir.path(), "ca.pem", ca.to_pem().unwrap())),
+            require_client_cert: false,
+        };
+        let port = start(&config).await;
+        let remote = format!("https://localhost:{}", port);
+
+        // Without the CA, the server can't be trusted
+        let untrusting = Client::builder().remote(&remote).build().unwrap();
+        assert!(untrusting
+            .send::<Whoami, ()>(Method::GET, "/whoami", None, false)
+            .await
+            .is_err());
+
+        // With it, but no client certificate, we're anonymous
+        let anonymous = Client::builder()
+            .remote(&remote)
+            .tls(ClientTls {
+                ca_bundle: Some(ca.to_pem().unwrap()),
+                cert: None,
+            })
+            .build()
+            .unwrap();
+        let whoami: Whoami = anonymous
+            .send(Method::GET, "/whoami", None::<&()>, false)
+            .await
+            .unwrap();
+        assert_eq!(whoami.name, None);
+
+        // And with a client certificate, the server knows who we are
+        let james = Client::builder()
+            .remote(&remote)
+            .tls(ClientTls {
+                ca_bundle: Some(ca.to_pem().unwrap()),
+                cert: Some((
+                    client_cert.to_pem().unwrap(),
+                    client_key.private_key_to_pem_pkcs8().unwrap(),
+                )),
+            })
+            .build()
+            .unwrap();
+        let whoami: Whoami = james
+            .send(Method::GET, "/whoami", None::<&()>, false)
+            .await
+            .unwrap();
+        assert_eq!(whoami.name.as_deref(), Some("james"));
+    }
+}
@@ -3,14 +3,16 @@ use anyhow::Result;
 use std::fs::File;
 use std::io::{Read, Write};
 
-use sha256::digest;
+use crate::hash::Hasher;
+use crate::BlobRef;
 
-const MAX_FILE_SIZE: usize = 10 * 1024 * 1024; // 10MB;
+pub const MAX_FILE_SIZE: usize = 10 * 1024 * 1024; // 10MB;
 const WINDOW_SIZE: usize = 1024 * 4; // 4KB
 const MIN_FILE_SIZE: usize = 2 * 1024 * 1024; // 2MB
 
-// Takes a reader and chunks it into files
-pub fn create_chunks<R: Read>(r: &mut R) -> Result<Vec<(String, File)>> {
+// Takes a reader and chunks it into files, each named by the blob id
+// the hasher gives it
+pub fn create_chunks<R: Read>(r: &mut R, hasher: &dyn Hasher) -> Result<Vec<(String, File)>> {
     // But the buffer we'll read into is larger that to reduce disk reads
     let mut buffer = vec![0_u8; MAX_FILE_SIZE];
     // The buffer that gets saved to a file, and the number of writes we've
@@ -49,6 +51,7 @@ pub fn create_chunks<R: Read>(r: &mut R) -> Result<Vec<(String, File)>> {
             // If we're here, it's time to flush the file to a temp file
             ret.push(flush(
                 &flush_buffer[(flush_buffer.len() - bytes_to_flush)..flush_buffer.len()],
+                hasher,
             )?);
 
             // Reset the flush buffer
@@ -61,6 +64,7 @@ pub fn create_chunks<R: Read>(r: &mut R) -> Result<Vec<(String, File)>> {
     if bytes_to_flush > 0 {
         ret.push(flush(
             &flush_buffer[(flush_buffer.len() - bytes_to_flush)..flush_buffer.len()],
+            hasher,
         )?);
     }
 
@@ -69,22 +73,21 @@ pub fn create_chunks<R: Read>(r: &mut R) -> Result<Vec<(String, File)>> {
 
 // Creates a tempfile with the given data
 //
-// It will produce a hash-named file, and try to see if that file
+// It will produce a file named by its blob id, and try to see if that file
 // already exists. If it does, we'll just reuse it
-fn flush(bytes: &[u8]) -> Result<(String, File)> {
-    let hash = digest(bytes);
-    let path = std::env::temp_dir().join(hash);
+fn flush(bytes: &[u8], hasher: &dyn Hasher) -> Result<(String, File)> {
+    let id = BlobRef::hash(hasher, bytes);
+    let path = std::env::temp_dir().join(id.as_str());
     let path_str = path.clone().into_os_string().into_string().unwrap();
 
     // Try to open, otherwise create a new file
-    let mut f = if let Ok(file) = File::open(&path) {
+    if let Ok(file) = File::open(&path) {
         return Ok((path_str, file));
-    } else {
-        File::create(&path)?
-    };
-    f.write_all(bytes)?;
+    }
+    File::create(&path)?.write_all(bytes)?;
 
-    Ok((path_str, f))
+    // Reopened so the handle can be read from the start, like the one above
+    Ok((path_str, File::open(&path)?))
 }
 
 use std::collections::LinkedList;
@@ -122,6 +125,7 @@ mod create_chunks_tests {
     use std::io::BufReader;
 
     use super::*;
+    use crate::hash::{Blake3, Sha256};
 
     // Tests that we get consistent ranges on a sample file of Chloe
     #[test]
@@ -129,12 +133,12 @@ mod create_chunks_tests {
         let f = File::open("./test_samples/cat.jpg").unwrap();
         let mut r = BufReader::new(f);
 
-        let chunks = create_chunks(&mut r).unwrap();
+        let chunks = create_chunks(&mut r, &Sha256).unwrap();
         let names: Vec<&str> = chunks.iter().map(|(name, _)| name.as_str()).collect();
         assert_eq!(
             names,
             vec![std::env::temp_dir()
-                .join("1ea808b45afad786bfa113cb0cbf5ac992299be255f14b99251732eb370c6465")
+                .join("sha256-1ea808b45afad786bfa113cb0cbf5ac992299be255f14b99251732eb370c6465")
                 .to_str()
                 .unwrap()]
         );
@@ -147,7 +151,7 @@ mod create_chunks_tests {
                    celebrating his eleventyifirst birthday with a party of special \
                    magnificence, there was much talk and excitement in Hobbiton.";
 
-        let mut chunks = create_chunks(&mut BufReader::new(bytes)).unwrap();
+        let mut chunks = create_chunks(&mut BufReader::new(bytes), &Blake3).unwrap();
         assert_eq!(chunks.len(), 1);
 
         // We didn't leave off any bytes
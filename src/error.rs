@@ -5,10 +5,13 @@ use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Kind {
+    Unauthenticated,
     Permission,
     BadRequest,
     Internal,
     NotFound,
+    InsufficientStorage,
+    Conflict, // Something changed since the client last looked
 }
 
 impl std::fmt::Display for Kind {
@@ -122,16 +125,35 @@ impl From<reqwest::Error> for Error {
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
         let status_code = match self.kind {
+            Kind::Unauthenticated => StatusCode::UNAUTHORIZED,
             Kind::Permission => StatusCode::FORBIDDEN,
             Kind::BadRequest => StatusCode::BAD_REQUEST,
             Kind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
             Kind::NotFound => StatusCode::NOT_FOUND,
+            Kind::InsufficientStorage => StatusCode::INSUFFICIENT_STORAGE,
+            Kind::Conflict => StatusCode::CONFLICT,
         };
 
         (status_code, Json(self)).into_response()
     }
 }
 
+// Goes the other way from responses, for when a client gets a status
+// without an error body
+impl From<StatusCode> for Kind {
+    fn from(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => Kind::Unauthenticated,
+            StatusCode::FORBIDDEN => Kind::Permission,
+            StatusCode::NOT_FOUND => Kind::NotFound,
+            StatusCode::INSUFFICIENT_STORAGE => Kind::InsufficientStorage,
+            StatusCode::CONFLICT => Kind::Conflict,
+            s if s.is_client_error() => Kind::BadRequest,
+            _ => Kind::Internal,
+        }
+    }
+}
+
 pub trait WithKind<T> {
     fn with_kind(self, msg: &str, kind: Kind) -> Result<T, Error>;
 }
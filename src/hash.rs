@@ -0,0 +1,77 @@
+// The hash algorithms blob ids can come from.
+//
+// Every id is prefixed with the name of the algorithm that made it, so
+// blobs hashed different ways can live side by side in a store.
+
+/// Something that can turn a blob's contents into its id.
+pub trait Hasher: Send + Sync {
+    // The prefix of the ids it makes, e.g. `sha256`
+    fn name(&self) -> &'static str;
+    // How many hex characters its hashes are
+    fn hex_len(&self) -> usize;
+    // The lowercase hex hash of the data
+    fn digest(&self, data: &[u8]) -> String;
+}
+
+pub struct Sha256;
+
+impl Hasher for Sha256 {
+    fn name(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn hex_len(&self) -> usize {
+        64
+    }
+
+    fn digest(&self, data: &[u8]) -> String {
+        sha256::digest(data)
+    }
+}
+
+/// Much faster than sha256 on big chunks.
+pub struct Blake3;
+
+impl Hasher for Blake3 {
+    fn name(&self) -> &'static str {
+        "blake3"
+    }
+
+    fn hex_len(&self) -> usize {
+        64
+    }
+
+    fn digest(&self, data: &[u8]) -> String {
+        blake3::hash(data).to_hex().to_string()
+    }
+}
+
+/// Every algorithm ids are accepted from.
+pub const ALGORITHMS: &[&dyn Hasher] = &[&Sha256, &Blake3];
+
+/// What ids are made with when nothing says otherwise.
+pub const DEFAULT: &dyn Hasher = &Sha256;
+
+/// Looks up an algorithm by the name in its ids.
+pub fn algorithm(name: &str) -> Option<&'static dyn Hasher> {
+    ALGORITHMS.iter().copied().find(|h| h.name() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests_known_values() {
+        assert_eq!(
+            Sha256.digest(b"hello\n"),
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03"
+        );
+        assert_eq!(
+            Blake3.digest(b""),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!(algorithm("blake3").unwrap().name(), "blake3");
+        assert!(algorithm("md5").is_none());
+    }
+}
@@ -1,8 +1,11 @@
 pub mod blobserver;
 pub mod chunk;
 pub mod error;
+pub mod hash;
 pub mod storage;
 
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 use error::Error;
@@ -13,6 +16,8 @@ use error::Error;
 pub enum StorageError {
     NotFound, // The blob being stored could not be located
     IO(String),
+    // There's less free space than the storage is allowed to dip into
+    InsufficientSpace { free: u64, min_free: u64 },
 }
 
 impl std::error::Error for StorageError {}
@@ -22,27 +27,466 @@ impl std::fmt::Display for StorageError {
     }
 }
 
+/// The id of a blob: the algorithm its contents were hashed with, then the
+/// hash in lowercase hex, like `sha256-<hex>`.
+///
+/// Only ever holds a well formed id, so it's safe to use as a file name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct BlobRef(String);
+
+impl BlobRef {
+    pub fn parse(id: &str) -> Result<Self, Error> {
+        let invalid = |why: &str| {
+            Error::from_msg(
+                &format!("invalid blob id '{}': {}", id, why),
+                error::Kind::BadRequest,
+            )
+        };
+
+        let (algo, hex) = id
+            .split_once('-')
+            .ok_or_else(|| invalid("expected <algorithm>-<hex>"))?;
+        let hasher = hash::algorithm(algo).ok_or_else(|| invalid("unknown algorithm"))?;
+        if hex.len() != hasher.hex_len() {
+            return Err(invalid("wrong length for the algorithm"));
+        }
+        if !hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+            return Err(invalid("not lowercase hex"));
+        }
+
+        Ok(Self(id.to_owned()))
+    }
+
+    /// The id the data gets with the default algorithm.
+    pub fn of(data: &[u8]) -> Self {
+        Self::hash(hash::DEFAULT, data)
+    }
+
+    /// The id the data gets with the given algorithm.
+    pub fn hash(hasher: &dyn hash::Hasher, data: &[u8]) -> Self {
+        Self(format!("{}-{}", hasher.name(), hasher.digest(data)))
+    }
+
+    /// Whether the data is what this id was made from.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match hash::algorithm(self.algorithm()) {
+            Some(hasher) => hasher.digest(data) == self.hex(),
+            None => false,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn algorithm(&self) -> &str {
+        self.0
+            .split_once('-')
+            .map(|(algo, _)| algo)
+            .unwrap_or_default()
+    }
+
+    pub fn hex(&self) -> &str {
+        self.0
+            .split_once('-')
+            .map(|(_, hex)| hex)
+            .unwrap_or_default()
+    }
+}
+
+impl std::str::FromStr for BlobRef {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl TryFrom<String> for BlobRef {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse(&value)
+    }
+}
+
+impl From<BlobRef> for String {
+    fn from(value: BlobRef) -> Self {
+        value.0
+    }
+}
+
+impl std::fmt::Display for BlobRef {
+    fn fmt(&self, w: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(w, "{}", self.0)
+    }
+}
+
 // Storages manage blobs bytes.
 pub trait Storage {
-    fn get(&self, id: &str) -> Result<Vec<u8>, StorageError>;
-    fn put(&self, id: &str, data: Vec<u8>) -> Result<(), StorageError>;
+    fn get(&self, id: &BlobRef) -> Result<Vec<u8>, StorageError>;
+    fn put(&self, id: &BlobRef, data: Vec<u8>) -> Result<(), StorageError>;
+    // Stores a blob over a copy that's gone bad. Storages that skip puts of
+    // blobs they already have need to overwrite them here instead.
+    fn repair(&self, id: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        self.put(id, data)
+    }
+    fn contains(&self, id: &BlobRef) -> Result<bool, StorageError>;
+    // Which of the ids aren't stored, for checking a batch at once. Storages
+    // that can do better than asking one at a time should.
+    fn missing(&self, ids: &[BlobRef]) -> Result<Vec<BlobRef>, StorageError> {
+        let mut missing = vec![];
+        for id in ids {
+            if !self.contains(id)? {
+                missing.push(id.clone());
+            }
+        }
+
+        Ok(missing)
+    }
+    // Every blob stored, in no particular order
+    fn list_blobs(&self) -> Result<Vec<BlobRef>, StorageError>;
+    fn stats(&self) -> Result<StorageStats, StorageError>;
+    // Checks that the storage is reachable, without reading or writing any blobs
+    fn check(&self) -> Result<StorageHealth, StorageError>;
+}
+
+// So a storage that's shared can be wrapped by another
+impl<T: Storage + ?Sized> Storage for std::sync::Arc<T> {
+    fn get(&self, id: &BlobRef) -> Result<Vec<u8>, StorageError> {
+        (**self).get(id)
+    }
+    fn put(&self, id: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        (**self).put(id, data)
+    }
+    fn repair(&self, id: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        (**self).repair(id, data)
+    }
+    fn contains(&self, id: &BlobRef) -> Result<bool, StorageError> {
+        (**self).contains(id)
+    }
+    fn missing(&self, ids: &[BlobRef]) -> Result<Vec<BlobRef>, StorageError> {
+        (**self).missing(ids)
+    }
+    fn list_blobs(&self) -> Result<Vec<BlobRef>, StorageError> {
+        (**self).list_blobs()
+    }
+    fn stats(&self) -> Result<StorageStats, StorageError> {
+        (**self).stats()
+    }
+    fn check(&self) -> Result<StorageHealth, StorageError> {
+        (**self).check()
+    }
+}
+
+/// What a storage reports when it's reachable.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct StorageHealth {
+    // None if the storage has no way of knowing
+    pub free_bytes: Option<u64>,
+}
+
+/// How much a storage is holding onto.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct StorageStats {
+    pub blobs: u64,
+    // What the blobs add up to as they were uploaded
+    pub bytes: u64,
+    // What they actually take up once stored, after compression
+    #[serde(default)]
+    pub physical_bytes: u64,
+    // Only for storages that cache what they read
+    #[serde(default)]
+    pub cache: Option<CacheStats>,
+}
+
+/// How well a cache in front of a storage is doing.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CacheStats {
+    // Reads served from the cache, and ones that had to go past it
+    pub hits: u64,
+    pub misses: u64,
+    // What's held in memory and on disk
+    pub bytes: u64,
+    pub disk_bytes: u64,
 }
 
 /// Internal representation of a node.
-#[derive(Debug, Serialize, Deserialize)]
+///
+/// The id stays the same as a node changes, each change making a new
+/// revision of it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Node {
     pub id: String,
+    // Nodes written before namespaces existed all live in the default one
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
     pub node_type: NodeType,
-    pub blobs: Vec<String>,
+    pub blobs: Vec<BlobRef>,
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    // Counts up from 1, and nodes from before revisions are on their first
+    #[serde(default = "first_revision")]
+    pub revision: u64,
+    // Seconds since the epoch this revision was made, 0 if it's not known
+    #[serde(default)]
+    pub updated: u64,
+    // Who made this revision, None if they were anonymous
+    #[serde(default)]
+    pub author: Option<String>,
+}
+
+fn first_revision() -> u64 {
+    1
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Checks a node id is safe to use as part of a path or key.
+///
+/// Ids made here are `sha256-` and some hex, but ones from peers and older
+/// stores can be anything, as long as they can't climb out of where nodes
+/// are kept.
+pub fn validate_node_id(id: &str) -> Result<(), Error> {
+    let invalid = |why: &str| {
+        Err(Error::from_msg(
+            &format!("invalid node id '{}': {}", id, why),
+            error::Kind::BadRequest,
+        ))
+    };
+
+    if id.is_empty() {
+        return invalid("it's empty");
+    }
+    if id.starts_with('.') || id.contains("..") {
+        return invalid("it can't start with a dot or have two in a row");
+    }
+    if id.contains(['/', '\\', '\0']) {
+        return invalid("it can't have slashes or nul bytes");
+    }
+
+    Ok(())
+}
+
+/// The namespace used when a client doesn't ask for one.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+fn default_namespace() -> String {
+    String::from(DEFAULT_NAMESPACE)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum NodeType {
     File,
+    // Its only blob is a json encoded `Directory`
+    Directory,
+    // Has no blobs, it points at the directory node that was backed up
+    Snapshot(Snapshot),
+}
+
+/// A directory as it was at one point in time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub root: String, // The directory node
+    pub time: u64,    // Seconds since the epoch
+    pub hostname: String,
+    pub path: String, // Where the directory was on the host
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // The snapshot of the same directory before this one
+    pub parent: Option<String>,
+}
+
+/// The listing a directory node points to.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Directory {
+    // Sorted by name, so the same listing always makes the same blob
+    pub entries: Vec<DirEntry>,
+}
+
+/// A file or directory within a directory.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DirEntry {
+    pub name: String,
+    pub node_type: NodeType,
+    pub node: String,
+    pub size: u64, // Of the file, or everything under the directory
 }
 
 // NodeStore wraps the surface of how nodes are retrieved.
 pub trait NodeStore {
     fn get(&self, id: &str) -> Result<Node, Error>;
+    // Stores the first revision of a node
     fn put(&self, id: &str, node: &Node) -> Result<(), Error>;
+    // Stores a new revision of a node, as long as the latest is still the
+    // expected one. Otherwise it's a conflict.
+    fn update(&self, id: &str, expected_revision: u64, node: &Node) -> Result<(), Error>;
+    // Every revision of a node, oldest first
+    fn revisions(&self, id: &str) -> Result<Vec<Node>, Error>;
+    fn delete(&self, id: &str) -> Result<(), Error>;
+    // Every node in the store, in no particular order. Ones that can't be
+    // read are logged and left out rather than failing the rest.
+    fn list(&self) -> Result<Vec<Node>, Error>;
+    // Checks that nodes can be reached
+    fn check(&self) -> Result<(), Error>;
+}
+
+/// Matches any identity in an access list, including anonymous requests.
+/// As a namespace name, it matches every namespace.
+pub const WILDCARD: &str = "*";
+
+/// Who a request is being made by. Requests that haven't been
+/// authenticated are anonymous and have no name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Identity(pub Option<String>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Read,
+    Write,
+}
+
+/// The access controls for every namespace on a server.
+///
+/// This is the special blob from the concepts doc: it isn't content addressed
+/// and there's only ever one of them, replaced wholesale on every write.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Acl {
+    // Admins can do anything, including changing the acl itself
+    #[serde(default)]
+    pub admins: Vec<String>,
+    #[serde(default)]
+    pub namespaces: HashMap<String, NamespaceAcl>,
+}
+
+/// Who can read and write a single namespace.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NamespaceAcl {
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub write: Vec<String>,
+}
+
+impl Acl {
+    pub fn is_admin(&self, identity: &Identity) -> bool {
+        listed(&self.admins, identity)
+    }
+
+    /// Checks if the identity has the permission on the namespace, either
+    /// directly or through the wildcard namespace.
+    pub fn allows(&self, identity: &Identity, namespace: &str, permission: Permission) -> bool {
+        if self.is_admin(identity) {
+            return true;
+        }
+
+        [namespace, WILDCARD]
+            .iter()
+            .filter_map(|ns| self.namespaces.get(*ns))
+            .any(|ns_acl| match permission {
+                Permission::Read => listed(&ns_acl.read, identity),
+                Permission::Write => listed(&ns_acl.write, identity),
+            })
+    }
+}
+
+// Whether the identity appears in the list of principals
+fn listed(principals: &[String], identity: &Identity) -> bool {
+    principals
+        .iter()
+        .any(|p| p == WILDCARD || Some(p.as_str()) == identity.0.as_deref())
+}
+
+// AclStore holds onto the single acl document for a server.
+pub trait AclStore {
+    // Returns an empty acl, which denies everything, if none was ever stored.
+    fn get_acl(&self) -> Result<Acl, Error>;
+    fn put_acl(&self, acl: &Acl) -> Result<(), Error>;
+}
+
+#[cfg(test)]
+mod acl_tests {
+    use super::*;
+
+    fn user(name: &str) -> Identity {
+        Identity(Some(name.to_owned()))
+    }
+
+    #[test]
+    fn namespace_permissions() {
+        let mut acl = Acl::default();
+        acl.namespaces.insert(
+            String::from("photos"),
+            NamespaceAcl {
+                read: vec![String::from("chloe"), String::from("james")],
+                write: vec![String::from("james")],
+            },
+        );
+
+        assert!(acl.allows(&user("james"), "photos", Permission::Write));
+        assert!(acl.allows(&user("chloe"), "photos", Permission::Read));
+        assert!(!acl.allows(&user("chloe"), "photos", Permission::Write));
+        assert!(!acl.allows(&user("james"), "documents", Permission::Read));
+        assert!(!acl.allows(&Identity::default(), "photos", Permission::Read));
+    }
+
+    #[test]
+    fn wildcards_and_admins() {
+        let mut acl = Acl {
+            admins: vec![String::from("root")],
+            ..Default::default()
+        };
+        acl.namespaces.insert(
+            String::from(WILDCARD),
+            NamespaceAcl {
+                read: vec![String::from(WILDCARD)],
+                write: vec![],
+            },
+        );
+
+        assert!(acl.allows(&Identity::default(), "anything", Permission::Read));
+        assert!(!acl.allows(&user("james"), "anything", Permission::Write));
+        assert!(acl.allows(&user("root"), "anything", Permission::Write));
+        assert!(acl.is_admin(&user("root")));
+        assert!(!acl.is_admin(&Identity::default()));
+    }
+}
+
+#[cfg(test)]
+mod blob_ref_tests {
+    use super::*;
+
+    const HEX: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+    #[test]
+    fn parses_valid_ids() {
+        let id = BlobRef::parse(&format!("sha256-{}", HEX)).unwrap();
+        assert_eq!(id.algorithm(), "sha256");
+        assert_eq!(id.hex(), HEX);
+        assert_eq!(id, BlobRef::of(b"hello\n"));
+        assert!(id.matches(b"hello\n"));
+        assert!(!id.matches(b"goodbye\n"));
+
+        let id = BlobRef::hash(&hash::Blake3, b"hello\n");
+        assert_eq!(id.algorithm(), "blake3");
+        assert_eq!(BlobRef::parse(id.as_str()).unwrap(), id);
+        assert!(id.matches(b"hello\n"));
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        for id in [
+            String::from(HEX),
+            format!("md5-{}", HEX),
+            String::from("sha256-abc"),
+            format!("sha256-{}", HEX.to_uppercase()),
+            format!("sha256-../../{}", &HEX[6..]),
+            format!("sha256-{}/x", &HEX[2..]),
+        ] {
+            let err = BlobRef::parse(&id).unwrap_err();
+            assert!(matches!(err.kind, error::Kind::BadRequest), "{}", id);
+        }
+
+        let decoded: Result<BlobRef, _> = serde_json::from_str("\"sha256-abc\"");
+        assert!(decoded.is_err());
+    }
 }
@@ -1,3 +1,18 @@
 /// Different implementations of blob storage.
+mod cached;
+pub mod codec;
+mod erasure;
 mod local;
+mod memory;
+mod mirror;
+mod pack;
+mod s3;
+mod sqlite;
+pub use cached::*;
+pub use erasure::*;
 pub use local::*;
+pub use memory::*;
+pub use mirror::*;
+pub use pack::*;
+pub use s3::*;
+pub use sqlite::*;
@@ -0,0 +1,378 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::{BlobRef, CacheStats, Storage, StorageError, StorageHealth, StorageStats};
+
+/// Keeps blobs read from another storage around for the next read, in
+/// memory and optionally on local disk behind that.
+///
+/// Blobs never change once stored, so nothing cached ever needs to be
+/// thrown out for being stale, only for room.
+pub struct Cached<S> {
+    inner: S,
+    memory: Mutex<Lru<Vec<u8>>>,
+    disk: Option<Disk>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<S: Storage> Cached<S> {
+    /// Caches up to max_bytes of blobs in memory.
+    pub fn new(inner: S, max_bytes: u64) -> Self {
+        Self {
+            inner,
+            memory: Mutex::new(Lru::new(max_bytes)),
+            disk: None,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds a second tier in a directory, holding up to max_bytes of blobs
+    /// that didn't fit in memory.
+    ///
+    /// Whatever the directory held from before is kept, oldest going first.
+    pub fn with_disk(mut self, directory: PathBuf, max_bytes: u64) -> Result<Self, StorageError> {
+        self.disk = Some(Disk::open(directory, max_bytes)?);
+        Ok(self)
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bytes: self.memory.lock().unwrap().bytes,
+            disk_bytes: self
+                .disk
+                .as_ref()
+                .map_or(0, |disk| disk.lru.lock().unwrap().bytes),
+        }
+    }
+}
+
+impl<S: Storage> Storage for Cached<S> {
+    fn get(&self, id: &BlobRef) -> Result<Vec<u8>, StorageError> {
+        if let Some(data) = self.memory.lock().unwrap().get(id) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(data.clone());
+        }
+        if let Some(data) = self.disk.as_ref().and_then(|disk| disk.get(id)) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            self.memory
+                .lock()
+                .unwrap()
+                .insert(id, data.len() as u64, data.clone());
+            return Ok(data);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let data = self.inner.get(id)?;
+        if let Some(disk) = &self.disk {
+            disk.put(id, &data);
+        }
+        self.memory
+            .lock()
+            .unwrap()
+            .insert(id, data.len() as u64, data.clone());
+
+        Ok(data)
+    }
+
+    // Only reads fill the cache, so a big upload doesn't push out what's
+    // actually being read
+    fn put(&self, id: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        self.inner.put(id, data)
+    }
+
+    fn repair(&self, id: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        self.inner.repair(id, data)
+    }
+
+    fn contains(&self, id: &BlobRef) -> Result<bool, StorageError> {
+        if self.memory.lock().unwrap().contains(id) {
+            return Ok(true);
+        }
+        self.inner.contains(id)
+    }
+
+    fn missing(&self, ids: &[BlobRef]) -> Result<Vec<BlobRef>, StorageError> {
+        self.inner.missing(ids)
+    }
+
+    fn list_blobs(&self) -> Result<Vec<BlobRef>, StorageError> {
+        self.inner.list_blobs()
+    }
+
+    fn stats(&self) -> Result<StorageStats, StorageError> {
+        Ok(StorageStats {
+            cache: Some(self.cache_stats()),
+            ..self.inner.stats()?
+        })
+    }
+
+    fn check(&self) -> Result<StorageHealth, StorageError> {
+        self.inner.check()
+    }
+}
+
+// The disk tier, a flat directory of blob files named by their ids
+struct Disk {
+    directory: PathBuf,
+    lru: Mutex<Lru<()>>,
+}
+
+// Blobs are written here first, then renamed into place
+const TMP_SUFFIX: &str = ".tmp";
+
+// Whether a file is one the cache was partway through writing
+fn is_leftover(name: &str) -> bool {
+    name.strip_suffix(TMP_SUFFIX)
+        .is_some_and(|id| BlobRef::parse(id).is_ok())
+}
+
+impl Disk {
+    fn open(directory: PathBuf, max_bytes: u64) -> Result<Self, StorageError> {
+        fs::create_dir_all(&directory)?;
+
+        // Oldest first, so they're the first to go
+        let mut files = vec![];
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let meta = entry.metadata()?;
+            if !meta.is_file() {
+                continue;
+            }
+            if let Ok(id) = BlobRef::parse(&name) {
+                files.push((meta.modified()?, id, meta.len()));
+            } else if is_leftover(&name) {
+                // A write that never finished
+                fs::remove_file(entry.path())?;
+            }
+            // Anything else isn't the cache's to touch
+        }
+        files.sort_by_key(|(modified, _, _)| *modified);
+
+        let disk = Self {
+            directory,
+            lru: Mutex::new(Lru::new(max_bytes)),
+        };
+        for (_, id, size) in files {
+            let evicted = disk.lru.lock().unwrap().insert(&id, size, ());
+            disk.remove(evicted);
+        }
+
+        Ok(disk)
+    }
+
+    fn path(&self, id: &BlobRef) -> PathBuf {
+        self.directory.join(id.as_str())
+    }
+
+    fn tmp_path(&self, id: &BlobRef) -> PathBuf {
+        self.directory.join(format!("{}{}", id, TMP_SUFFIX))
+    }
+
+    // Files that don't match their id are dropped, since the disk's not
+    // trusted the way the storage behind it is
+    fn get(&self, id: &BlobRef) -> Option<Vec<u8>> {
+        self.lru.lock().unwrap().get(id)?;
+        match fs::read(self.path(id)) {
+            Ok(data) if id.matches(&data) => Some(data),
+            _ => {
+                self.lru.lock().unwrap().remove(id);
+                let _ = fs::remove_file(self.path(id));
+                None
+            }
+        }
+    }
+
+    // Failing to cache a blob isn't worth failing the read over, so errors
+    // are only logged
+    fn put(&self, id: &BlobRef, data: &[u8]) {
+        if data.len() as u64 > self.lru.lock().unwrap().max_bytes {
+            return;
+        }
+
+        let tmp = self.tmp_path(id);
+        if let Err(e) = fs::write(&tmp, data).and_then(|_| fs::rename(&tmp, self.path(id))) {
+            tracing::warn!("error caching blob {} on disk: {}", id, e);
+            let _ = fs::remove_file(&tmp);
+            return;
+        }
+
+        let evicted = self.lru.lock().unwrap().insert(id, data.len() as u64, ());
+        self.remove(evicted);
+    }
+
+    fn remove(&self, ids: Vec<BlobRef>) {
+        for id in ids {
+            let _ = fs::remove_file(self.path(&id));
+        }
+    }
+}
+
+// Least recently used entries, kept to a total size
+pub(super) struct Lru<V> {
+    max_bytes: u64,
+    bytes: u64,
+    // Bumped on every use, so the lowest tick is the least recent
+    tick: u64,
+    entries: HashMap<BlobRef, Entry<V>>,
+    order: BTreeMap<u64, BlobRef>,
+}
+
+struct Entry<V> {
+    tick: u64,
+    size: u64,
+    value: V,
+}
+
+impl<V> Lru<V> {
+    pub(super) fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            bytes: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn contains(&self, id: &BlobRef) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    pub(super) fn get(&mut self, id: &BlobRef) -> Option<&V> {
+        let entry = self.entries.get_mut(id)?;
+        self.order.remove(&entry.tick);
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.order.insert(entry.tick, id.clone());
+
+        Some(&entry.value)
+    }
+
+    // Adds an entry, returning the ids pushed out to make room. Anything
+    // bigger than the whole cache isn't kept.
+    pub(super) fn insert(&mut self, id: &BlobRef, size: u64, value: V) -> Vec<BlobRef> {
+        if size > self.max_bytes || self.contains(id) {
+            return vec![];
+        }
+
+        let mut evicted = vec![];
+        while self.bytes + size > self.max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.bytes -= entry.size;
+            }
+            evicted.push(oldest);
+        }
+
+        self.tick += 1;
+        self.bytes += size;
+        self.order.insert(self.tick, id.clone());
+        self.entries.insert(
+            id.clone(),
+            Entry {
+                tick: self.tick,
+                size,
+                value,
+            },
+        );
+
+        evicted
+    }
+
+    fn remove(&mut self, id: &BlobRef) {
+        if let Some(entry) = self.entries.remove(id) {
+            self.order.remove(&entry.tick);
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Memory;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut lru = Lru::new(10);
+        let (a, b, c) = (BlobRef::of(b"a"), BlobRef::of(b"b"), BlobRef::of(b"c"));
+
+        assert!(lru.insert(&a, 4, ()).is_empty());
+        assert!(lru.insert(&b, 4, ()).is_empty());
+        lru.get(&a);
+        assert_eq!(lru.insert(&c, 4, ()), vec![b.clone()]);
+        assert!(lru.contains(&a) && lru.contains(&c) && !lru.contains(&b));
+        assert_eq!(lru.bytes, 8);
+
+        // Too big to ever fit
+        assert!(lru.insert(&b, 11, ()).is_empty());
+        assert!(!lru.contains(&b));
+    }
+
+    #[test]
+    fn reads_through_both_tiers() {
+        let dir = tempfile::tempdir().unwrap();
+        let memory = Memory::new();
+        let hot = BlobRef::of(b"hot");
+        let cold = BlobRef::of(b"cold");
+        Storage::put(&memory, &hot, b"hot".to_vec()).unwrap();
+        Storage::put(&memory, &cold, b"cold".to_vec()).unwrap();
+
+        let cached = Cached::new(memory, 4)
+            .with_disk(dir.path().to_owned(), 1024)
+            .unwrap();
+        assert_eq!(cached.get(&hot).unwrap(), b"hot");
+        assert_eq!(cached.get(&hot).unwrap(), b"hot");
+        // Pushes hot out of memory, but it's still on disk
+        assert_eq!(cached.get(&cold).unwrap(), b"cold");
+        cached.inner().corrupt(&hot);
+        assert_eq!(cached.get(&hot).unwrap(), b"hot");
+
+        let stats = cached.stats().unwrap().cache.unwrap();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+        assert_eq!(stats.disk_bytes, 7);
+
+        // A restart keeps what was on disk
+        drop(cached);
+        let cached = Cached::new(Memory::new(), 4)
+            .with_disk(dir.path().to_owned(), 1024)
+            .unwrap();
+        assert_eq!(cached.get(&cold).unwrap(), b"cold");
+        assert!(matches!(
+            cached.get(&BlobRef::of(b"nope")),
+            Err(StorageError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn only_cleans_up_its_own_leftovers() {
+        let dir = tempfile::tempdir().unwrap();
+        let leftover = dir.path().join(format!("{}.tmp", BlobRef::of(b"half")));
+        std::fs::write(&leftover, b"ha").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"mine").unwrap();
+        std::fs::write(dir.path().join("other.tmp"), b"mine").unwrap();
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+
+        Cached::new(Memory::new(), 4)
+            .with_disk(dir.path().to_owned(), 1024)
+            .unwrap();
+        assert!(!leftover.exists());
+        assert!(dir.path().join("notes.txt").exists());
+        assert!(dir.path().join("other.tmp").exists());
+        assert!(dir.path().join("nested").is_dir());
+    }
+}
@@ -0,0 +1,178 @@
+use crate::StorageError;
+
+// How a blob's bytes are laid out at rest.
+//
+// Encoded blobs start with a header: the magic bytes, which codec was used,
+// then the size of the blob before encoding as a little endian u64.
+//
+// Stores made since codecs only hold encoded blobs, and say so in their
+// layout. Older ones can have blobs without a header, which are read as
+// they are, so a header is only believed in those if it adds up.
+
+const MAGIC: &[u8; 4] = b"\xa7anc";
+/// How many bytes `header` needs to look at.
+pub const HEADER_LEN: usize = MAGIC.len() + 1 + 8;
+
+// Not worth the work of trying below this
+const MIN_COMPRESS_LEN: usize = 64;
+// What zstd considers a good tradeoff between speed and size
+const ZSTD_LEVEL: i32 = 3;
+// Well past anything the server takes in one upload, so a corrupt header
+// can't have decoding allocate whatever it says
+const MAX_LEN: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    None,
+    Zstd,
+}
+
+impl Codec {
+    fn byte(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => 1,
+        }
+    }
+
+    fn from_byte(b: u8) -> Result<Self, StorageError> {
+        match b {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Zstd),
+            _ => Err(StorageError::IO(format!("unknown blob codec {}", b))),
+        }
+    }
+}
+
+/// Encodes a blob for storage, compressing it unless that doesn't save
+/// enough to bother, like with photos and other already compressed data.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let compressed = if data.len() >= MIN_COMPRESS_LEN {
+        zstd::bulk::compress(data, ZSTD_LEVEL)
+            .ok()
+            // Has to save at least a tenth to be worth decompressing later
+            .filter(|c| c.len() < data.len() - data.len() / 10)
+    } else {
+        None
+    };
+
+    let (codec, body) = match &compressed {
+        Some(c) => (Codec::Zstd, &c[..]),
+        None => (Codec::None, data),
+    };
+
+    let mut encoded = Vec::with_capacity(HEADER_LEN + body.len());
+    encoded.extend_from_slice(MAGIC);
+    encoded.push(codec.byte());
+    encoded.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    encoded.extend_from_slice(body);
+    encoded
+}
+
+/// Gets a blob back from what was stored, in a store where every blob was
+/// encoded.
+pub fn decode(stored: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+    let Some((codec, len)) = header(&stored)? else {
+        return Err(StorageError::IO(String::from("blob has no codec header")));
+    };
+
+    decode_body(&stored[HEADER_LEN..], codec, len)
+}
+
+/// Gets a blob back from what was stored, in a store that might have blobs
+/// from before codecs. Ones whose header doesn't add up are taken to be
+/// one of those that happens to start like it.
+pub fn decode_legacy(stored: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+    match header(&stored) {
+        Ok(Some((codec, len))) => {
+            Ok(decode_body(&stored[HEADER_LEN..], codec, len).unwrap_or(stored))
+        }
+        _ => Ok(stored),
+    }
+}
+
+fn decode_body(body: &[u8], codec: Codec, len: u64) -> Result<Vec<u8>, StorageError> {
+    if len > MAX_LEN {
+        return Err(StorageError::IO(format!("blob claims to be {} bytes", len)));
+    }
+
+    let data = match codec {
+        Codec::None => body.to_vec(),
+        Codec::Zstd => zstd::bulk::decompress(body, len as usize)
+            .map_err(|e| StorageError::IO(format!("error decompressing blob: {}", e)))?,
+    };
+    if data.len() as u64 != len {
+        return Err(StorageError::IO(format!(
+            "blob decoded to {} bytes instead of {}",
+            data.len(),
+            len
+        )));
+    }
+
+    Ok(data)
+}
+
+/// The codec and size before encoding of a stored blob, from at least its
+/// first `HEADER_LEN` bytes. None if it was stored before codecs.
+pub fn header(stored: &[u8]) -> Result<Option<(Codec, u64)>, StorageError> {
+    if stored.len() < HEADER_LEN || &stored[..MAGIC.len()] != MAGIC {
+        return Ok(None);
+    }
+
+    let codec = Codec::from_byte(stored[MAGIC.len()])?;
+    let mut len = [0; 8];
+    len.copy_from_slice(&stored[MAGIC.len() + 1..HEADER_LEN]);
+    Ok(Some((codec, u64::from_le_bytes(len))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compresses_only_when_it_helps() {
+        let text = "all work and no play makes jack a dull boy\n".repeat(100);
+        let encoded = encode(text.as_bytes());
+        assert!(encoded.len() < text.len() / 5);
+        assert_eq!(
+            header(&encoded).unwrap(),
+            Some((Codec::Zstd, text.len() as u64))
+        );
+        assert_eq!(decode(encoded).unwrap(), text.as_bytes());
+
+        let cat = std::fs::read("./test_samples/cat.jpg").unwrap();
+        let encoded = encode(&cat);
+        assert_eq!(
+            header(&encoded).unwrap(),
+            Some((Codec::None, cat.len() as u64))
+        );
+        assert_eq!(decode(encoded).unwrap(), cat);
+    }
+
+    #[test]
+    fn reads_blobs_from_before_codecs() {
+        assert_eq!(header(b"hello").unwrap(), None);
+        assert_eq!(decode_legacy(b"hello".to_vec()).unwrap(), b"hello");
+        assert!(decode(b"hello".to_vec()).is_err());
+
+        // Starts like a header, but the rest doesn't match it
+        let mut raw = MAGIC.to_vec();
+        raw.push(Codec::None.byte());
+        raw.extend_from_slice(&100u64.to_le_bytes());
+        raw.extend_from_slice(b"hello");
+        assert_eq!(decode_legacy(raw.clone()).unwrap(), raw);
+        assert!(decode(raw).is_err());
+
+        let encoded = encode(b"hello");
+        assert_eq!(decode_legacy(encoded).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn refuses_huge_lengths() {
+        let mut stored = MAGIC.to_vec();
+        stored.push(Codec::Zstd.byte());
+        stored.extend_from_slice(&u64::MAX.to_le_bytes());
+        stored.extend_from_slice(&[0; 16]);
+        assert!(decode(stored).is_err());
+    }
+}
@@ -0,0 +1,372 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::{BlobRef, Storage, StorageError, StorageHealth, StorageStats};
+
+// Shards start with these, then their index, the data and parity shard
+// counts, the length of the whole blob and a checksum of the rest
+const MAGIC: &[u8; 4] = b"\xa7ers";
+const HEADER_LEN: usize = MAGIC.len() + 3 + 8 + 32;
+
+/// Splits every blob into data shards plus parity shards, one per child,
+/// so any of them can be lost up to the number of parity shards.
+///
+/// With 4 data and 2 parity shards, two children can go while each blob
+/// only takes up one and a half times its size. The children hold shards
+/// under the blob's id, so they can't be anything that checks what's stored
+/// matches its id, like a mirror.
+pub struct Erasure {
+    children: Vec<Arc<dyn Storage + Send + Sync>>,
+    data_shards: usize,
+    codec: ReedSolomon,
+}
+
+/// What a repair found and fixed.
+#[derive(Debug, Default)]
+pub struct Repaired {
+    pub blobs: u64,
+    // Shards that were missing or corrupt and got rewritten
+    pub shards: u64,
+    // Blobs with too few shards left to be put back together
+    pub lost: u64,
+}
+
+// The shards of one blob that could be read, None for the rest
+struct Shards {
+    shards: Vec<Option<Vec<u8>>>,
+    found: usize,
+    len: u64,
+    // The last thing that went wrong reading one
+    err: Option<StorageError>,
+}
+
+impl Erasure {
+    /// Spreads blobs over the children, the first data_shards of them
+    /// holding the data and the rest parity.
+    pub fn new(
+        children: Vec<Arc<dyn Storage + Send + Sync>>,
+        data_shards: usize,
+    ) -> Result<Self, StorageError> {
+        let parity_shards = children.len().saturating_sub(data_shards);
+        let codec = ReedSolomon::new(data_shards, parity_shards).map_err(|e| {
+            StorageError::IO(format!(
+                "can't split blobs into {} data and {} parity shards: {}",
+                data_shards, parity_shards, e
+            ))
+        })?;
+
+        Ok(Self {
+            children,
+            data_shards,
+            codec,
+        })
+    }
+
+    /// Rewrites any shard that's gone missing or corrupt, like those of a
+    /// drive that's just been replaced.
+    ///
+    /// Every shard gets read, so this takes as long as reading everything.
+    pub fn repair_all(&self) -> Result<Repaired, StorageError> {
+        let mut repaired = Repaired::default();
+        for id in self.list_blobs()? {
+            repaired.blobs += 1;
+
+            let mut read = self.read_shards(&id, true);
+            if read.found == self.children.len() {
+                continue;
+            }
+            if read.found < self.data_shards {
+                tracing::warn!("blob {} has too few shards left to repair", id);
+                repaired.lost += 1;
+                continue;
+            }
+
+            let missing: Vec<usize> = (0..self.children.len())
+                .filter(|i| read.shards[*i].is_none())
+                .collect();
+            self.codec
+                .reconstruct(&mut read.shards)
+                .map_err(|e| StorageError::IO(format!("error reconstructing {}: {}", id, e)))?;
+            for i in missing {
+                let payload = read.shards[i].as_deref().unwrap_or_default();
+                let shard = self.shard(i, read.len, payload);
+                match self.children[i].repair(&id, shard) {
+                    Ok(()) => repaired.shards += 1,
+                    Err(e) => tracing::warn!("error repairing shard {} of {}: {}", i, id, e),
+                }
+            }
+        }
+
+        Ok(repaired)
+    }
+
+    // Splits a blob into its shards, headers and all
+    fn encode(&self, data: &[u8]) -> Result<Vec<Vec<u8>>, StorageError> {
+        let shard_len = data.len().div_ceil(self.data_shards).max(1);
+        let mut payloads: Vec<Vec<u8>> = (0..self.children.len())
+            .map(|i| {
+                let start = (i * shard_len).min(data.len());
+                let end = ((i + 1) * shard_len).min(data.len());
+                let mut payload = data[start..end].to_vec();
+                payload.resize(shard_len, 0);
+                payload
+            })
+            .collect();
+        self.codec
+            .encode(&mut payloads)
+            .map_err(|e| StorageError::IO(format!("error encoding shards: {}", e)))?;
+
+        Ok(payloads
+            .iter()
+            .enumerate()
+            .map(|(i, payload)| self.shard(i, data.len() as u64, payload))
+            .collect())
+    }
+
+    fn shard(&self, index: usize, len: u64, payload: &[u8]) -> Vec<u8> {
+        let mut shard = Vec::with_capacity(HEADER_LEN + payload.len());
+        shard.extend_from_slice(MAGIC);
+        shard.push(index as u8);
+        shard.push(self.data_shards as u8);
+        shard.push((self.children.len() - self.data_shards) as u8);
+        shard.extend_from_slice(&len.to_le_bytes());
+        shard.extend_from_slice(blake3::hash(payload).as_bytes());
+        shard.extend_from_slice(payload);
+        shard
+    }
+
+    // The blob's length and the shard's payload, if it's the shard that
+    // should be there and hasn't been damaged
+    fn decode(&self, index: usize, shard: &[u8]) -> Option<(u64, Vec<u8>)> {
+        if shard.len() < HEADER_LEN || &shard[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        let header = &shard[MAGIC.len()..HEADER_LEN];
+        let layout = [
+            index as u8,
+            self.data_shards as u8,
+            (self.children.len() - self.data_shards) as u8,
+        ];
+        if header[..3] != layout {
+            return None;
+        }
+
+        let len = u64::from_le_bytes(header[3..11].try_into().ok()?);
+        let payload = &shard[HEADER_LEN..];
+        if blake3::hash(payload).as_bytes() != &header[11..] {
+            return None;
+        }
+
+        Some((len, payload.to_vec()))
+    }
+
+    // Reads shards until there are enough to put the blob back together,
+    // or every one of them when asked to
+    fn read_shards(&self, id: &BlobRef, all: bool) -> Shards {
+        let mut read = Shards {
+            shards: vec![None; self.children.len()],
+            found: 0,
+            len: 0,
+            err: None,
+        };
+        for (i, child) in self.children.iter().enumerate() {
+            // The data shards are tried first, so parity only gets read
+            // when some of them are gone
+            if !all && read.found == self.data_shards {
+                break;
+            }
+
+            match child.get(id).map(|shard| self.decode(i, &shard)) {
+                Ok(Some((len, payload))) => {
+                    read.shards[i] = Some(payload);
+                    read.found += 1;
+                    read.len = len;
+                }
+                Ok(None) => tracing::warn!("shard {} of {} is corrupt", i, id),
+                Err(StorageError::NotFound) => {}
+                Err(e) => {
+                    tracing::warn!("error reading shard {} of {}: {}", i, id, e);
+                    read.err = Some(e);
+                }
+            }
+        }
+
+        read
+    }
+}
+
+impl Storage for Erasure {
+    fn get(&self, id: &BlobRef) -> Result<Vec<u8>, StorageError> {
+        let mut read = self.read_shards(id, false);
+        if read.found < self.data_shards {
+            return Err(match read.err {
+                Some(e) => e,
+                None if read.found == 0 => StorageError::NotFound,
+                None => StorageError::IO(format!(
+                    "only {} of the {} shards needed for {} are left",
+                    read.found, self.data_shards, id
+                )),
+            });
+        }
+
+        self.codec
+            .reconstruct_data(&mut read.shards)
+            .map_err(|e| StorageError::IO(format!("error reconstructing {}: {}", id, e)))?;
+        let mut data: Vec<u8> = read.shards[..self.data_shards]
+            .iter()
+            .flat_map(|shard| shard.as_deref().unwrap_or_default())
+            .copied()
+            .collect();
+        data.truncate(read.len as usize);
+
+        if !id.matches(&data) {
+            return Err(StorageError::IO(format!(
+                "blob {} doesn't match its id once put back together",
+                id
+            )));
+        }
+
+        Ok(data)
+    }
+
+    // Goes through as long as enough shards were stored to read it back,
+    // a repair can fill in the rest
+    fn put(&self, id: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        let mut stored = 0;
+        let mut err = None;
+        for (i, (child, shard)) in self.children.iter().zip(self.encode(&data)?).enumerate() {
+            match child.put(id, shard) {
+                Ok(()) => stored += 1,
+                Err(e) => {
+                    tracing::warn!("error storing shard {} of {}: {}", i, id, e);
+                    err.get_or_insert(e);
+                }
+            }
+        }
+
+        match err {
+            Some(e) if stored < self.data_shards => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn repair(&self, id: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        for (child, shard) in self.children.iter().zip(self.encode(&data)?) {
+            child.repair(id, shard)?;
+        }
+        Ok(())
+    }
+
+    fn contains(&self, id: &BlobRef) -> Result<bool, StorageError> {
+        let mut found = 0;
+        for child in &self.children {
+            if child.contains(id)? {
+                found += 1;
+            }
+        }
+        Ok(found >= self.data_shards)
+    }
+
+    fn list_blobs(&self) -> Result<Vec<BlobRef>, StorageError> {
+        let mut all = HashSet::new();
+        for child in &self.children {
+            all.extend(child.list_blobs()?);
+        }
+        Ok(all.into_iter().collect())
+    }
+
+    // The data shards add up to the blobs, give or take their headers and
+    // padding, while the parity ones take up space on top
+    fn stats(&self) -> Result<StorageStats, StorageError> {
+        let mut stats = StorageStats::default();
+        for (i, child) in self.children.iter().enumerate() {
+            let child_stats = child.stats()?;
+            stats.blobs = stats.blobs.max(child_stats.blobs);
+            if i < self.data_shards {
+                stats.bytes += child_stats.bytes;
+            }
+            stats.physical_bytes += child_stats.physical_bytes;
+        }
+
+        Ok(stats)
+    }
+
+    // Each child only holds a shard of every blob, so the fullest one runs
+    // out after that many shards' worth
+    fn check(&self) -> Result<StorageHealth, StorageError> {
+        let mut healthy = 0;
+        let mut free_bytes: Option<u64> = None;
+        let mut err = None;
+        for child in &self.children {
+            match child.check() {
+                Ok(health) => {
+                    healthy += 1;
+                    if let Some(free) = health.free_bytes {
+                        free_bytes = Some(free_bytes.map_or(free, |f| f.min(free)));
+                    }
+                }
+                Err(e) => {
+                    err.get_or_insert(e);
+                }
+            }
+        }
+
+        match err {
+            Some(e) if healthy < self.data_shards => Err(e),
+            _ => Ok(StorageHealth {
+                free_bytes: free_bytes.map(|f| f.saturating_mul(self.data_shards as u64)),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Memory;
+
+    fn erasure(children: &[Arc<Memory>]) -> Erasure {
+        let children = children
+            .iter()
+            .map(|c| c.clone() as Arc<dyn Storage + Send + Sync>)
+            .collect();
+        Erasure::new(children, 2).unwrap()
+    }
+
+    #[test]
+    fn reconstructs_lost_shards() {
+        let mut children: Vec<_> = (0..4).map(|_| Arc::new(Memory::new())).collect();
+        let data = b"split across four drives, any two of which can go".to_vec();
+        let id = BlobRef::of(&data);
+        erasure(&children).put(&id, data.clone()).unwrap();
+
+        // One drive goes bad, another is swapped for an empty one
+        children[0].corrupt(&id);
+        children[1] = Arc::new(Memory::new());
+        let store = erasure(&children);
+        assert_eq!(store.get(&id).unwrap(), data);
+
+        let repaired = store.repair_all().unwrap();
+        assert_eq!((repaired.blobs, repaired.shards, repaired.lost), (1, 2, 0));
+
+        // Only the data shards are needed now
+        children[2] = Arc::new(Memory::new());
+        children[3] = Arc::new(Memory::new());
+        assert_eq!(erasure(&children).get(&id).unwrap(), data);
+
+        children[1] = Arc::new(Memory::new());
+        assert!(erasure(&children).get(&id).is_err());
+        assert!(matches!(
+            erasure(&children).get(&BlobRef::of(b"nope")),
+            Err(StorageError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn refuses_too_few_children() {
+        let children = vec![Arc::new(Memory::new()) as Arc<dyn Storage + Send + Sync>];
+        assert!(Erasure::new(children, 2).is_err());
+    }
+}
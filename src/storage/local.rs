@@ -1,41 +1,417 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
+use super::codec;
 use crate::error::{Error, Kind, WithKind};
-use crate::{Node, StorageError};
+use crate::hash::{self, Hasher};
+use crate::{Acl, BlobRef, Node, StorageError, StorageHealth, StorageStats};
 
-// Prefixes for the different types of files.
+// Blobs and nodes are spread out over two levels of directories named
+// after the start of their hash, e.g. `blobs/ab/cd/sha256-abcd...`, so no
+// one directory gets too big. Nodes are hashed by their id to find theirs,
+// and have a `.json` file with the latest revision and a `.revs` file with
+// every revision, one json line each.
+const BLOBS_DIR: &str = "blobs";
+const NODES_DIR: &str = "nodes";
+const NODE_EXTENSION: &str = ".json";
+const REVISIONS_EXTENSION: &str = ".revs";
+
+// Which layout the directory's in. Stores without one might be from before
+// sharding, with everything in the root. Sharded ones from before codecs
+// can still have blobs stored as they were uploaded.
+const LAYOUT_FILE: &str = "layout";
+const SHARDED_LAYOUT: &str = "2";
+const ENCODED_LAYOUT: &str = "3";
+
+// Prefixes for the different types of files in a flat store.
 //
 // This makes it a bit easier to figure out if what the
 // id refers to is a blob or node.
 const BLOB_PREFIX: &str = "blob-";
 const NODE_PREFIX: &str = "node-";
+const REVISIONS_PREFIX: &str = "revs-";
 
-// Constructs an id from a blob hash with the prefix
-fn blob_id(hash: &str) -> String {
-    format!("{}{}", BLOB_PREFIX, hash)
-}
-// Constructs an id from a blob hash with the prefix
-fn node_id(hash: &str) -> String {
-    format!("{}{}", NODE_PREFIX, hash)
+// The acl isn't content addressed, so it gets one fixed name
+const ACL_FILE: &str = "acl.json";
+
+// Where blobs moved by a rekey went, from their old id to their new one.
+// Nodes still refer to the old ids, so lookups follow it.
+const ALIASES_FILE: &str = "aliases.json";
+
+// Written and read back to make sure the directory is usable
+const PROBE_FILE: &str = ".probe";
+
+// The two directories below the root a hash is sharded into
+fn shard(hex: &str) -> PathBuf {
+    Path::new(&hex[..2]).join(&hex[2..4])
 }
 
 /// An implementation of a blobstore that is contained in a single,
 /// local directory.
 pub struct Local {
     directory: String,
+    // Puts are refused once free space drops below this
+    min_free_bytes: u64,
+    // Held while writing a node, so checking its revision and writing the
+    // next one can't interleave with another update or a migration
+    node_lock: Mutex<()>,
+    // Whether there might still be files from the flat layout to fall
+    // back to, until the store's known to be migrated
+    flat: AtomicBool,
+    // Whether every blob has a codec header, rather than there maybe being
+    // some from before codecs
+    encoded: AtomicBool,
+    // What's in the aliases file, read the first time a blob isn't where
+    // its id says and kept until a rekey changes it
+    aliases: RwLock<Option<Arc<HashMap<BlobRef, BlobRef>>>>,
+}
+
+/// What a migration to the sharded layout moved.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Migrated {
+    pub blobs: usize,
+    pub nodes: usize,
+}
+
+/// What a rekey did.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Rekeyed {
+    // Blobs moved to an id from the new algorithm
+    pub moved: usize,
+    // Blobs that already had one
+    pub skipped: usize,
 }
 
 impl Local {
     pub fn new(directory: String) -> Self {
-        Self { directory }
+        Self {
+            directory,
+            min_free_bytes: 0,
+            node_lock: Mutex::new(()),
+            flat: AtomicBool::new(true),
+            encoded: AtomicBool::new(false),
+            aliases: RwLock::new(None),
+        }
+    }
+
+    /// Sets how much free space has to be left on the disk for puts to
+    /// be accepted.
+    pub fn with_min_free_bytes(mut self, min_free_bytes: u64) -> Self {
+        self.min_free_bytes = min_free_bytes;
+        self
+    }
+
+    /// Makes sure the directory can actually be used, creating it first if
+    /// asked to, so problems show up at startup instead of on the first put.
+    pub fn validate(&self, create: bool) -> Result<(), StorageError> {
+        let dir = Path::new(&self.directory);
+        if create {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let metadata = std::fs::metadata(dir)?;
+        if !metadata.is_dir() {
+            return Err(StorageError::IO(format!(
+                "{} is not a directory",
+                self.directory
+            )));
+        }
+        if metadata.permissions().readonly() {
+            return Err(StorageError::IO(format!("{} is read only", self.directory)));
+        }
+
+        // Write something and make sure the same thing comes back
+        let probe = dir.join(PROBE_FILE);
+        let written = uuid::Uuid::new_v4().to_string();
+        std::fs::write(&probe, &written)?;
+        let read = std::fs::read_to_string(&probe)?;
+        std::fs::remove_file(&probe)?;
+        if read != written {
+            return Err(StorageError::IO(String::from(
+                "probe file read back differently",
+            )));
+        }
+
+        // New stores start out sharded
+        let layout = dir.join(LAYOUT_FILE);
+        if layout.exists() {
+            let version = std::fs::read_to_string(&layout)?;
+            let version = version.trim();
+            self.flat.store(
+                version != SHARDED_LAYOUT && version != ENCODED_LAYOUT,
+                Ordering::SeqCst,
+            );
+            self.encoded
+                .store(version == ENCODED_LAYOUT, Ordering::SeqCst);
+        } else if self.flat_files(BLOB_PREFIX)?.is_empty()
+            && self.flat_files(NODE_PREFIX)?.is_empty()
+        {
+            std::fs::write(layout, ENCODED_LAYOUT)?;
+            self.flat.store(false, Ordering::SeqCst);
+            self.encoded.store(true, Ordering::SeqCst);
+        }
+
+        Ok(())
+    }
+
+    /// Whether the store still has files in the flat layout to migrate.
+    pub fn needs_migration(&self) -> bool {
+        self.flat.load(Ordering::SeqCst)
+    }
+
+    /// Moves a store from before sharding into the sharded layout.
+    ///
+    /// It's safe to run while the store's in use, since reads fall back to
+    /// where files used to be until it's done.
+    pub fn migrate(&self) -> Result<Migrated, StorageError> {
+        let mut migrated = Migrated::default();
+
+        for (name, path) in self.flat_files(BLOB_PREFIX)? {
+            let Ok(id) = BlobRef::parse(&name) else {
+                continue;
+            };
+            move_file(&path, &self.blob_file(&id))?;
+            migrated.blobs += 1;
+        }
+
+        for (id, path) in self.flat_files(NODE_PREFIX)? {
+            let _lock = self.node_lock.lock().unwrap();
+            match move_file(&path, &self.node_file(&id)) {
+                Ok(()) => migrated.nodes += 1,
+                // Updated in between, which moves it itself
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            self.move_flat_revisions(&id)?;
+        }
+        // Revisions can be left behind by a node that was moved without them
+        for (id, _) in self.flat_files(REVISIONS_PREFIX)? {
+            let _lock = self.node_lock.lock().unwrap();
+            self.move_flat_revisions(&id)?;
+        }
+
+        let layout = if self.encoded.load(Ordering::SeqCst) {
+            ENCODED_LAYOUT
+        } else {
+            SHARDED_LAYOUT
+        };
+        std::fs::write(Path::new(&self.directory).join(LAYOUT_FILE), layout)?;
+        self.flat.store(false, Ordering::SeqCst);
+
+        Ok(migrated)
+    }
+
+    // Moves a node's revisions from the flat layout into its shard, after
+    // any already there. Only called holding the node lock.
+    fn move_flat_revisions(&self, id: &str) -> std::io::Result<()> {
+        let flat = self.flat_file(REVISIONS_PREFIX, id);
+        let sharded = self.revisions_file(id);
+        if !sharded.try_exists()? {
+            return match move_file(&flat, &sharded) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                moved => moved,
+            };
+        }
+
+        let mut revisions = match std::fs::read(&flat) {
+            Ok(revisions) => revisions,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        revisions.extend(std::fs::read(&sharded)?);
+        write_file(&sharded, &revisions)?;
+        std::fs::remove_file(flat)
+    }
+
+    fn blob_file(&self, hash: &BlobRef) -> PathBuf {
+        Path::new(&self.directory)
+            .join(BLOBS_DIR)
+            .join(shard(hash.hex()))
+            .join(hash.as_str())
+    }
+
+    // Node ids can be anything, so they're sharded by their hash
+    fn node_dir(&self, id: &str) -> PathBuf {
+        let hex = hash::Sha256.digest(id.as_bytes());
+        Path::new(&self.directory).join(NODES_DIR).join(shard(&hex))
+    }
+
+    fn node_file(&self, id: &str) -> PathBuf {
+        self.node_dir(id).join(format!("{}{}", id, NODE_EXTENSION))
+    }
+
+    fn revisions_file(&self, id: &str) -> PathBuf {
+        self.node_dir(id)
+            .join(format!("{}{}", id, REVISIONS_EXTENSION))
+    }
+
+    fn flat_file(&self, prefix: &str, id: &str) -> PathBuf {
+        Path::new(&self.directory).join(format!("{}{}", prefix, id))
+    }
+
+    // The ids and paths of files in the root from the flat layout
+    fn flat_files(&self, prefix: &str) -> Result<Vec<(String, PathBuf)>, std::io::Error> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(&self.directory)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(id) = name.strip_prefix(prefix) {
+                files.push((id.to_owned(), entry.path()));
+            }
+        }
+
+        Ok(files)
+    }
+
+    // Picks whichever of a sharded file or its flat original is there
+    fn locate(&self, sharded: PathBuf, prefix: &str, id: &str) -> std::io::Result<PathBuf> {
+        if !self.flat.load(Ordering::SeqCst) || sharded.try_exists()? {
+            return Ok(sharded);
+        }
+
+        let flat = self.flat_file(prefix, id);
+        if flat.try_exists()? {
+            return Ok(flat);
+        }
+
+        // Not there either, or a migration moved it in between
+        Ok(sharded)
+    }
+
+    // Every blob in the store and where it is
+    fn blob_files(&self) -> Result<Vec<(BlobRef, PathBuf)>, StorageError> {
+        let mut files = vec![];
+        for path in files_under(&Path::new(&self.directory).join(BLOBS_DIR))? {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if let Ok(id) = BlobRef::parse(&name) {
+                files.push((id, path));
+            }
+        }
+        for (name, path) in self.flat_files(BLOB_PREFIX)? {
+            if let Ok(id) = BlobRef::parse(&name) {
+                files.push((id, path));
+            }
+        }
+
+        Ok(files)
+    }
+
+    fn decode(&self, stored: Vec<u8>) -> Result<Vec<u8>, StorageError> {
+        if self.encoded.load(Ordering::SeqCst) {
+            codec::decode(stored)
+        } else {
+            codec::decode_legacy(stored)
+        }
+    }
+
+    fn free_bytes(&self) -> Result<u64, StorageError> {
+        Ok(fs2::available_space(&self.directory)?)
+    }
+
+    fn aliases(&self) -> Result<Arc<HashMap<BlobRef, BlobRef>>, StorageError> {
+        if let Some(aliases) = &*self.aliases.read().unwrap() {
+            return Ok(aliases.clone());
+        }
+
+        let path = Path::new(&self.directory).join(ALIASES_FILE);
+        let aliases = match std::fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map_err(|e| StorageError::IO(format!("error decoding aliases: {}", e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let aliases = Arc::new(aliases);
+        *self.aliases.write().unwrap() = Some(aliases.clone());
+
+        Ok(aliases)
+    }
+
+    // The file a blob lives in, following the aliases if it's been rekeyed
+    fn blob_path(&self, hash: &BlobRef) -> Result<PathBuf, StorageError> {
+        let path = self.locate(self.blob_file(hash), BLOB_PREFIX, hash.as_str())?;
+        if path.try_exists()? {
+            return Ok(path);
+        }
+
+        match self.aliases()?.get(hash) {
+            Some(alias) => Ok(self.locate(self.blob_file(alias), BLOB_PREFIX, alias.as_str())?),
+            None => Ok(path),
+        }
+    }
+
+    /// Moves every blob to an id made with `hasher`, remembering where each
+    /// one went so the old ids keep working.
+    ///
+    /// Meant to be run while nothing else is using the directory.
+    pub fn rekey(&self, hasher: &dyn Hasher) -> Result<Rekeyed, StorageError> {
+        let mut aliases = (*self.aliases()?).clone();
+        let mut rekeyed = Rekeyed::default();
+        let mut old = vec![];
+
+        for (id, path) in self.blob_files()? {
+            if id.algorithm() == hasher.name() {
+                rekeyed.skipped += 1;
+                continue;
+            }
+
+            // Ids are of what was uploaded, but it's moved as it's stored
+            let stored = std::fs::read(&path)?;
+            let new = BlobRef::hash(hasher, &self.decode(stored.clone())?);
+            write_file(&self.blob_file(&new), &stored)?;
+
+            // Anything that pointed at the old id now points at the new one
+            for target in aliases.values_mut() {
+                if *target == id {
+                    *target = new.clone();
+                }
+            }
+            aliases.insert(id, new);
+            aliases.retain(|from, to| from != to);
+            old.push(path);
+            rekeyed.moved += 1;
+        }
+
+        // The old files only go once the aliases to their replacements are
+        // safely written
+        let path = Path::new(&self.directory).join(ALIASES_FILE);
+        let tmp = Path::new(&self.directory).join(format!(".tmp-{}", ALIASES_FILE));
+        let contents = serde_json::to_vec_pretty(&aliases)
+            .map_err(|e| StorageError::IO(format!("error encoding aliases: {}", e)))?;
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, &path)?;
+        *self.aliases.write().unwrap() = Some(Arc::new(aliases));
+        for path in old {
+            std::fs::remove_file(path)?;
+        }
+
+        Ok(rekeyed)
+    }
+
+    // Always to the sharded file, since updating a node moves any flat one
+    // first. Only called holding the node lock.
+    fn append_revision(&self, hash: &str, node: &Node) -> Result<(), Error> {
+        let path = self.revisions_file(hash);
+        std::fs::create_dir_all(path.parent().unwrap())
+            .with_kind("error creating node directory", Kind::Internal)?;
+        let mut line = serde_json::to_vec(node).with_kind("error encoding node", Kind::Internal)?;
+        line.push(b'\n');
+
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut f| f.write_all(&line))
+            .with_kind("error writing revision", Kind::Internal)
     }
 }
 
 impl crate::Storage for Local {
-    fn get(&self, hash: &str) -> Result<Vec<u8>, StorageError> {
-        let path = Path::new(&self.directory).join(blob_id(hash));
+    fn get(&self, hash: &BlobRef) -> Result<Vec<u8>, StorageError> {
+        let path = self.blob_path(hash)?;
 
         tracing::debug!("path: {}", path.display());
 
@@ -49,22 +425,125 @@ impl crate::Storage for Local {
         })?;
         f.read_to_end(&mut buf)?;
 
-        Ok(buf)
+        self.decode(buf)
     }
 
-    fn put(&self, hash: &str, data: Vec<u8>) -> Result<(), StorageError> {
+    fn put(&self, hash: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
         // If the file is there, return early
-        let path = Path::new(&self.directory).join(blob_id(hash));
+        let path = self.blob_path(hash)?;
         if File::open(&path).is_ok() {
             return Ok(());
         }
 
+        // Refuse it if it'd eat into the space we're meant to leave free
+        let data = codec::encode(&data);
+        if self.min_free_bytes > 0 {
+            let free = self.free_bytes()?;
+            if free.saturating_sub(data.len() as u64) < self.min_free_bytes {
+                return Err(StorageError::InsufficientSpace {
+                    free,
+                    min_free: self.min_free_bytes,
+                });
+            }
+        }
+
         // Otherwise, create the file and write the data to it
-        let mut f = File::create(&path)?;
-        f.write_all(&data[..])?;
+        write_file(&self.blob_file(hash), &data)?;
 
         Ok(())
     }
+
+    fn repair(&self, hash: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        Ok(write_file(&self.blob_path(hash)?, &codec::encode(&data))?)
+    }
+
+    fn contains(&self, hash: &BlobRef) -> Result<bool, StorageError> {
+        Ok(self.blob_path(hash)?.try_exists()?)
+    }
+
+    fn list_blobs(&self) -> Result<Vec<BlobRef>, StorageError> {
+        Ok(self.blob_files()?.into_iter().map(|(id, _)| id).collect())
+    }
+
+    fn stats(&self) -> Result<StorageStats, StorageError> {
+        let mut stats = StorageStats::default();
+        for (_, path) in self.blob_files()? {
+            let physical = std::fs::metadata(&path)?.len();
+            stats.blobs += 1;
+            stats.physical_bytes += physical;
+            stats.bytes += match codec::header(&read_header(&path)?)? {
+                Some((_, len)) => len,
+                None => physical,
+            };
+        }
+
+        Ok(stats)
+    }
+
+    fn check(&self) -> Result<StorageHealth, StorageError> {
+        if !Path::new(&self.directory).is_dir() {
+            return Err(StorageError::IO(format!(
+                "{} is not a directory",
+                self.directory
+            )));
+        }
+
+        Ok(StorageHealth {
+            free_bytes: Some(self.free_bytes()?),
+        })
+    }
+}
+
+// Writes a file, making the directories it goes in if they're not there.
+//
+// It's written next to where it goes and renamed into place, so a crash
+// partway through never leaves half a file under the real name.
+fn write_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(parent)?;
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = parent.join(format!(".tmp-{}-{}", uuid::Uuid::new_v4(), name));
+    std::fs::write(&tmp, data)
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp);
+        })
+}
+
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(from, to)
+}
+
+// Every file in the shards below a directory
+fn files_under(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let outer = match std::fs::read_dir(dir) {
+        Ok(outer) => outer,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(files),
+        Err(e) => return Err(e),
+    };
+    for outer in outer {
+        for inner in std::fs::read_dir(outer?.path())? {
+            for file in std::fs::read_dir(inner?.path())? {
+                files.push(file?.path());
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+// Just enough of a blob file to see how it was stored
+fn read_header(path: &Path) -> Result<Vec<u8>, StorageError> {
+    let mut buf = vec![];
+    File::open(path)?
+        .take(codec::HEADER_LEN as u64)
+        .read_to_end(&mut buf)?;
+    Ok(buf)
 }
 
 impl From<std::io::Error> for StorageError {
@@ -75,7 +554,10 @@ impl From<std::io::Error> for StorageError {
 
 impl crate::NodeStore for Local {
     fn get(&self, hash: &str) -> Result<Node, Error> {
-        let path = Path::new(&self.directory).join(node_id(hash));
+        crate::validate_node_id(hash)?;
+        let path = self
+            .locate(self.node_file(hash), NODE_PREFIX, hash)
+            .with_kind("error finding node", Kind::Internal)?;
 
         let f = File::open(path).map_err(|e| {
             let kind = if e.kind() == std::io::ErrorKind::NotFound {
@@ -92,17 +574,476 @@ impl crate::NodeStore for Local {
     }
 
     fn put(&self, hash: &str, node: &Node) -> Result<(), Error> {
+        crate::validate_node_id(hash)?;
+        let _lock = self.node_lock.lock().unwrap();
+
         // If the file is there, return early
-        let path = Path::new(&self.directory).join(node_id(hash));
+        let path = self
+            .locate(self.node_file(hash), NODE_PREFIX, hash)
+            .with_kind("error finding node", Kind::Internal)?;
         if File::open(&path).is_ok() {
             return Ok(());
         }
 
+        let path = self.node_file(hash);
+        std::fs::create_dir_all(path.parent().unwrap())
+            .with_kind("error creating node directory", Kind::Internal)?;
         let f = File::create(&path).with_kind("error creating file", Kind::Internal)?;
         serde_json::to_writer_pretty(f, node).with_kind("error writing json", Kind::Internal)?;
 
         // Otherwise, create the file and write the data to it
 
+        self.append_revision(hash, node)
+    }
+
+    fn update(&self, hash: &str, expected_revision: u64, node: &Node) -> Result<(), Error> {
+        crate::validate_node_id(hash)?;
+        let _lock = self.node_lock.lock().unwrap();
+
+        let latest = crate::NodeStore::get(self, hash)?;
+        if latest.revision != expected_revision {
+            return Err(Error::from_msg(
+                &format!(
+                    "node is at revision {}, not {}",
+                    latest.revision, expected_revision
+                ),
+                Kind::Conflict,
+            ));
+        }
+
+        // The node's about to be written sharded, so its revisions go too
+        if self.flat.load(Ordering::SeqCst) {
+            self.move_flat_revisions(hash)
+                .with_kind("error moving revisions", Kind::Internal)?;
+        }
+
+        // Nodes from before revisions only have their node file
+        let revisions = self.revisions_file(hash);
+        if !revisions.exists() {
+            self.append_revision(hash, &latest)?;
+        }
+        self.append_revision(hash, node)?;
+
+        // Swapped in whole, so readers never see half a node
+        let path = self.node_file(hash);
+        std::fs::create_dir_all(path.parent().unwrap())
+            .with_kind("error creating node directory", Kind::Internal)?;
+        let tmp = self.node_dir(hash).join(format!(".tmp-{}", hash));
+        let f = File::create(&tmp).with_kind("error creating file", Kind::Internal)?;
+        serde_json::to_writer_pretty(f, node).with_kind("error writing json", Kind::Internal)?;
+        std::fs::rename(&tmp, &path).with_kind("error replacing node", Kind::Internal)?;
+
+        // The sharded one's the latest now, if it was still flat
+        match std::fs::remove_file(self.flat_file(NODE_PREFIX, hash)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::from_err(
+                "error removing old node",
+                e,
+                Kind::Internal,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn revisions(&self, hash: &str) -> Result<Vec<Node>, Error> {
+        crate::validate_node_id(hash)?;
+        let path = self
+            .locate(self.revisions_file(hash), REVISIONS_PREFIX, hash)
+            .with_kind("error finding revisions", Kind::Internal)?;
+        let contents = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(vec![crate::NodeStore::get(self, hash)?])
+            }
+            Err(e) => {
+                return Err(Error::from_err(
+                    "error reading revisions",
+                    e,
+                    Kind::Internal,
+                ))
+            }
+        };
+
+        contents
+            .lines()
+            .map(|line| {
+                serde_json::from_str(line).with_kind("error decoding revision", Kind::Internal)
+            })
+            .collect()
+    }
+
+    fn delete(&self, hash: &str) -> Result<(), Error> {
+        crate::validate_node_id(hash)?;
+        let _lock = self.node_lock.lock().unwrap();
+
+        // Wherever it is, whether it's been migrated or not
+        let mut deleted = false;
+        for path in [self.node_file(hash), self.flat_file(NODE_PREFIX, hash)] {
+            match std::fs::remove_file(path) {
+                Ok(()) => deleted = true,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Error::from_err("error deleting node", e, Kind::Internal)),
+            }
+        }
+        if !deleted {
+            return Err(Error::from_msg("node not found", Kind::NotFound));
+        }
+
+        for path in [
+            self.revisions_file(hash),
+            self.flat_file(REVISIONS_PREFIX, hash),
+        ] {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(Error::from_err(
+                        "error deleting revisions",
+                        e,
+                        Kind::Internal,
+                    ))
+                }
+                _ => {}
+            }
+        }
+
         Ok(())
     }
+
+    fn list(&self) -> Result<Vec<Node>, Error> {
+        // Flat ones first, so one being migrated meanwhile isn't missed
+        let mut ids = HashSet::new();
+        if self.flat.load(Ordering::SeqCst) {
+            let flat = self
+                .flat_files(NODE_PREFIX)
+                .with_kind("error listing nodes", Kind::Internal)?;
+            ids.extend(flat.into_iter().map(|(id, _)| id));
+        }
+        let files = files_under(&Path::new(&self.directory).join(NODES_DIR))
+            .with_kind("error listing nodes", Kind::Internal)?;
+        for path in files {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with('.') {
+                continue;
+            }
+            if let Some(id) = name.strip_suffix(NODE_EXTENSION) {
+                ids.insert(id.to_owned());
+            }
+        }
+
+        Ok(ids
+            .iter()
+            .filter_map(|id| match crate::NodeStore::get(self, id) {
+                Ok(node) => Some(node),
+                // Deleted since it was listed
+                Err(e) if matches!(e.kind, Kind::NotFound) => None,
+                Err(e) => {
+                    tracing::warn!("skipping node {} in listing: {}", id, e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        crate::Storage::check(self)
+            .map(|_| ())
+            .with_kind("node directory unreachable", Kind::Internal)
+    }
+}
+
+impl crate::AclStore for Local {
+    fn get_acl(&self) -> Result<Acl, Error> {
+        let path = Path::new(&self.directory).join(ACL_FILE);
+
+        let f = match File::open(path) {
+            Ok(f) => f,
+            // Nothing stored yet means nobody has been granted anything
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Acl::default()),
+            Err(e) => return Err(Error::from_err("error opening acl", e, Kind::Internal)),
+        };
+
+        serde_json::from_reader(f).with_kind("error decoding acl", Kind::Internal)
+    }
+
+    fn put_acl(&self, acl: &Acl) -> Result<(), Error> {
+        // Unlike blobs and nodes, the acl gets replaced on every write
+        // Written aside and renamed over, so it's never read half written
+        let path = Path::new(&self.directory).join(ACL_FILE);
+        let data =
+            serde_json::to_vec_pretty(acl).with_kind("error encoding acl", Kind::Internal)?;
+        write_file(&path, &data).with_kind("error writing acl", Kind::Internal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Storage;
+
+    #[test]
+    fn replaces_the_acl_whole() {
+        use crate::AclStore;
+
+        let dir = tempfile::tempdir().unwrap();
+        let local = Local::new(dir.path().to_str().unwrap().to_owned());
+        assert!(local.get_acl().unwrap().admins.is_empty());
+
+        for admin in ["james", "jo"] {
+            let acl = Acl {
+                admins: vec![String::from(admin)],
+                ..Default::default()
+            };
+            local.put_acl(&acl).unwrap();
+            assert_eq!(local.get_acl().unwrap().admins, acl.admins);
+        }
+        // Nothing's left over from writing it aside first
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn validate_creates_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_dir = dir.path().join("store");
+        let local = Local::new(store_dir.to_str().unwrap().to_owned());
+
+        assert!(local.validate(false).is_err());
+        local.validate(true).unwrap();
+        assert!(store_dir.is_dir());
+        assert!(local.check().unwrap().free_bytes.is_some());
+    }
+
+    #[test]
+    fn only_trusts_headers_in_encoded_stores() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = Local::new(dir.path().to_str().unwrap().to_owned());
+        local.validate(false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join(LAYOUT_FILE)).unwrap(),
+            ENCODED_LAYOUT
+        );
+
+        // Stored as uploaded before codecs, and happens to look like a header
+        let raw = b"\xa7anc\x00\x05\x00\x00\x00\x00\x00\x00\x00hello!".to_vec();
+        let id = BlobRef::of(&raw);
+        write_file(&local.blob_file(&id), &raw).unwrap();
+        assert!(local.get(&id).is_err());
+
+        std::fs::write(dir.path().join(LAYOUT_FILE), SHARDED_LAYOUT).unwrap();
+        let local = Local::new(dir.path().to_str().unwrap().to_owned());
+        local.validate(false).unwrap();
+        assert_eq!(local.get(&id).unwrap(), raw);
+    }
+
+    #[test]
+    fn lists_and_deletes_nodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = Local::new(dir.path().to_str().unwrap().to_owned());
+        let node = Node {
+            id: String::from("abc"),
+            namespace: String::from(crate::DEFAULT_NAMESPACE),
+            node_type: crate::NodeType::File,
+            blobs: vec![],
+            attributes: Default::default(),
+            revision: 1,
+            updated: 0,
+            author: None,
+        };
+        crate::NodeStore::put(&local, &node.id, &node).unwrap();
+        local
+            .put(&BlobRef::of(b"hello"), b"hello".to_vec())
+            .unwrap();
+
+        // One that can't be decoded is left out rather than failing the rest
+        let bad = local.node_file("bad");
+        std::fs::create_dir_all(bad.parent().unwrap()).unwrap();
+        std::fs::write(&bad, b"{").unwrap();
+
+        let nodes = crate::NodeStore::list(&local).unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].id, "abc");
+        let stats = local.stats().unwrap();
+        assert_eq!((stats.blobs, stats.bytes), (1, 5));
+
+        crate::NodeStore::delete(&local, "abc").unwrap();
+        assert!(crate::NodeStore::list(&local).unwrap().is_empty());
+        let err = crate::NodeStore::delete(&local, "abc").unwrap_err();
+        assert!(matches!(err.kind, Kind::NotFound));
+    }
+
+    #[test]
+    fn refuses_ids_outside_the_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = dir.path().join("store");
+        let local = Local::new(store.to_str().unwrap().to_owned());
+        let node = Node {
+            id: String::from("../../escaped"),
+            namespace: String::from(crate::DEFAULT_NAMESPACE),
+            node_type: crate::NodeType::File,
+            blobs: vec![],
+            attributes: Default::default(),
+            revision: 1,
+            updated: 0,
+            author: None,
+        };
+
+        for id in ["../../escaped", "..", ".hidden", "a/b", "a\\b", ""] {
+            let err = crate::NodeStore::put(&local, id, &node).unwrap_err();
+            assert!(matches!(err.kind, Kind::BadRequest), "{}", id);
+            let err = crate::NodeStore::get(&local, id).unwrap_err();
+            assert!(matches!(err.kind, Kind::BadRequest), "{}", id);
+        }
+        assert!(!dir.path().join("escaped.json").exists());
+    }
+
+    #[test]
+    fn updates_nodes_by_revision() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = Local::new(dir.path().to_str().unwrap().to_owned());
+        let mut node = Node {
+            id: String::from("abc"),
+            namespace: String::from(crate::DEFAULT_NAMESPACE),
+            node_type: crate::NodeType::File,
+            blobs: vec![BlobRef::of(b"one")],
+            attributes: Default::default(),
+            revision: 1,
+            updated: 10,
+            author: None,
+        };
+        crate::NodeStore::put(&local, &node.id, &node).unwrap();
+
+        node.blobs = vec![BlobRef::of(b"two")];
+        node.revision = 2;
+        crate::NodeStore::update(&local, "abc", 1, &node).unwrap();
+
+        // Someone else working from the first revision loses
+        node.revision = 2;
+        let err = crate::NodeStore::update(&local, "abc", 1, &node).unwrap_err();
+        assert!(matches!(err.kind, Kind::Conflict));
+
+        let latest = crate::NodeStore::get(&local, "abc").unwrap();
+        assert_eq!(latest.revision, 2);
+        assert_eq!(latest.blobs, vec![BlobRef::of(b"two")]);
+        let revisions = crate::NodeStore::revisions(&local, "abc").unwrap();
+        let blobs: Vec<_> = revisions.iter().map(|n| n.blobs[0].clone()).collect();
+        assert_eq!(blobs, vec![BlobRef::of(b"one"), BlobRef::of(b"two")]);
+    }
+
+    #[test]
+    fn rekeys_blobs_behind_aliases() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = Local::new(dir.path().to_str().unwrap().to_owned());
+        let old = BlobRef::of(b"hello");
+        local.put(&old, b"hello".to_vec()).unwrap();
+        // Missing, so the aliases are read and kept from before the rekey
+        let new = BlobRef::hash(&crate::hash::Blake3, b"hello");
+        assert!(!local.contains(&new).unwrap());
+
+        let rekeyed = local.rekey(&crate::hash::Blake3).unwrap();
+        assert_eq!(
+            rekeyed,
+            Rekeyed {
+                moved: 1,
+                skipped: 0
+            }
+        );
+        assert_eq!(local.get(&new).unwrap(), b"hello");
+        assert!(!local.blob_file(&old).exists());
+
+        // The old id still finds it
+        assert!(local.contains(&old).unwrap());
+        assert_eq!(local.get(&old).unwrap(), b"hello");
+
+        let rekeyed = local.rekey(&crate::hash::Blake3).unwrap();
+        assert_eq!(
+            rekeyed,
+            Rekeyed {
+                moved: 0,
+                skipped: 1
+            }
+        );
+        assert_eq!(local.get(&old).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn migrates_flat_stores() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = Node {
+            id: String::from("abc"),
+            namespace: String::from(crate::DEFAULT_NAMESPACE),
+            node_type: crate::NodeType::File,
+            blobs: vec![BlobRef::of(b"hello")],
+            attributes: Default::default(),
+            revision: 1,
+            updated: 0,
+            author: None,
+        };
+        // How a store from before sharding looked
+        let blob = BlobRef::of(b"hello");
+        std::fs::write(dir.path().join(format!("blob-{}", blob)), b"hello").unwrap();
+        std::fs::write(
+            dir.path().join("node-abc"),
+            serde_json::to_vec(&node).unwrap(),
+        )
+        .unwrap();
+        let revs = |nodes: &[&Node]| {
+            let mut revs = vec![];
+            for node in nodes {
+                revs.extend(serde_json::to_vec(node).unwrap());
+                revs.push(b'\n');
+            }
+            revs
+        };
+        std::fs::write(dir.path().join("revs-abc"), revs(&[&node])).unwrap();
+
+        let local = Local::new(dir.path().to_str().unwrap().to_owned());
+        local.validate(false).unwrap();
+        assert!(local.needs_migration());
+        assert_eq!(local.get(&blob).unwrap(), b"hello");
+        assert_eq!(crate::NodeStore::get(&local, "abc").unwrap().id, "abc");
+
+        // Updated before the migration gets to it
+        let mut next = node.clone();
+        next.revision = 2;
+        crate::NodeStore::update(&local, "abc", 1, &next).unwrap();
+        assert!(!dir.path().join("node-abc").exists());
+
+        assert!(!dir.path().join("revs-abc").exists());
+
+        // And a node that was moved without its revisions
+        let mut stranded = node.clone();
+        stranded.id = String::from("def");
+        write_file(
+            &local.node_file("def"),
+            &serde_json::to_vec(&stranded).unwrap(),
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("revs-def"), revs(&[&stranded])).unwrap();
+
+        let migrated = local.migrate().unwrap();
+        assert_eq!(migrated, Migrated { blobs: 1, nodes: 0 });
+        assert!(!dir.path().join("revs-def").exists());
+        assert_eq!(crate::NodeStore::revisions(&local, "def").unwrap().len(), 1);
+        assert!(!local.needs_migration());
+        assert!(local.blob_file(&blob).exists());
+        assert_eq!(local.get(&blob).unwrap(), b"hello");
+        assert_eq!(crate::NodeStore::list(&local).unwrap().len(), 2);
+        assert_eq!(crate::NodeStore::get(&local, "abc").unwrap().revision, 2);
+        assert_eq!(crate::NodeStore::revisions(&local, "abc").unwrap().len(), 2);
+
+        // And it remembers
+        let local = Local::new(dir.path().to_str().unwrap().to_owned());
+        local.validate(false).unwrap();
+        assert!(!local.needs_migration());
+        assert_eq!(local.stats().unwrap().blobs, 1);
+    }
+
+    #[test]
+    fn refuses_puts_when_low_on_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let local =
+            Local::new(dir.path().to_str().unwrap().to_owned()).with_min_free_bytes(u64::MAX);
+
+        let id = BlobRef::of(b"hello");
+        let res = local.put(&id, b"hello".to_vec());
+        assert!(matches!(res, Err(StorageError::InsufficientSpace { .. })));
+        assert!(!local.contains(&id).unwrap());
+    }
 }
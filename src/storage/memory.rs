@@ -0,0 +1,240 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use crate::error::{Error, Kind};
+use crate::{Acl, BlobRef, Node, StorageError, StorageHealth, StorageStats};
+
+/// A store that keeps everything in memory, gone once it's dropped. Meant
+/// for tests and throwaway servers.
+///
+/// It can be told to misbehave, failing puts or handing back corrupt
+/// blobs, to test what happens when a real store does.
+#[derive(Default)]
+pub struct Memory {
+    // Puts are refused once the blobs would take up more than this
+    max_bytes: Option<u64>,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    blobs: HashMap<BlobRef, Vec<u8>>,
+    bytes: u64,
+    // Every revision of each node, oldest first
+    nodes: HashMap<String, Vec<Node>>,
+    acl: Option<Acl>,
+
+    // Puts still to go before one fails, if one's meant to
+    fail_put_in: Option<usize>,
+    corrupt: HashSet<BlobRef>,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refuses puts that would take the blobs past this many bytes.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Makes the nth put from now fail, counting from 1.
+    pub fn fail_nth_put(&self, n: usize) {
+        self.inner.lock().unwrap().fail_put_in = Some(n.max(1));
+    }
+
+    /// Makes gets of a blob return it with its bytes flipped.
+    pub fn corrupt(&self, id: &BlobRef) {
+        self.inner.lock().unwrap().corrupt.insert(id.clone());
+    }
+}
+
+impl crate::Storage for Memory {
+    fn get(&self, hash: &BlobRef) -> Result<Vec<u8>, StorageError> {
+        let inner = self.inner.lock().unwrap();
+        let mut data = inner
+            .blobs
+            .get(hash)
+            .cloned()
+            .ok_or(StorageError::NotFound)?;
+        if inner.corrupt.contains(hash) {
+            data.iter_mut().for_each(|b| *b = !*b);
+        }
+
+        Ok(data)
+    }
+
+    fn put(&self, hash: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(n) = inner.fail_put_in {
+            if n == 1 {
+                inner.fail_put_in = None;
+                return Err(StorageError::IO(String::from("injected put failure")));
+            }
+            inner.fail_put_in = Some(n - 1);
+        }
+
+        if inner.blobs.contains_key(hash) {
+            return Ok(());
+        }
+
+        let bytes = inner.bytes + data.len() as u64;
+        if let Some(max) = self.max_bytes {
+            if bytes > max {
+                return Err(StorageError::InsufficientSpace {
+                    free: max - inner.bytes,
+                    min_free: 0,
+                });
+            }
+        }
+
+        inner.bytes = bytes;
+        inner.blobs.insert(hash.clone(), data);
+
+        Ok(())
+    }
+
+    // Puts back a good copy of a blob it was told to corrupt
+    fn repair(&self, hash: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        self.inner.lock().unwrap().corrupt.remove(hash);
+        self.put(hash, data)
+    }
+
+    fn contains(&self, hash: &BlobRef) -> Result<bool, StorageError> {
+        Ok(self.inner.lock().unwrap().blobs.contains_key(hash))
+    }
+
+    fn list_blobs(&self) -> Result<Vec<BlobRef>, StorageError> {
+        Ok(self.inner.lock().unwrap().blobs.keys().cloned().collect())
+    }
+
+    fn stats(&self) -> Result<StorageStats, StorageError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(StorageStats {
+            blobs: inner.blobs.len() as u64,
+            bytes: inner.bytes,
+            physical_bytes: inner.bytes,
+            ..Default::default()
+        })
+    }
+
+    fn check(&self) -> Result<StorageHealth, StorageError> {
+        let inner = self.inner.lock().unwrap();
+        Ok(StorageHealth {
+            free_bytes: self.max_bytes.map(|max| max - inner.bytes),
+        })
+    }
+}
+
+fn not_found() -> Error {
+    Error::from_msg("node not found", Kind::NotFound)
+}
+
+impl crate::NodeStore for Memory {
+    fn get(&self, hash: &str) -> Result<Node, Error> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .nodes
+            .get(hash)
+            .and_then(|revisions| revisions.last())
+            .cloned()
+            .ok_or_else(not_found)
+    }
+
+    fn put(&self, hash: &str, node: &Node) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .nodes
+            .entry(hash.to_owned())
+            .or_insert_with(|| vec![node.clone()]);
+
+        Ok(())
+    }
+
+    fn update(&self, hash: &str, expected_revision: u64, node: &Node) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        let revisions = inner.nodes.get_mut(hash).ok_or_else(not_found)?;
+
+        let latest = revisions.last().map_or(0, |n| n.revision);
+        if latest != expected_revision {
+            return Err(Error::from_msg(
+                &format!("node is at revision {}, not {}", latest, expected_revision),
+                Kind::Conflict,
+            ));
+        }
+        revisions.push(node.clone());
+
+        Ok(())
+    }
+
+    fn revisions(&self, hash: &str) -> Result<Vec<Node>, Error> {
+        let inner = self.inner.lock().unwrap();
+        inner.nodes.get(hash).cloned().ok_or_else(not_found)
+    }
+
+    fn delete(&self, hash: &str) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.nodes.remove(hash).map(|_| ()).ok_or_else(not_found)
+    }
+
+    fn list(&self) -> Result<Vec<Node>, Error> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .nodes
+            .values()
+            .filter_map(|revisions| revisions.last())
+            .cloned()
+            .collect())
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl crate::AclStore for Memory {
+    fn get_acl(&self) -> Result<Acl, Error> {
+        // Nothing stored yet means nobody has been granted anything
+        Ok(self.inner.lock().unwrap().acl.clone().unwrap_or_default())
+    }
+
+    fn put_acl(&self, acl: &Acl) -> Result<(), Error> {
+        self.inner.lock().unwrap().acl = Some(acl.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Storage;
+
+    #[test]
+    fn misbehaves_when_told_to() {
+        let memory = Memory::new().with_max_bytes(10);
+        let one = BlobRef::of(b"one");
+        let two = BlobRef::of(b"two");
+
+        memory.fail_nth_put(2);
+        memory.put(&one, b"one".to_vec()).unwrap();
+        assert!(matches!(
+            memory.put(&two, b"two".to_vec()),
+            Err(StorageError::IO(_))
+        ));
+        memory.put(&two, b"two".to_vec()).unwrap();
+
+        let res = memory.put(&BlobRef::of(b"too big"), b"too big".to_vec());
+        assert!(matches!(
+            res,
+            Err(StorageError::InsufficientSpace { free: 4, .. })
+        ));
+
+        memory.corrupt(&one);
+        let data = memory.get(&one).unwrap();
+        assert_ne!(data, b"one");
+        assert!(!one.matches(&data));
+        assert_eq!(memory.get(&two).unwrap(), b"two");
+    }
+}
@@ -0,0 +1,721 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::error::{Error, Kind};
+use crate::{
+    Acl, AclStore, BlobRef, Node, NodeStore, Storage, StorageError, StorageHealth, StorageStats,
+};
+
+/// How many of a mirror's children a put has to reach to go through.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum WritePolicy {
+    #[default]
+    All,
+    // More than half of them
+    Quorum,
+}
+
+impl WritePolicy {
+    /// How many of that many children a write has to reach.
+    pub fn needed(&self, children: usize) -> usize {
+        match self {
+            WritePolicy::All => children,
+            WritePolicy::Quorum => children / 2 + 1,
+        }
+    }
+}
+
+/// Keeps a copy of every blob in each of its children, so losing one
+/// loses nothing.
+///
+/// Reads come from the first child with a good copy, and any child found
+/// missing it or holding a corrupt one along the way is given the good one.
+pub struct Mirror {
+    children: Vec<Arc<dyn Storage + Send + Sync>>,
+    policy: WritePolicy,
+}
+
+/// What a resync copied over.
+#[derive(Debug, Default)]
+pub struct Resynced {
+    pub copied: u64,
+    // Blobs that couldn't be read from anywhere or written where missing
+    pub failed: u64,
+}
+
+impl Mirror {
+    pub fn new(children: Vec<Arc<dyn Storage + Send + Sync>>, policy: WritePolicy) -> Self {
+        Self { children, policy }
+    }
+
+    // Children a put has to reach
+    fn needed(&self) -> usize {
+        self.policy.needed(self.children.len())
+    }
+
+    /// Copies blobs over to any child that doesn't have them, like a disk
+    /// that's just been replaced.
+    ///
+    /// Children that can't be listed are left out, to be caught up by the
+    /// next resync. Corrupt copies are only found by reading them.
+    pub fn resync(&self) -> Result<Resynced, StorageError> {
+        let mut listed = vec![];
+        for (i, child) in self.children.iter().enumerate() {
+            match child.list_blobs() {
+                Ok(ids) => listed.push((i, ids.into_iter().collect::<HashSet<_>>())),
+                Err(e) => tracing::warn!("skipping mirror child {} in resync: {}", i, e),
+            }
+        }
+        if listed.is_empty() {
+            return Err(StorageError::IO(String::from(
+                "no mirror children could be listed",
+            )));
+        }
+
+        let all: HashSet<&BlobRef> = listed.iter().flat_map(|(_, ids)| ids).collect();
+        let mut resynced = Resynced::default();
+        for id in all {
+            let missing: Vec<_> = listed
+                .iter()
+                .filter(|(_, ids)| !ids.contains(id))
+                .map(|(i, _)| &self.children[*i])
+                .collect();
+            if missing.is_empty() {
+                continue;
+            }
+
+            // Reading repairs wherever it notices the blob missing
+            match self.get(id) {
+                Ok(data) => {
+                    for child in missing {
+                        if child.contains(id)? {
+                            resynced.copied += 1;
+                            continue;
+                        }
+                        match child.put(id, data.clone()) {
+                            Ok(()) => resynced.copied += 1,
+                            Err(e) => {
+                                tracing::warn!("error resyncing blob {}: {}", id, e);
+                                resynced.failed += 1;
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("error reading blob {} to resync: {}", id, e);
+                    resynced.failed += missing.len() as u64;
+                }
+            }
+        }
+
+        Ok(resynced)
+    }
+}
+
+impl Storage for Mirror {
+    fn get(&self, id: &BlobRef) -> Result<Vec<u8>, StorageError> {
+        let mut bad: Vec<(usize, &Arc<dyn Storage + Send + Sync>)> = vec![];
+        let mut corrupt = false;
+        let mut err = None;
+        for (i, child) in self.children.iter().enumerate() {
+            match child.get(id) {
+                Ok(data) if id.matches(&data) => {
+                    for (i, child) in bad {
+                        if let Err(e) = child.repair(id, data.clone()) {
+                            tracing::warn!("error repairing blob {} on child {}: {}", id, i, e);
+                        }
+                    }
+                    return Ok(data);
+                }
+                Ok(_) => {
+                    tracing::warn!("mirror child {} has a corrupt copy of {}", i, id);
+                    corrupt = true;
+                    bad.push((i, child));
+                }
+                Err(StorageError::NotFound) => bad.push((i, child)),
+                // Unhealthy, so not worth trying to repair
+                Err(e) => {
+                    tracing::warn!("error reading blob {} from child {}: {}", id, i, e);
+                    err = Some(e);
+                }
+            }
+        }
+
+        // Only not found if no child had anything for it at all
+        match err {
+            Some(e) => Err(e),
+            None if corrupt => Err(StorageError::IO(format!(
+                "every copy of blob {} is corrupt",
+                id
+            ))),
+            None => Err(StorageError::NotFound),
+        }
+    }
+
+    // Goes to every child either way, the policy only decides how many
+    // failures are too many
+    fn put(&self, id: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        let mut stored = 0;
+        let mut err = None;
+        for (i, child) in self.children.iter().enumerate() {
+            match child.put(id, data.clone()) {
+                Ok(()) => stored += 1,
+                Err(e) => {
+                    tracing::warn!("error storing blob {} on child {}: {}", id, i, e);
+                    err.get_or_insert(e);
+                }
+            }
+        }
+
+        match err {
+            Some(e) if stored < self.needed() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn repair(&self, id: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        for child in &self.children {
+            child.repair(id, data.clone())?;
+        }
+        Ok(())
+    }
+
+    // A blob only some children have is still there, reads will find it
+    fn contains(&self, id: &BlobRef) -> Result<bool, StorageError> {
+        let mut err = None;
+        for child in &self.children {
+            match child.contains(id) {
+                Ok(true) => return Ok(true),
+                Ok(false) => {}
+                Err(e) => {
+                    err.get_or_insert(e);
+                }
+            }
+        }
+
+        match err {
+            Some(e) => Err(e),
+            None => Ok(false),
+        }
+    }
+
+    fn list_blobs(&self) -> Result<Vec<BlobRef>, StorageError> {
+        let mut all = HashSet::new();
+        for child in &self.children {
+            all.extend(child.list_blobs()?);
+        }
+        Ok(all.into_iter().collect())
+    }
+
+    // The blobs are counted once, going by the fullest child, but every
+    // copy takes up space
+    fn stats(&self) -> Result<StorageStats, StorageError> {
+        let mut stats = StorageStats::default();
+        for child in &self.children {
+            let child_stats = child.stats()?;
+            stats.blobs = stats.blobs.max(child_stats.blobs);
+            stats.bytes = stats.bytes.max(child_stats.bytes);
+            stats.physical_bytes += child_stats.physical_bytes;
+        }
+
+        Ok(stats)
+    }
+
+    // Healthy as long as puts can still go through, with only as much
+    // room as the fullest child has
+    fn check(&self) -> Result<StorageHealth, StorageError> {
+        let mut healthy = 0;
+        let mut free_bytes: Option<u64> = None;
+        let mut err = None;
+        for child in &self.children {
+            match child.check() {
+                Ok(health) => {
+                    healthy += 1;
+                    if let Some(free) = health.free_bytes {
+                        free_bytes = Some(free_bytes.map_or(free, |f| f.min(free)));
+                    }
+                }
+                Err(e) => {
+                    err.get_or_insert(e);
+                }
+            }
+        }
+
+        match err {
+            Some(e) if healthy < self.needed() => Err(e),
+            _ => Ok(StorageHealth { free_bytes }),
+        }
+    }
+}
+
+/// Keeps nodes and the acl in each of several stores, like the children
+/// of a mirror, so losing one of them loses none of either.
+///
+/// Writes go to every store, and the first one that can answer decides
+/// whether they go through, so a conflict there is a conflict. Stores that
+/// missed a write, or were just replaced, are caught up by a resync. A
+/// delete one of them missed comes back with it.
+pub struct MirroredNodes {
+    nodes: Vec<Arc<dyn NodeStore + Send + Sync>>,
+    acls: Vec<Arc<dyn AclStore + Send + Sync>>,
+    // Stores a write has to reach
+    needed: usize,
+}
+
+impl MirroredNodes {
+    pub fn new(
+        nodes: Vec<Arc<dyn NodeStore + Send + Sync>>,
+        acls: Vec<Arc<dyn AclStore + Send + Sync>>,
+        needed: usize,
+    ) -> Self {
+        Self {
+            nodes,
+            acls,
+            needed,
+        }
+    }
+
+    /// Copies nodes over to any store missing them or behind on them, and
+    /// the acl to any store without one.
+    ///
+    /// Stores that can't be listed are left out, to be caught up by the
+    /// next resync. A node that's been changed differently in two stores
+    /// is left alone in both and counted as failed.
+    pub fn resync(&self) -> Result<Resynced, Error> {
+        let mut listed = vec![];
+        for (i, store) in self.nodes.iter().enumerate() {
+            match store.list() {
+                Ok(nodes) => listed.push((
+                    i,
+                    nodes
+                        .into_iter()
+                        .map(|n| (n.id.clone(), n))
+                        .collect::<HashMap<_, _>>(),
+                )),
+                Err(e) => tracing::warn!("skipping node store {} in resync: {}", i, e),
+            }
+        }
+        if listed.is_empty() {
+            return Err(Error::from_msg(
+                "no node stores could be listed",
+                Kind::Internal,
+            ));
+        }
+
+        // Where the latest revision of each node is
+        let mut latest: HashMap<&str, (usize, &Node)> = HashMap::new();
+        for (i, nodes) in &listed {
+            for (id, node) in nodes {
+                let entry = latest.entry(id).or_insert((*i, node));
+                if node.revision > entry.1.revision {
+                    *entry = (*i, node);
+                }
+            }
+        }
+
+        let mut resynced = Resynced::default();
+        for (id, (from, node)) in latest {
+            let behind: Vec<_> = listed
+                .iter()
+                .filter(|(_, nodes)| nodes.get(id).map_or(0, |n| n.revision) < node.revision)
+                .map(|(i, _)| *i)
+                .collect();
+            if behind.is_empty() {
+                continue;
+            }
+
+            let revisions = match self.nodes[from].revisions(id) {
+                Ok(revisions) => revisions,
+                Err(e) => {
+                    tracing::warn!("error reading node {} to resync: {}", id, e);
+                    resynced.failed += behind.len() as u64;
+                    continue;
+                }
+            };
+            for i in behind {
+                match copy_revisions(self.nodes[i].as_ref(), id, &revisions) {
+                    Ok(()) => resynced.copied += 1,
+                    Err(e) => {
+                        tracing::warn!("error resyncing node {} to store {}: {}", id, i, e);
+                        resynced.failed += 1;
+                    }
+                }
+            }
+        }
+
+        // Nothing says which of two different acls is newer, so only ones
+        // that are missing get filled in
+        let acls: Vec<_> = self.acls.iter().map(|store| store.get_acl()).collect();
+        if let Some(acl) = acls.iter().flatten().find(|acl| !is_empty(acl)) {
+            for (store, held) in self.acls.iter().zip(&acls) {
+                if !held.as_ref().is_ok_and(is_empty) {
+                    continue;
+                }
+                match store.put_acl(acl) {
+                    Ok(()) => resynced.copied += 1,
+                    Err(e) => {
+                        tracing::warn!("error resyncing the acl: {}", e);
+                        resynced.failed += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(resynced)
+    }
+
+    // Makes a write to every store. Until one of them takes it, one that
+    // turns it down for anything but being unhealthy or not having the
+    // node decides the answer.
+    fn write<F>(&self, what: &str, write: F) -> Result<(), Error>
+    where
+        F: Fn(&dyn NodeStore) -> Result<(), Error>,
+    {
+        let mut stored = 0;
+        let mut err = None;
+        for (i, store) in self.nodes.iter().enumerate() {
+            match write(store.as_ref()) {
+                Ok(()) => stored += 1,
+                Err(e) if stored == 0 && !matches!(e.kind, Kind::Internal | Kind::NotFound) => {
+                    return Err(e)
+                }
+                Err(e) => {
+                    tracing::warn!("error {} in node store {}: {}", what, i, e);
+                    err.get_or_insert(e);
+                }
+            }
+        }
+
+        match err {
+            Some(e) if stored < self.needed => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    // Reads from the first store that has what's asked for, since one
+    // could have just been replaced
+    fn read<T, F>(&self, what: &str, read: F) -> Result<T, Error>
+    where
+        F: Fn(&dyn NodeStore) -> Result<T, Error>,
+    {
+        let mut err = None;
+        for (i, store) in self.nodes.iter().enumerate() {
+            match read(store.as_ref()) {
+                Ok(found) => return Ok(found),
+                Err(e) if matches!(e.kind, Kind::NotFound) => {}
+                Err(e) => {
+                    tracing::warn!("error {} from node store {}: {}", what, i, e);
+                    err = Some(e);
+                }
+            }
+        }
+
+        Err(err.unwrap_or_else(|| Error::from_msg("node not found", Kind::NotFound)))
+    }
+}
+
+// Brings a store up to date with a node's revisions from another, as long
+// as their histories agree up to where the store is
+fn copy_revisions(store: &dyn NodeStore, id: &str, revisions: &[Node]) -> Result<(), Error> {
+    let held = match store.revisions(id) {
+        Ok(held) => held,
+        Err(e) if matches!(e.kind, Kind::NotFound) => vec![],
+        Err(e) => return Err(e),
+    };
+    let mut latest = held.last().map_or(0, |n| n.revision);
+    if let Some(ours) = held.last() {
+        if !revisions
+            .iter()
+            .any(|n| n.revision == latest && n.updated == ours.updated)
+        {
+            return Err(Error::from_msg(
+                &format!("node {} has diverged at revision {}", id, latest),
+                Kind::Conflict,
+            ));
+        }
+    }
+
+    let from = latest;
+    for node in revisions.iter().filter(|n| n.revision > from) {
+        if latest == 0 {
+            store.put(id, node)?;
+        } else {
+            store.update(id, latest, node)?;
+        }
+        latest = node.revision;
+    }
+
+    Ok(())
+}
+
+fn is_empty(acl: &Acl) -> bool {
+    acl.admins.is_empty() && acl.namespaces.is_empty()
+}
+
+impl NodeStore for MirroredNodes {
+    fn get(&self, id: &str) -> Result<Node, Error> {
+        self.read("reading node", |store| store.get(id))
+    }
+
+    fn put(&self, id: &str, node: &Node) -> Result<(), Error> {
+        self.write("storing node", |store| store.put(id, node))
+    }
+
+    fn update(&self, id: &str, expected_revision: u64, node: &Node) -> Result<(), Error> {
+        self.write("updating node", |store| {
+            store.update(id, expected_revision, node)
+        })
+    }
+
+    fn revisions(&self, id: &str) -> Result<Vec<Node>, Error> {
+        self.read("reading node revisions", |store| store.revisions(id))
+    }
+
+    // Stores that never had it are already done
+    fn delete(&self, id: &str) -> Result<(), Error> {
+        self.get(id)?;
+        self.write("deleting node", |store| match store.delete(id) {
+            Err(e) if matches!(e.kind, Kind::NotFound) => Ok(()),
+            deleted => deleted,
+        })
+    }
+
+    // The latest revision of each node any store has
+    fn list(&self) -> Result<Vec<Node>, Error> {
+        let mut all: HashMap<String, Node> = HashMap::new();
+        let mut listed = false;
+        for (i, store) in self.nodes.iter().enumerate() {
+            let nodes = match store.list() {
+                Ok(nodes) => nodes,
+                Err(e) => {
+                    tracing::warn!("error listing node store {}: {}", i, e);
+                    continue;
+                }
+            };
+            listed = true;
+            for node in nodes {
+                match all.get(&node.id) {
+                    Some(held) if held.revision >= node.revision => {}
+                    _ => {
+                        all.insert(node.id.clone(), node);
+                    }
+                }
+            }
+        }
+        if !listed {
+            return Err(Error::from_msg(
+                "no node stores could be listed",
+                Kind::Internal,
+            ));
+        }
+
+        Ok(all.into_values().collect())
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        let mut healthy = 0;
+        let mut err = None;
+        for store in &self.nodes {
+            match store.check() {
+                Ok(()) => healthy += 1,
+                Err(e) => {
+                    err.get_or_insert(e);
+                }
+            }
+        }
+
+        match err {
+            Some(e) if healthy < self.needed => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl AclStore for MirroredNodes {
+    // An empty acl is what a store that's never had one gives back, so
+    // one that isn't wins
+    fn get_acl(&self) -> Result<Acl, Error> {
+        let mut found = None;
+        let mut err = None;
+        for (i, store) in self.acls.iter().enumerate() {
+            match store.get_acl() {
+                Ok(acl) if !is_empty(&acl) => return Ok(acl),
+                Ok(acl) => found = Some(acl),
+                Err(e) => {
+                    tracing::warn!("error reading the acl from store {}: {}", i, e);
+                    err = Some(e);
+                }
+            }
+        }
+
+        match (found, err) {
+            (Some(acl), _) => Ok(acl),
+            (None, Some(e)) => Err(e),
+            (None, None) => Ok(Acl::default()),
+        }
+    }
+
+    fn put_acl(&self, acl: &Acl) -> Result<(), Error> {
+        let mut stored = 0;
+        let mut err = None;
+        for (i, store) in self.acls.iter().enumerate() {
+            match store.put_acl(acl) {
+                Ok(()) => stored += 1,
+                Err(e) => {
+                    tracing::warn!("error storing the acl in store {}: {}", i, e);
+                    err.get_or_insert(e);
+                }
+            }
+        }
+
+        match err {
+            Some(e) if stored < self.needed => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Memory;
+
+    fn mirror(policy: WritePolicy) -> (Vec<Arc<Memory>>, Mirror) {
+        let children: Vec<_> = (0..3).map(|_| Arc::new(Memory::new())).collect();
+        let mirror = Mirror::new(
+            children
+                .iter()
+                .map(|c| c.clone() as Arc<dyn Storage + Send + Sync>)
+                .collect(),
+            policy,
+        );
+        (children, mirror)
+    }
+
+    #[test]
+    fn writes_by_policy() {
+        let id = BlobRef::of(b"data");
+
+        let (children, all) = mirror(WritePolicy::All);
+        children[1].fail_nth_put(1);
+        assert!(all.put(&id, b"data".to_vec()).is_err());
+
+        let (children, quorum) = mirror(WritePolicy::Quorum);
+        children[1].fail_nth_put(1);
+        quorum.put(&id, b"data".to_vec()).unwrap();
+        assert!(!children[1].contains(&id).unwrap());
+
+        children[0].fail_nth_put(1);
+        children[2].fail_nth_put(1);
+        assert!(quorum.put(&BlobRef::of(b"more"), b"more".to_vec()).is_err());
+    }
+
+    #[test]
+    fn repairs_on_read_and_resync() {
+        let (children, mirror) = mirror(WritePolicy::All);
+        let one = BlobRef::of(b"one");
+        let two = BlobRef::of(b"two");
+        mirror.put(&one, b"one".to_vec()).unwrap();
+        Storage::put(children[2].as_ref(), &two, b"two".to_vec()).unwrap();
+
+        // The first copy is bad, so it's replaced with the second
+        children[0].corrupt(&one);
+        assert_eq!(mirror.get(&one).unwrap(), b"one");
+        assert_eq!(Storage::get(children[0].as_ref(), &one).unwrap(), b"one");
+
+        assert_eq!(mirror.get(&two).unwrap(), b"two");
+        assert!(children.iter().all(|c| c.contains(&two).unwrap()));
+
+        // As if the last child was a disk that's just been replaced
+        let three = BlobRef::of(b"three");
+        Storage::put(children[0].as_ref(), &three, b"three".to_vec()).unwrap();
+        let resynced = mirror.resync().unwrap();
+        assert_eq!((resynced.copied, resynced.failed), (2, 0));
+        assert!(children.iter().all(|c| c.contains(&three).unwrap()));
+        assert_eq!(mirror.stats().unwrap().blobs, 3);
+
+        // Nothing good left to read is corruption, not a missing blob
+        for child in &children {
+            child.corrupt(&three);
+        }
+        assert!(matches!(mirror.get(&three), Err(StorageError::IO(_))));
+    }
+
+    fn node(revision: u64) -> Node {
+        Node {
+            id: String::from("abc"),
+            namespace: String::from(crate::DEFAULT_NAMESPACE),
+            node_type: crate::NodeType::File,
+            blobs: vec![],
+            attributes: Default::default(),
+            revision,
+            updated: revision,
+            author: None,
+        }
+    }
+
+    fn mirrored_nodes(children: &[Arc<Memory>]) -> MirroredNodes {
+        MirroredNodes::new(
+            children
+                .iter()
+                .map(|c| c.clone() as Arc<dyn NodeStore + Send + Sync>)
+                .collect(),
+            children
+                .iter()
+                .map(|c| c.clone() as Arc<dyn AclStore + Send + Sync>)
+                .collect(),
+            WritePolicy::All.needed(children.len()),
+        )
+    }
+
+    #[test]
+    fn mirrors_nodes_and_the_acl() {
+        let mut children: Vec<_> = (0..2).map(|_| Arc::new(Memory::new())).collect();
+        let nodes = mirrored_nodes(&children);
+        NodeStore::put(&nodes, "abc", &node(1)).unwrap();
+        nodes.update("abc", 1, &node(2)).unwrap();
+        let err = nodes.update("abc", 1, &node(2)).unwrap_err();
+        assert!(matches!(err.kind, Kind::Conflict));
+        let acl = Acl {
+            admins: vec![String::from("admin")],
+            ..Default::default()
+        };
+        nodes.put_acl(&acl).unwrap();
+        for child in &children {
+            assert_eq!(NodeStore::get(child.as_ref(), "abc").unwrap().revision, 2);
+            assert_eq!(child.get_acl().unwrap().admins, acl.admins);
+        }
+
+        // The first is swapped for an empty one, and the other still has
+        // everything
+        children[0] = Arc::new(Memory::new());
+        let nodes = mirrored_nodes(&children);
+        assert_eq!(NodeStore::get(&nodes, "abc").unwrap().revision, 2);
+        assert_eq!(nodes.revisions("abc").unwrap().len(), 2);
+        assert_eq!(nodes.list().unwrap().len(), 1);
+        assert_eq!(nodes.get_acl().unwrap().admins, acl.admins);
+
+        let resynced = nodes.resync().unwrap();
+        assert_eq!((resynced.copied, resynced.failed), (2, 0));
+        assert_eq!(children[0].revisions("abc").unwrap().len(), 2);
+        assert_eq!(children[0].get_acl().unwrap().admins, acl.admins);
+
+        // One that's gone its own way is left alone
+        let mut diverged = node(3);
+        diverged.updated = 99;
+        children[0].update("abc", 2, &node(3)).unwrap();
+        children[1].update("abc", 2, &diverged).unwrap();
+        children[0].update("abc", 3, &node(4)).unwrap();
+        let resynced = nodes.resync().unwrap();
+        assert_eq!((resynced.copied, resynced.failed), (0, 1));
+
+        NodeStore::delete(&nodes, "abc").unwrap();
+        assert!(children
+            .iter()
+            .all(|c| NodeStore::get(c.as_ref(), "abc").is_err()));
+        let err = NodeStore::delete(&nodes, "abc").unwrap_err();
+        assert!(matches!(err.kind, Kind::NotFound));
+    }
+}
@@ -0,0 +1,666 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+
+use super::codec;
+use crate::{BlobRef, StorageError, StorageHealth, StorageStats};
+
+// Blobs are appended to pack files, named by a counting number, until one
+// gets too big and the next is started. Each record in a pack is:
+//
+//   kind (u8) | id length (u8) | id | data length (u64 le) | data
+//
+// where the data is the blob as encoded by the codec. Deleting a blob
+// appends a tombstone record with no data, so the packs alone are enough
+// to rebuild the index.
+const PACK_PREFIX: &str = "pack-";
+const BLOB_RECORD: u8 = 0;
+const TOMBSTONE_RECORD: u8 = 1;
+
+// Where each blob is, one json line per record appended. The last line for
+// an id wins.
+const INDEX_FILE: &str = "index";
+
+fn pack_name(pack: u32) -> String {
+    format!("{}{:08}", PACK_PREFIX, pack)
+}
+
+/// A line of the index.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    id: BlobRef,
+    pack: u32,
+    // Where the record's data starts in the pack
+    offset: u64,
+    // How long the stored data is
+    len: u64,
+    // How big the blob was before it was encoded
+    size: u64,
+    #[serde(default)]
+    deleted: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    pack: u32,
+    offset: u64,
+    len: u64,
+    size: u64,
+}
+
+struct State {
+    index: HashMap<BlobRef, Location>,
+    // The pack being appended to, and how long it is
+    current: u32,
+    current_len: u64,
+    // Bytes of records in each pack that nothing points at any more, from
+    // blobs that were deleted or rewritten and the tombstones themselves
+    dead: HashMap<u32, u64>,
+}
+
+impl State {
+    // Adds what an index entry says to the index, counting the record it
+    // replaces as dead
+    fn apply(&mut self, entry: IndexEntry) {
+        let replaced = if entry.deleted {
+            *self.dead.entry(entry.pack).or_default() += record_len(&entry.id, entry.len);
+            self.index.remove(&entry.id)
+        } else {
+            self.index.insert(
+                entry.id.clone(),
+                Location {
+                    pack: entry.pack,
+                    offset: entry.offset,
+                    len: entry.len,
+                    size: entry.size,
+                },
+            )
+        };
+
+        if let Some(old) = replaced {
+            *self.dead.entry(old.pack).or_default() += record_len(&entry.id, old.len);
+        }
+    }
+}
+
+// How many bytes a record takes up in its pack
+fn record_len(id: &BlobRef, len: u64) -> u64 {
+    2 + id.as_str().len() as u64 + 8 + len
+}
+
+/// What a compaction did.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Compacted {
+    pub packs_removed: usize,
+    pub bytes_reclaimed: u64,
+}
+
+/// Stores blobs appended into a few big pack files instead of a file each,
+/// so millions of small blobs don't mean millions of files.
+pub struct Pack {
+    directory: PathBuf,
+    // A pack stops being appended to once it's at least this big
+    max_pack_bytes: u64,
+    // Reads share it, while puts, deletes and compactions get it to
+    // themselves
+    state: RwLock<State>,
+}
+
+impl Pack {
+    /// Opens the packs in a directory, creating it first if asked to.
+    ///
+    /// The index is rebuilt from the packs if it's missing, and anything
+    /// appended to the last pack that didn't make it into the index is
+    /// picked back up.
+    pub fn open(directory: &str, create: bool) -> Result<Self, StorageError> {
+        let directory = PathBuf::from(directory);
+        if create {
+            std::fs::create_dir_all(&directory)?;
+        }
+        if !directory.is_dir() {
+            return Err(StorageError::IO(format!(
+                "{} is not a directory",
+                directory.display()
+            )));
+        }
+
+        let pack = Self {
+            directory,
+            max_pack_bytes: 256 * 1024 * 1024,
+            state: RwLock::new(State {
+                index: HashMap::new(),
+                current: 1,
+                current_len: 0,
+                dead: HashMap::new(),
+            }),
+        };
+
+        if pack.directory.join(INDEX_FILE).exists() {
+            pack.load_index()?;
+        } else {
+            pack.rebuild_index()?;
+        }
+
+        Ok(pack)
+    }
+
+    /// Sets how big a pack gets before the next one's started.
+    pub fn with_max_pack_bytes(mut self, max_pack_bytes: u64) -> Self {
+        self.max_pack_bytes = max_pack_bytes;
+        self
+    }
+
+    // The numbers of every pack in the directory, in order
+    fn packs(&self) -> Result<Vec<u32>, StorageError> {
+        let mut packs = vec![];
+        for entry in std::fs::read_dir(&self.directory)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(Ok(n)) = name.strip_prefix(PACK_PREFIX).map(str::parse) {
+                packs.push(n);
+            }
+        }
+        packs.sort();
+
+        Ok(packs)
+    }
+
+    fn load_index(&self) -> Result<(), StorageError> {
+        let mut state = self.state.write().unwrap();
+        let f = File::open(self.directory.join(INDEX_FILE))?;
+
+        // How far into each pack the index got
+        let mut indexed: HashMap<u32, u64> = HashMap::new();
+        for line in BufReader::new(f).lines() {
+            let line = line?;
+            // A line cut off by a crash is picked back up from the pack
+            let Ok(entry) = serde_json::from_str::<IndexEntry>(&line) else {
+                continue;
+            };
+            let end = indexed.entry(entry.pack).or_default();
+            *end = (*end).max(entry.offset + entry.len);
+            state.apply(entry);
+        }
+
+        let Some(&last) = self.packs()?.last() else {
+            return Ok(());
+        };
+        state.current = last;
+        let from = indexed.get(&last).copied().unwrap_or(0);
+        let (entries, len) = self.scan(last, from)?;
+        for entry in entries {
+            self.append_index(&entry)?;
+            state.apply(entry);
+        }
+        state.current_len = len;
+
+        Ok(())
+    }
+
+    /// Throws away the index and builds it again by reading every pack.
+    pub fn rebuild_index(&self) -> Result<(), StorageError> {
+        let mut state = self.state.write().unwrap();
+        state.index.clear();
+        state.dead.clear();
+        state.current = 1;
+        state.current_len = 0;
+
+        let mut lines = vec![];
+        for pack in self.packs()? {
+            let (entries, len) = self.scan(pack, 0)?;
+            for entry in entries {
+                lines.push(entry.clone());
+                state.apply(entry);
+            }
+            state.current = pack;
+            state.current_len = len;
+        }
+
+        self.write_index(&lines)
+    }
+
+    // Reads a pack's records from an offset, returning them and where the
+    // last whole record ends
+    fn scan(&self, pack: u32, from: u64) -> Result<(Vec<IndexEntry>, u64), StorageError> {
+        let mut f = BufReader::new(File::open(self.directory.join(pack_name(pack)))?);
+        f.seek(SeekFrom::Start(from))?;
+
+        let mut entries = vec![];
+        let mut at = from;
+        loop {
+            let mut head = [0; 2];
+            match f.read_exact(&mut head) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            let [kind, id_len] = head;
+
+            let mut id = vec![0; id_len as usize];
+            let mut len = [0; 8];
+            if f.read_exact(&mut id).is_err() || f.read_exact(&mut len).is_err() {
+                // Cut off part way through a record
+                break;
+            }
+            let len = u64::from_le_bytes(len);
+            let offset = at + 2 + id_len as u64 + 8;
+
+            let id = BlobRef::parse(&String::from_utf8_lossy(&id)).map_err(|e| {
+                StorageError::IO(format!("bad id in {}: {}", pack_name(pack), e.message))
+            })?;
+            let mut data = vec![];
+            (&mut f).take(len).read_to_end(&mut data)?;
+            if (data.len() as u64) < len {
+                break;
+            }
+            let size = match codec::header(&data)? {
+                Some((_, size)) => size,
+                None => len,
+            };
+
+            entries.push(IndexEntry {
+                id,
+                pack,
+                offset,
+                len,
+                size,
+                deleted: kind == TOMBSTONE_RECORD,
+            });
+            at = offset + len;
+        }
+
+        // Anything after the last whole record is from a write that didn't
+        // finish, and would throw off where the next one goes
+        let f = f.into_inner();
+        if f.metadata()?.len() > at {
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(self.directory.join(pack_name(pack)))?
+                .set_len(at)?;
+        }
+
+        Ok((entries, at))
+    }
+
+    fn append_index(&self, entry: &IndexEntry) -> Result<(), StorageError> {
+        let mut line = serde_json::to_vec(entry)
+            .map_err(|e| StorageError::IO(format!("error encoding index: {}", e)))?;
+        line.push(b'\n');
+
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.directory.join(INDEX_FILE))?
+            .write_all(&line)?;
+
+        Ok(())
+    }
+
+    // Replaces the whole index at once
+    fn write_index(&self, entries: &[IndexEntry]) -> Result<(), StorageError> {
+        let mut contents = vec![];
+        for entry in entries {
+            serde_json::to_writer(&mut contents, entry)
+                .map_err(|e| StorageError::IO(format!("error encoding index: {}", e)))?;
+            contents.push(b'\n');
+        }
+
+        let tmp = self.directory.join(format!(".tmp-{}", INDEX_FILE));
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(tmp, self.directory.join(INDEX_FILE))?;
+
+        Ok(())
+    }
+
+    // Appends a record to the current pack, starting a new one if it's full
+    fn append(
+        &self,
+        state: &mut State,
+        kind: u8,
+        id: &BlobRef,
+        data: &[u8],
+        size: u64,
+    ) -> Result<IndexEntry, StorageError> {
+        let id_bytes = id.as_str().as_bytes();
+        let mut record = Vec::with_capacity(2 + id_bytes.len() + 8 + data.len());
+        record.push(kind);
+        record.push(id_bytes.len() as u8);
+        record.extend_from_slice(id_bytes);
+        record.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        record.extend_from_slice(data);
+
+        if state.current_len > 0 && state.current_len + record.len() as u64 > self.max_pack_bytes {
+            state.current += 1;
+            state.current_len = 0;
+        }
+
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.directory.join(pack_name(state.current)))?
+            .write_all(&record)?;
+
+        let entry = IndexEntry {
+            id: id.clone(),
+            pack: state.current,
+            offset: state.current_len + (record.len() - data.len()) as u64,
+            len: data.len() as u64,
+            size,
+            deleted: kind == TOMBSTONE_RECORD,
+        };
+        state.current_len += record.len() as u64;
+        self.append_index(&entry)?;
+
+        Ok(entry)
+    }
+
+    /// Forgets a blob. Its space is only given back by compacting.
+    pub fn delete(&self, id: &BlobRef) -> Result<(), StorageError> {
+        let mut state = self.state.write().unwrap();
+        if !state.index.contains_key(id) {
+            return Err(StorageError::NotFound);
+        }
+
+        let entry = self.append(&mut state, TOMBSTONE_RECORD, id, &[], 0)?;
+        state.apply(entry);
+
+        Ok(())
+    }
+
+    /// Rewrites the packs with dead records in them, keeping only the blobs
+    /// still in the index. Packs with nothing dead are left alone.
+    pub fn compact(&self) -> Result<Compacted, StorageError> {
+        let mut state = self.state.write().unwrap();
+        let mut dirty: Vec<u32> = state
+            .dead
+            .iter()
+            .filter(|(_, dead)| **dead > 0)
+            .map(|(pack, _)| *pack)
+            .collect();
+        if dirty.is_empty() {
+            return Ok(Compacted::default());
+        }
+        dirty.sort();
+        let before: u64 = dirty
+            .iter()
+            .map(|p| std::fs::metadata(self.directory.join(pack_name(*p))).map(|m| m.len()))
+            .sum::<Result<_, _>>()?;
+
+        // Copied into packs numbered after every old one, so the old ones
+        // stay good until the new index is in place
+        let mut live: Vec<(BlobRef, Location)> = state
+            .index
+            .iter()
+            .filter(|(_, l)| dirty.contains(&l.pack))
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        live.sort_by_key(|(_, l)| (l.pack, l.offset));
+        state.current = self.packs()?.last().map_or(1, |p| p + 1);
+        state.current_len = 0;
+
+        let mut after = 0;
+        for (id, location) in live {
+            let data = self.read(&location)?;
+            let entry = self.append(&mut state, BLOB_RECORD, &id, &data, location.size)?;
+            after += record_len(&id, entry.len);
+            state.apply(entry);
+        }
+        for pack in &dirty {
+            state.dead.remove(pack);
+        }
+
+        let mut entries: Vec<IndexEntry> = state
+            .index
+            .iter()
+            .map(|(id, l)| IndexEntry {
+                id: id.clone(),
+                pack: l.pack,
+                offset: l.offset,
+                len: l.len,
+                size: l.size,
+                deleted: false,
+            })
+            .collect();
+        entries.sort_by_key(|e| (e.pack, e.offset));
+        self.write_index(&entries)?;
+
+        // Oldest first, so a tombstone is never left without the pack
+        // holding the blob it deletes
+        for pack in &dirty {
+            std::fs::remove_file(self.directory.join(pack_name(*pack)))?;
+        }
+
+        Ok(Compacted {
+            packs_removed: dirty.len(),
+            bytes_reclaimed: before.saturating_sub(after),
+        })
+    }
+
+    // The stored data at a location, still encoded
+    fn read(&self, location: &Location) -> Result<Vec<u8>, StorageError> {
+        let mut f = File::open(self.directory.join(pack_name(location.pack)))?;
+        f.seek(SeekFrom::Start(location.offset))?;
+        let mut data = vec![0; location.len as usize];
+        f.read_exact(&mut data)?;
+
+        Ok(data)
+    }
+}
+
+impl crate::Storage for Pack {
+    fn get(&self, hash: &BlobRef) -> Result<Vec<u8>, StorageError> {
+        let state = self.state.read().unwrap();
+        let location = state.index.get(hash).ok_or(StorageError::NotFound)?;
+
+        codec::decode(self.read(location)?)
+    }
+
+    fn put(&self, hash: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        let mut state = self.state.write().unwrap();
+        if state.index.contains_key(hash) {
+            return Ok(());
+        }
+
+        let encoded = codec::encode(&data);
+        let entry = self.append(&mut state, BLOB_RECORD, hash, &encoded, data.len() as u64)?;
+        state.apply(entry);
+
+        Ok(())
+    }
+
+    // The bad copy is left behind in its pack until a compaction
+    fn repair(&self, hash: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        let mut state = self.state.write().unwrap();
+        let encoded = codec::encode(&data);
+        let entry = self.append(&mut state, BLOB_RECORD, hash, &encoded, data.len() as u64)?;
+        state.apply(entry);
+
+        Ok(())
+    }
+
+    fn contains(&self, hash: &BlobRef) -> Result<bool, StorageError> {
+        Ok(self.state.read().unwrap().index.contains_key(hash))
+    }
+
+    fn list_blobs(&self) -> Result<Vec<BlobRef>, StorageError> {
+        Ok(self.state.read().unwrap().index.keys().cloned().collect())
+    }
+
+    fn stats(&self) -> Result<StorageStats, StorageError> {
+        let state = self.state.read().unwrap();
+        let mut stats = StorageStats {
+            blobs: state.index.len() as u64,
+            bytes: state.index.values().map(|l| l.size).sum(),
+            ..Default::default()
+        };
+        // Deleted blobs take up space until a compaction
+        for pack in self.packs()? {
+            stats.physical_bytes += std::fs::metadata(self.directory.join(pack_name(pack)))?.len();
+        }
+
+        Ok(stats)
+    }
+
+    fn check(&self) -> Result<StorageHealth, StorageError> {
+        if !Path::new(&self.directory).is_dir() {
+            return Err(StorageError::IO(format!(
+                "{} is not a directory",
+                self.directory.display()
+            )));
+        }
+
+        Ok(StorageHealth {
+            free_bytes: Some(fs2::available_space(&self.directory)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Storage;
+
+    fn blob(n: usize) -> (BlobRef, Vec<u8>) {
+        let data = format!("blob number {}", n).repeat(n + 1).into_bytes();
+        (BlobRef::of(&data), data)
+    }
+
+    #[test]
+    fn spreads_blobs_across_packs() {
+        let dir = tempfile::tempdir().unwrap();
+        let pack = Pack::open(dir.path().to_str().unwrap(), false)
+            .unwrap()
+            .with_max_pack_bytes(1024);
+
+        for n in 0..50 {
+            let (id, data) = blob(n);
+            pack.put(&id, data).unwrap();
+        }
+        assert!(pack.packs().unwrap().len() > 1);
+
+        for n in 0..50 {
+            let (id, data) = blob(n);
+            assert_eq!(pack.get(&id).unwrap(), data);
+        }
+        let stats = pack.stats().unwrap();
+        assert_eq!(stats.blobs, 50);
+        assert!(stats.physical_bytes < stats.bytes);
+    }
+
+    #[test]
+    fn rebuilds_index_from_packs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let pack = Pack::open(path, false).unwrap();
+        let (kept, data) = blob(1);
+        pack.put(&kept, data.clone()).unwrap();
+        let (deleted, other) = blob(2);
+        pack.put(&deleted, other).unwrap();
+        pack.delete(&deleted).unwrap();
+        drop(pack);
+
+        std::fs::remove_file(dir.path().join(INDEX_FILE)).unwrap();
+        let pack = Pack::open(path, false).unwrap();
+        assert_eq!(pack.get(&kept).unwrap(), data);
+        assert!(!pack.contains(&deleted).unwrap());
+
+        // Records the index never heard about are picked up too
+        let (late, late_data) = blob(3);
+        pack.put(&late, late_data.clone()).unwrap();
+        drop(pack);
+        let index = std::fs::read_to_string(dir.path().join(INDEX_FILE)).unwrap();
+        let without_late: String = index.lines().take(2).map(|l| format!("{}\n", l)).collect();
+        std::fs::write(dir.path().join(INDEX_FILE), without_late).unwrap();
+
+        // Along with a write that was cut off
+        let last = dir.path().join(pack_name(1));
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&last)
+            .unwrap();
+        f.write_all(&[BLOB_RECORD, 200, b's']).unwrap();
+        drop(f);
+
+        let pack = Pack::open(path, false).unwrap();
+        assert_eq!(pack.get(&late).unwrap(), late_data);
+        assert!(!pack.contains(&deleted).unwrap());
+        let (after, after_data) = blob(4);
+        pack.put(&after, after_data.clone()).unwrap();
+        assert_eq!(pack.get(&after).unwrap(), after_data);
+    }
+
+    #[test]
+    fn compacts_away_deleted_blobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let pack = Pack::open(dir.path().to_str().unwrap(), false)
+            .unwrap()
+            .with_max_pack_bytes(1024);
+        for n in 0..20 {
+            let (id, data) = blob(n);
+            pack.put(&id, data).unwrap();
+        }
+        for n in 0..10 {
+            pack.delete(&blob(n).0).unwrap();
+        }
+        assert!(matches!(
+            pack.delete(&blob(0).0),
+            Err(StorageError::NotFound)
+        ));
+
+        let compacted = pack.compact().unwrap();
+        assert!(compacted.bytes_reclaimed > 0);
+        for n in 0..20 {
+            let (id, data) = blob(n);
+            if n < 10 {
+                assert!(!pack.contains(&id).unwrap());
+            } else {
+                assert_eq!(pack.get(&id).unwrap(), data);
+            }
+        }
+
+        // Still right after starting over from the packs
+        pack.rebuild_index().unwrap();
+        assert_eq!(pack.stats().unwrap().blobs, 10);
+        assert_eq!(pack.compact().unwrap(), Compacted::default());
+    }
+
+    #[test]
+    fn only_compacts_packs_with_dead_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let pack = Pack::open(path, false).unwrap().with_max_pack_bytes(1024);
+        for n in 0..20 {
+            let (id, data) = blob(n);
+            pack.put(&id, data).unwrap();
+        }
+        let packs = pack.packs().unwrap();
+        assert!(packs.len() > 2);
+
+        // Only the first pack has anything dead in it, and that's still
+        // known after reopening
+        pack.delete(&blob(0).0).unwrap();
+        let tombstones = *pack.packs().unwrap().last().unwrap();
+        drop(pack);
+        let pack = Pack::open(path, false).unwrap().with_max_pack_bytes(1024);
+
+        let compacted = pack.compact().unwrap();
+        assert_eq!(compacted.packs_removed, 2);
+        let remaining = pack.packs().unwrap();
+        assert!(!remaining.contains(&packs[0]));
+        assert!(!remaining.contains(&tombstones));
+        for untouched in &packs[1..packs.len() - 1] {
+            assert!(remaining.contains(untouched));
+        }
+
+        for n in 1..20 {
+            let (id, data) = blob(n);
+            assert_eq!(pack.get(&id).unwrap(), data);
+        }
+        pack.rebuild_index().unwrap();
+        assert!(!pack.contains(&blob(0).0).unwrap());
+        assert_eq!(pack.stats().unwrap().blobs, 19);
+    }
+}
@@ -0,0 +1,453 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, TransactionBehavior};
+
+use super::codec;
+use crate::error::{Error, Kind, WithKind};
+use crate::{Acl, BlobRef, Node, StorageError, StorageHealth, StorageStats};
+
+// Everything in one sqlite database, which makes backing it up a matter of
+// copying a file.
+//
+// Blobs are stored encoded by the codec, like in the other stores. Nodes
+// keep their latest revision in `nodes` and every revision in `revisions`.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS blobs (
+    id TEXT PRIMARY KEY,
+    data BLOB NOT NULL,
+    size INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS nodes (
+    id TEXT PRIMARY KEY,
+    revision INTEGER NOT NULL,
+    node TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS revisions (
+    id TEXT NOT NULL,
+    revision INTEGER NOT NULL,
+    node TEXT NOT NULL,
+    PRIMARY KEY (id, revision)
+);
+CREATE TABLE IF NOT EXISTS acl (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    acl TEXT NOT NULL
+);
+";
+
+// How many connections reads are spread over
+const READERS: usize = 4;
+
+/// A store that keeps blobs, nodes and the acl in a single sqlite file.
+pub struct Sqlite {
+    // Writes are serialized by sqlite anyway, so they share one connection
+    writer: Mutex<Connection>,
+    // Reads get their own, so they don't wait on writes or each other
+    readers: Vec<Mutex<Connection>>,
+    next_reader: AtomicUsize,
+}
+
+impl Sqlite {
+    /// Opens the database, creating it and its tables if they're not there.
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        let writer = Connection::open(path)?;
+        // Readers don't block the writer, and a crash mid-put leaves the
+        // last committed state behind
+        writer.pragma_update(None, "journal_mode", "WAL")?;
+        writer.pragma_update(None, "synchronous", "NORMAL")?;
+        writer.busy_timeout(std::time::Duration::from_secs(5))?;
+        writer.execute_batch(SCHEMA)?;
+
+        let mut readers = vec![];
+        for _ in 0..READERS {
+            let reader = Connection::open_with_flags(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?;
+            reader.busy_timeout(std::time::Duration::from_secs(5))?;
+            readers.push(Mutex::new(reader));
+        }
+
+        Ok(Self {
+            writer: Mutex::new(writer),
+            readers,
+            next_reader: AtomicUsize::new(0),
+        })
+    }
+
+    // Whichever reader's free, or the next one in turn if they're all busy
+    fn reader(&self) -> MutexGuard<'_, Connection> {
+        for reader in &self.readers {
+            if let Ok(conn) = reader.try_lock() {
+                return conn;
+            }
+        }
+
+        let next = self.next_reader.fetch_add(1, Ordering::Relaxed) % self.readers.len();
+        self.readers[next].lock().unwrap()
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(value: rusqlite::Error) -> Self {
+        Self::IO(value.to_string())
+    }
+}
+
+fn encode_node(node: &Node) -> Result<String, Error> {
+    serde_json::to_string(node).with_kind("error encoding node", Kind::Internal)
+}
+
+fn decode_node(json: &str) -> Result<Node, Error> {
+    serde_json::from_str(json).with_kind("error decoding node", Kind::Internal)
+}
+
+impl crate::Storage for Sqlite {
+    fn get(&self, hash: &BlobRef) -> Result<Vec<u8>, StorageError> {
+        let conn = self.reader();
+        let data: Option<Vec<u8>> = conn
+            .query_row(
+                "SELECT data FROM blobs WHERE id = ?1",
+                params![hash.as_str()],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        codec::decode(data.ok_or(StorageError::NotFound)?)
+    }
+
+    fn put(&self, hash: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        let encoded = codec::encode(&data);
+        let conn = self.writer.lock().unwrap();
+        conn.execute(
+            "INSERT OR IGNORE INTO blobs (id, data, size) VALUES (?1, ?2, ?3)",
+            params![hash.as_str(), encoded, data.len() as i64],
+        )?;
+
+        Ok(())
+    }
+
+    fn repair(&self, hash: &BlobRef, data: Vec<u8>) -> Result<(), StorageError> {
+        let encoded = codec::encode(&data);
+        let conn = self.writer.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO blobs (id, data, size) VALUES (?1, ?2, ?3)",
+            params![hash.as_str(), encoded, data.len() as i64],
+        )?;
+
+        Ok(())
+    }
+
+    fn contains(&self, hash: &BlobRef) -> Result<bool, StorageError> {
+        let conn = self.reader();
+        let found = conn
+            .query_row(
+                "SELECT 1 FROM blobs WHERE id = ?1",
+                params![hash.as_str()],
+                |_| Ok(()),
+            )
+            .optional()?;
+
+        Ok(found.is_some())
+    }
+
+    fn list_blobs(&self) -> Result<Vec<BlobRef>, StorageError> {
+        let conn = self.reader();
+        let mut stmt = conn.prepare("SELECT id FROM blobs")?;
+        let ids = stmt.query_map([], |row| row.get::<_, String>(0))?;
+
+        let mut blobs = vec![];
+        for id in ids {
+            blobs.push(BlobRef::parse(&id?).map_err(|e| StorageError::IO(e.to_string()))?);
+        }
+
+        Ok(blobs)
+    }
+
+    fn stats(&self) -> Result<StorageStats, StorageError> {
+        let conn = self.reader();
+        let (blobs, bytes, physical_bytes): (i64, i64, i64) = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0), COALESCE(SUM(LENGTH(data)), 0) FROM blobs",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+
+        Ok(StorageStats {
+            blobs: blobs as u64,
+            bytes: bytes as u64,
+            physical_bytes: physical_bytes as u64,
+            ..Default::default()
+        })
+    }
+
+    fn check(&self) -> Result<StorageHealth, StorageError> {
+        let conn = self.reader();
+        conn.query_row("SELECT 1", [], |_| Ok(()))?;
+
+        let free_bytes = conn
+            .path()
+            .and_then(|p| Path::new(p).parent())
+            .and_then(|dir| fs2::available_space(dir).ok());
+
+        Ok(StorageHealth { free_bytes })
+    }
+}
+
+impl crate::NodeStore for Sqlite {
+    fn get(&self, hash: &str) -> Result<Node, Error> {
+        let conn = self.reader();
+        let json: Option<String> = conn
+            .query_row(
+                "SELECT node FROM nodes WHERE id = ?1",
+                params![hash],
+                |row| row.get(0),
+            )
+            .optional()
+            .with_kind("error finding node", Kind::Internal)?;
+
+        match json {
+            Some(json) => decode_node(&json),
+            None => Err(Error::from_msg("node not found", Kind::NotFound)),
+        }
+    }
+
+    fn put(&self, hash: &str, node: &Node) -> Result<(), Error> {
+        let json = encode_node(node)?;
+        let mut conn = self.writer.lock().unwrap();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .with_kind("error starting transaction", Kind::Internal)?;
+
+        // Like the other stores, a node that's already there is left alone
+        let inserted = tx
+            .execute(
+                "INSERT OR IGNORE INTO nodes (id, revision, node) VALUES (?1, ?2, ?3)",
+                params![hash, node.revision as i64, json],
+            )
+            .with_kind("error writing node", Kind::Internal)?;
+        if inserted > 0 {
+            tx.execute(
+                "INSERT INTO revisions (id, revision, node) VALUES (?1, ?2, ?3)",
+                params![hash, node.revision as i64, json],
+            )
+            .with_kind("error writing revision", Kind::Internal)?;
+        }
+
+        tx.commit()
+            .with_kind("error committing node", Kind::Internal)
+    }
+
+    fn update(&self, hash: &str, expected_revision: u64, node: &Node) -> Result<(), Error> {
+        let json = encode_node(node)?;
+        let mut conn = self.writer.lock().unwrap();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .with_kind("error starting transaction", Kind::Internal)?;
+
+        let revision: Option<i64> = tx
+            .query_row(
+                "SELECT revision FROM nodes WHERE id = ?1",
+                params![hash],
+                |row| row.get(0),
+            )
+            .optional()
+            .with_kind("error finding node", Kind::Internal)?;
+        let Some(revision) = revision else {
+            return Err(Error::from_msg("node not found", Kind::NotFound));
+        };
+        if revision as u64 != expected_revision {
+            return Err(Error::from_msg(
+                &format!(
+                    "node is at revision {}, not {}",
+                    revision, expected_revision
+                ),
+                Kind::Conflict,
+            ));
+        }
+
+        tx.execute(
+            "UPDATE nodes SET revision = ?2, node = ?3 WHERE id = ?1",
+            params![hash, node.revision as i64, json],
+        )
+        .with_kind("error writing node", Kind::Internal)?;
+        tx.execute(
+            "INSERT INTO revisions (id, revision, node) VALUES (?1, ?2, ?3)",
+            params![hash, node.revision as i64, json],
+        )
+        .with_kind("error writing revision", Kind::Internal)?;
+
+        tx.commit()
+            .with_kind("error committing node", Kind::Internal)
+    }
+
+    fn revisions(&self, hash: &str) -> Result<Vec<Node>, Error> {
+        let conn = self.reader();
+        let mut stmt = conn
+            .prepare("SELECT node FROM revisions WHERE id = ?1 ORDER BY revision")
+            .with_kind("error reading revisions", Kind::Internal)?;
+        let revisions = stmt
+            .query_map(params![hash], |row| row.get::<_, String>(0))
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .with_kind("error reading revisions", Kind::Internal)?;
+        if revisions.is_empty() {
+            return Err(Error::from_msg("node not found", Kind::NotFound));
+        }
+
+        revisions.iter().map(|json| decode_node(json)).collect()
+    }
+
+    fn delete(&self, hash: &str) -> Result<(), Error> {
+        let mut conn = self.writer.lock().unwrap();
+        let tx = conn
+            .transaction()
+            .with_kind("error starting transaction", Kind::Internal)?;
+
+        let deleted = tx
+            .execute("DELETE FROM nodes WHERE id = ?1", params![hash])
+            .with_kind("error deleting node", Kind::Internal)?;
+        if deleted == 0 {
+            return Err(Error::from_msg("node not found", Kind::NotFound));
+        }
+        tx.execute("DELETE FROM revisions WHERE id = ?1", params![hash])
+            .with_kind("error deleting revisions", Kind::Internal)?;
+
+        tx.commit()
+            .with_kind("error committing delete", Kind::Internal)
+    }
+
+    fn list(&self) -> Result<Vec<Node>, Error> {
+        let conn = self.reader();
+        let mut stmt = conn
+            .prepare("SELECT node FROM nodes")
+            .with_kind("error listing nodes", Kind::Internal)?;
+        let nodes = stmt
+            .query_map([], |row| row.get::<_, String>(0))
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .with_kind("error listing nodes", Kind::Internal)?;
+
+        Ok(nodes
+            .iter()
+            .filter_map(|json| match decode_node(json) {
+                Ok(node) => Some(node),
+                Err(e) => {
+                    tracing::warn!("skipping node in listing: {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        crate::Storage::check(self)
+            .map(|_| ())
+            .with_kind("node database unreachable", Kind::Internal)
+    }
+}
+
+impl crate::AclStore for Sqlite {
+    fn get_acl(&self) -> Result<Acl, Error> {
+        let conn = self.reader();
+        let json: Option<String> = conn
+            .query_row("SELECT acl FROM acl WHERE id = 0", [], |row| row.get(0))
+            .optional()
+            .with_kind("error reading acl", Kind::Internal)?;
+
+        match json {
+            Some(json) => {
+                serde_json::from_str(&json).with_kind("error decoding acl", Kind::Internal)
+            }
+            // Nothing stored yet means nobody has been granted anything
+            None => Ok(Acl::default()),
+        }
+    }
+
+    fn put_acl(&self, acl: &Acl) -> Result<(), Error> {
+        let json = serde_json::to_string(acl).with_kind("error encoding acl", Kind::Internal)?;
+        let conn = self.writer.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO acl (id, acl) VALUES (0, ?1)",
+            params![json],
+        )
+        .with_kind("error writing acl", Kind::Internal)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AclStore, NodeStore, Storage};
+
+    fn node(revision: u64, blob: &[u8]) -> Node {
+        Node {
+            id: String::from("abc"),
+            namespace: String::from(crate::DEFAULT_NAMESPACE),
+            node_type: crate::NodeType::File,
+            blobs: vec![BlobRef::of(blob)],
+            attributes: Default::default(),
+            revision,
+            updated: 0,
+            author: None,
+        }
+    }
+
+    #[test]
+    fn stores_blobs_in_one_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        let store = Sqlite::open(&path).unwrap();
+
+        let text = "hello\n".repeat(100).into_bytes();
+        let id = BlobRef::of(&text);
+        Storage::put(&store, &id, text.clone()).unwrap();
+        Storage::put(&store, &id, text.clone()).unwrap();
+        assert!(store.contains(&id).unwrap());
+        assert!(matches!(
+            Storage::get(&store, &BlobRef::of(b"nope")),
+            Err(StorageError::NotFound)
+        ));
+
+        let stats = store.stats().unwrap();
+        assert_eq!((stats.blobs, stats.bytes), (1, 600));
+        assert!(stats.physical_bytes < stats.bytes);
+
+        // Reads don't wait on a write that's going on, and see what was
+        // committed before it
+        let writing = store.writer.lock().unwrap();
+        writing.execute("BEGIN IMMEDIATE", []).unwrap();
+        writing.execute("DELETE FROM blobs", []).unwrap();
+        assert_eq!(Storage::get(&store, &id).unwrap(), text);
+        writing.execute("ROLLBACK", []).unwrap();
+        drop(writing);
+
+        // Still there for whoever opens it next
+        drop(store);
+        let store = Sqlite::open(&path).unwrap();
+        assert_eq!(Storage::get(&store, &id).unwrap(), text);
+        assert!(store.get_acl().unwrap().admins.is_empty());
+    }
+
+    #[test]
+    fn updates_nodes_by_revision() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Sqlite::open(&dir.path().join("store.db")).unwrap();
+
+        NodeStore::put(&store, "abc", &node(1, b"one")).unwrap();
+        NodeStore::update(&store, "abc", 1, &node(2, b"two")).unwrap();
+        let err = NodeStore::update(&store, "abc", 1, &node(2, b"three")).unwrap_err();
+        assert!(matches!(err.kind, Kind::Conflict));
+
+        assert_eq!(NodeStore::get(&store, "abc").unwrap().revision, 2);
+        let revisions = NodeStore::revisions(&store, "abc").unwrap();
+        let blobs: Vec<_> = revisions.iter().map(|n| n.blobs[0].clone()).collect();
+        assert_eq!(blobs, vec![BlobRef::of(b"one"), BlobRef::of(b"two")]);
+        assert_eq!(NodeStore::list(&store).unwrap().len(), 1);
+
+        NodeStore::delete(&store, "abc").unwrap();
+        let err = NodeStore::get(&store, "abc").unwrap_err();
+        assert!(matches!(err.kind, Kind::NotFound));
+        assert!(NodeStore::revisions(&store, "abc").is_err());
+    }
+}